@@ -1,5 +1,4 @@
 use log::{debug, info};
-use serialport::SerialPort;
 use std::fs::OpenOptions;
 use std::io::{BufWriter, Write};
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -7,13 +6,14 @@ use std::sync::Arc;
 use std::thread::JoinHandle;
 use std::time::Duration;
 use std::{io, thread};
-use winapi::um::winuser::VK_RETURN;
 
 use crate::config::Config;
-use crate::keyboard::Keyboard;
+use crate::keyboard::{Keyboard, VK_RETURN};
 
 use crate::packets::rs232c;
 use crate::packets::rs232c::Packet;
+use crate::transport;
+use crate::transport::Transport;
 
 // #[derive(Debug)]
 // #[repr(u8)]
@@ -34,15 +34,20 @@ static CMD_RADIO_ON: u8 = 0x40;
 // static CMD_RADIO_OFF: u8 = 0x41;
 static CMD_POLL: u8 = 0x42;
 
+/// Client for the Finale Aime card reader
 pub struct CardReader {
-    buf_writer: BufWriter<serialport::COMPort>,
+    buf_writer: BufWriter<Box<dyn Transport>>,
     req_packet: rs232c::RequestPacket<128>,
     res_packet: rs232c::ResponsePacket<128>,
 }
 
 impl CardReader {
-    pub fn new(re2_port_name: String) -> Result<Self, serialport::Error> {
-        let mut re2_port = serialport::new(re2_port_name, 38_400).open_native()?;
+    pub fn new(re2_port_name: String) -> io::Result<Self> {
+        let re2_port = transport::open(&re2_port_name, 38_400)?;
+        Self::with_transport(re2_port)
+    }
+
+    pub fn with_transport(mut re2_port: Box<dyn Transport>) -> io::Result<Self> {
         re2_port.set_timeout(Duration::from_millis(5000))?;
 
         Ok(Self {
@@ -71,6 +76,7 @@ impl CardReader {
         Ok(())
     }
 
+    /// Sends a command and waits for the response, see [`CardReader::response`]
     pub fn cmd(&mut self, dest: u8, cmd: u8, data: &[u8]) -> io::Result<()> {
         self.req_packet
             .set_dest(dest)
@@ -80,6 +86,11 @@ impl CardReader {
         self.res_packet.read(self.buf_writer.get_mut())?;
         Ok(())
     }
+
+    /// Data of the last response
+    pub fn response(&mut self) -> &[u8] {
+        self.res_packet.data()
+    }
 }
 
 // fn read_aime_request(reader: &mut dyn SerialPort, buf: &mut [u8]) -> io::Result<usize> {
@@ -113,13 +124,13 @@ pub fn spawn_thread(
                     // TODO: handle error
                     debug!("timeout")
                 }
-                if reader.res_packet.data().len() == 20 {
+                if reader.response().len() == 20 {
                     let mut f = OpenOptions::new()
                         .write(true)
                         .open(&path)
                         .expect("Cannot read file");
                     let mut id = String::new();
-                    for &b in &reader.res_packet.data()[4..=11] {
+                    for &b in &reader.response()[4..=11] {
                         id.push_str(&format!("{:02X}", b));
                     }
                     f.write_all(id.as_bytes()).unwrap();
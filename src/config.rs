@@ -1,14 +1,22 @@
-use clap::{ArgAction, Parser};
+use clap::{ArgAction, Args, Parser, Subcommand};
 use clap_serde_derive::ClapSerde;
 use serde::{Deserialize, Serialize};
-use winapi::ctypes::c_int;
-use winapi::um::winuser::{
-    VK_NUMPAD1, VK_NUMPAD2, VK_NUMPAD3, VK_NUMPAD4, VK_NUMPAD6, VK_NUMPAD7, VK_NUMPAD8, VK_NUMPAD9,
+use std::collections::BTreeMap;
+
+use crate::jvs::lamp::LampConfig;
+use crate::jvs::mapping::{default_mapping as default_jvs_mapping, SwitchBit};
+use crate::jvs::JvsOutput;
+use crate::keyboard::{
+    KeyCode, VK_NUMPAD1, VK_NUMPAD2, VK_NUMPAD3, VK_NUMPAD4, VK_NUMPAD6, VK_NUMPAD7, VK_NUMPAD8,
+    VK_NUMPAD9,
 };
+use crate::touch::debounce::DebounceConfig;
+use crate::touch::keys::{TouchKeysConfig, TouchOutput};
+use crate::touch::layout::TouchMode;
+use crate::touch::mapping::{default_mapping, ZoneSynthesis};
+use crate::touch::profile::SensitivityConfig;
 
 #[derive(Parser, ClapSerde, Deserialize, Serialize, Debug)]
-#[clap(author = "robloxxa", version, about, long_about = None)]
-/// Tool that allow playing Maimai DX on original Maimai Finale Cabinet
 pub struct Config {
     /// Specify path to config
     #[serde(skip)]
@@ -31,6 +39,149 @@ pub struct Config {
     #[clap_serde]
     #[arg(skip)]
     pub input: Input,
+
+    #[clap_serde]
+    #[arg(skip)]
+    pub jvs: Jvs,
+
+    #[clap_serde]
+    #[arg(skip)]
+    pub touch: Touch,
+}
+
+// ClapSerde can't have an optional subcommand inside of Config, so command line is wrapped here
+#[derive(Parser, Debug)]
+#[clap(author = "robloxxa", version, about, long_about = None)]
+/// Tool that allow playing Maimai DX on original Maimai Finale Cabinet
+pub struct Cli {
+    #[command(flatten)]
+    pub config: Config,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+// Same as Cli, but config options that were not passed are left empty,
+// used to merge command line over the config file
+#[derive(Parser)]
+pub struct CliOpt {
+    #[command(flatten)]
+    pub config: <Config as ClapSerde>::Opt,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Feeds a touch recording (see --record-touch) through the translator
+    Replay(ReplayArgs),
+    /// Pretends to be the Finale touch panel, for testing without a cabinet
+    SimulateFinaleTouch(SimulateFinaleArgs),
+    /// Pretends to be the game on a Deluxe touch port: runs its startup handshake and prints touches
+    EmulateAllsTouch(EmulateAllsArgs),
+    /// Live diagnostic views
+    Diag(DiagArgs),
+    /// Measures how long translating a touch frame takes, with synthetic frames and [touch] config
+    BenchTouch(BenchTouchArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct BenchTouchArgs {
+    /// Number of frames to push through
+    #[arg(long, default_value = "100000")]
+    pub frames: u64,
+
+    /// Fail if p99 of per frame processing is over this many microseconds
+    #[arg(long)]
+    pub max_p99_us: Option<u64>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct DiagArgs {
+    #[command(subcommand)]
+    pub target: DiagTarget,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum DiagTarget {
+    /// Draws Finale and Deluxe touch rings lit in real time, with press counters
+    Touch(DiagTouchArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct DiagTouchArgs {
+    /// Finale touch port, touch_re2_com from config by default
+    #[arg(long)]
+    pub port: Option<String>,
+
+    /// Seconds a sensor has to be held to be highlighted as stuck, stuck_secs from config by default
+    #[arg(long)]
+    pub stuck_secs: Option<u64>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct EmulateAllsArgs {
+    /// Port to act as the game on, the bridge should use the other end as touch_alls_p1/p2_com
+    #[arg(long)]
+    pub port: String,
+
+    /// Player whose touch port this is, 1 or 2
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u8).range(1..=2))]
+    pub player: u8,
+
+    /// Ratio sent for every zone during the handshake
+    #[arg(long, default_value = "50")]
+    pub ratio: u8,
+
+    /// Sensitivity threshold sent for every zone during the handshake
+    #[arg(long, default_value = "20")]
+    pub sensitivity: u8,
+
+    /// Stop after this many frames instead of running until Ctrl+C
+    #[arg(long)]
+    pub frames: Option<u64>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct SimulateFinaleArgs {
+    /// Port to act as the panel on, the bridge should use the other end as touch_re2_com
+    #[arg(long, conflicts_with = "pty")]
+    pub port: Option<String>,
+
+    /// Create a pseudo terminal and print its path (Unix only)
+    #[arg(long, default_value = "false", action=ArgAction::SetTrue)]
+    pub pty: bool,
+
+    /// Touch script, lines of "<time_ms> <player> <zones...>", e.g. "250 1 A1 B1"
+    #[arg(long)]
+    pub script: Option<String>,
+
+    /// Delay between frames in milliseconds
+    #[arg(long, default_value = "15")]
+    pub interval_ms: u64,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ReplayArgs {
+    /// Recording made with --record-touch
+    pub file: String,
+
+    /// Player whose touches are replayed, 1 or 2
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u8).range(1..=2))]
+    pub player: u8,
+
+    /// Deluxe port to send translated frames to
+    #[arg(long, conflicts_with = "output")]
+    pub port: Option<String>,
+
+    /// File to write translated frames to instead of a port
+    #[arg(long)]
+    pub output: Option<String>,
+
+    /// Replay as fast as possible instead of the original timing
+    #[arg(long, default_value = "false", action=ArgAction::SetTrue)]
+    pub fast: bool,
 }
 
 #[derive(Parser, ClapSerde, Deserialize, Serialize, Debug, Clone)]
@@ -66,85 +217,190 @@ pub struct Settings {
     #[arg(long, default_value = "COM24")]
     pub jvs_re2_com: String,
 
+    /// COM Port for Deluxe's JVS, the emulated I/O board answers there (see [jvs] output)
+    #[arg(long, default_value = "COM4")]
+    pub jvs_alls_com: String,
+
     #[arg(long, default_value = "COM22")]
     pub reader_re2_com: String,
 
     #[arg(long)]
     pub reader_device_file: Option<String>,
 
+    /// Records every raw Finale and translated Deluxe touch frame into a file
+    #[arg(long)]
+    pub record_touch: Option<String>,
+
     #[arg(long, default_value = "1337")]
     pub spice_port: String,
 }
 
+#[derive(ClapSerde, Deserialize, Serialize, Debug, Clone)]
+pub struct Touch {
+    /// Which Deluxe players get touch: "both", "p1", "p2" (only that player's port is opened),
+    /// "mirror-p1" or "mirror-p2" (that Finale side drives both players)
+    #[arg(skip)]
+    #[default(TouchMode::Both)]
+    pub mode: TouchMode,
+
+    /// Where touches go: "deluxe" (serial ports), "keyboard" (keys from [touch.keys],
+    /// Deluxe ports are not opened) or "both"
+    #[arg(skip)]
+    #[default(TouchOutput::Deluxe)]
+    pub output: TouchOutput,
+
+    /// Rotates touches clockwise in 45 degree steps (0..7), for panels installed rotated
+    #[arg(skip)]
+    #[default(0)]
+    pub rotation: u8,
+
+    /// Mirrors touches left to right (after rotation)
+    #[arg(skip)]
+    #[default(false)]
+    pub flip: bool,
+
+    /// How Deluxe D zones are lit: "mapping" (by [touch.mapping]) or "adjacent"
+    /// (only when both neighbouring A sensors are pressed, e.g. A1 + A2 -> D2)
+    #[arg(skip)]
+    #[default(ZoneSynthesis::Mapping)]
+    pub d_zones: ZoneSynthesis,
+
+    /// Same as d_zones, for E zones and B sensors
+    #[arg(skip)]
+    #[default(ZoneSynthesis::Mapping)]
+    pub e_zones: ZoneSynthesis,
+
+    /// Seconds a Finale sensor has to be pressed without a break to be reported as stuck,
+    /// 0 disables the check
+    #[arg(skip)]
+    #[default(30)]
+    pub stuck_secs: u64,
+
+    /// Leave stuck sensors out of frames sent to Deluxe until they release
+    #[arg(skip)]
+    #[default(false)]
+    pub mask_stuck: bool,
+
+    /// Seconds between touch latency reports in the log (p50/p99/max), 0 disables measuring
+    #[arg(skip)]
+    #[default(60)]
+    pub latency_report_secs: u64,
+
+    /// Debounce of Finale sensors, see touch::debounce
+    #[arg(skip)]
+    #[default(DebounceConfig::default())]
+    pub debounce: DebounceConfig,
+
+    /// Stored and overridden touch thresholds, see touch::profile
+    #[arg(skip)]
+    #[default(SensitivityConfig::default())]
+    pub sensitivity: SensitivityConfig,
+
+    /// Keys pressed by touch zones, see touch::keys
+    #[arg(skip)]
+    #[default(TouchKeysConfig::default())]
+    pub keys: TouchKeysConfig,
+
+    /// Finale touch zone (A1..A8, B1..B8, C) to the list of Deluxe zones (A1..E8) it activates.
+    /// Zones that are not listed keep the built-in mapping
+    #[arg(skip)]
+    #[default(default_mapping())]
+    pub mapping: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(ClapSerde, Deserialize, Serialize, Debug, Clone)]
+pub struct Jvs {
+    /// Where Finale buttons go: "keyboard" (keys from [input]), "device" (emulated JVS I/O board
+    /// on jvs_alls_com, see jvs::device) or "both"
+    #[arg(skip)]
+    #[default(JvsOutput::Keyboard)]
+    pub output: JvsOutput,
+
+    /// Input (test, service, p1_btn1..p2_btn8) to the switch bit it is read from, see
+    /// jvs::mapping. Inputs that are not listed keep the Finale layout
+    #[arg(skip)]
+    #[default(default_jvs_mapping())]
+    pub mapping: BTreeMap<String, SwitchBit>,
+
+    /// Button lamps on the board's outputs, see jvs::lamp
+    #[arg(skip)]
+    #[default(LampConfig::default())]
+    pub lamps: LampConfig,
+}
+
 #[derive(ClapSerde, Deserialize, Serialize, Debug, Clone)]
 pub struct Input {
     #[default(SERVICE_DEFAULT)]
-    pub service: c_int,
+    pub service: KeyCode,
     #[default(TEST_DEFAULT)]
-    pub test: c_int,
+    pub test: KeyCode,
+    /// Pressed once per coin inserted into the Finale coin mech
+    #[default(COIN_DEFAULT)]
+    pub coin: KeyCode,
 
     #[default(P1_BTN1_DEFAULT)]
-	pub p1_btn1: c_int,
+    pub p1_btn1: KeyCode,
     #[default(P1_BTN2_DEFAULT)]
-	pub p1_btn2: c_int,
+    pub p1_btn2: KeyCode,
     #[default(P1_BTN3_DEFAULT)]
-	pub p1_btn3: c_int,
+    pub p1_btn3: KeyCode,
     #[default(P1_BTN4_DEFAULT)]
-	pub p1_btn4: c_int,
+    pub p1_btn4: KeyCode,
     #[default(P1_BTN5_DEFAULT)]
-	pub p1_btn5: c_int,
+    pub p1_btn5: KeyCode,
     #[default(P1_BTN6_DEFAULT)]
-	pub p1_btn6: c_int,
+    pub p1_btn6: KeyCode,
     #[default(P1_BTN7_DEFAULT)]
-	pub p1_btn7: c_int,
+    pub p1_btn7: KeyCode,
     #[default(P1_BTN8_DEFAULT)]
-	pub p1_btn8: c_int,
+    pub p1_btn8: KeyCode,
 
     #[default(P2_BTN1_DEFAULT)]
-	pub p2_btn1: c_int,
+    pub p2_btn1: KeyCode,
     #[default(P2_BTN2_DEFAULT)]
-	pub p2_btn2: c_int,
+    pub p2_btn2: KeyCode,
     #[default(P2_BTN3_DEFAULT)]
-	pub p2_btn3: c_int,
+    pub p2_btn3: KeyCode,
     #[default(P2_BTN4_DEFAULT)]
-	pub p2_btn4: c_int,
+    pub p2_btn4: KeyCode,
     #[default(P2_BTN5_DEFAULT)]
-	pub p2_btn5: c_int,
+    pub p2_btn5: KeyCode,
     #[default(P2_BTN6_DEFAULT)]
-	pub p2_btn6: c_int,
+    pub p2_btn6: KeyCode,
     #[default(P2_BTN7_DEFAULT)]
-	pub p2_btn7: c_int,
+    pub p2_btn7: KeyCode,
     #[default(P2_BTN8_DEFAULT)]
-	pub p2_btn8: c_int,
+    pub p2_btn8: KeyCode,
 }
 
-static TEST_DEFAULT: c_int = 0x54;
-static SERVICE_DEFAULT: c_int = 0x33;
+static TEST_DEFAULT: KeyCode = 0x54;
+static SERVICE_DEFAULT: KeyCode = 0x33;
+static COIN_DEFAULT: KeyCode = 0x72; // F3
 
-static P1_BTN1_DEFAULT: c_int = 0x57;
+static P1_BTN1_DEFAULT: KeyCode = 0x57;
 // W
-static P1_BTN2_DEFAULT: c_int = 0x45;
+static P1_BTN2_DEFAULT: KeyCode = 0x45;
 // E
-static P1_BTN3_DEFAULT: c_int = 0x44;
+static P1_BTN3_DEFAULT: KeyCode = 0x44;
 // D
-static P1_BTN4_DEFAULT: c_int = 0x43;
+static P1_BTN4_DEFAULT: KeyCode = 0x43;
 // C
-static P1_BTN5_DEFAULT: c_int = 0x58;
+static P1_BTN5_DEFAULT: KeyCode = 0x58;
 // X
-static P1_BTN6_DEFAULT: c_int = 0x5A;
+static P1_BTN6_DEFAULT: KeyCode = 0x5A;
 // Z
-static P1_BTN7_DEFAULT: c_int = 0x41;
+static P1_BTN7_DEFAULT: KeyCode = 0x41;
 // A
-static P1_BTN8_DEFAULT: c_int = 0x51; // Q
-
-static P2_BTN1_DEFAULT: c_int = VK_NUMPAD8;
-static P2_BTN2_DEFAULT: c_int = VK_NUMPAD9;
-static P2_BTN3_DEFAULT: c_int = VK_NUMPAD6;
-static P2_BTN4_DEFAULT: c_int = VK_NUMPAD3;
-static P2_BTN5_DEFAULT: c_int = VK_NUMPAD2;
-static P2_BTN6_DEFAULT: c_int = VK_NUMPAD1;
-static P2_BTN7_DEFAULT: c_int = VK_NUMPAD4;
-static P2_BTN8_DEFAULT: c_int = VK_NUMPAD7;
+static P1_BTN8_DEFAULT: KeyCode = 0x51; // Q
+
+static P2_BTN1_DEFAULT: KeyCode = VK_NUMPAD8;
+static P2_BTN2_DEFAULT: KeyCode = VK_NUMPAD9;
+static P2_BTN3_DEFAULT: KeyCode = VK_NUMPAD6;
+static P2_BTN4_DEFAULT: KeyCode = VK_NUMPAD3;
+static P2_BTN5_DEFAULT: KeyCode = VK_NUMPAD2;
+static P2_BTN6_DEFAULT: KeyCode = VK_NUMPAD1;
+static P2_BTN7_DEFAULT: KeyCode = VK_NUMPAD4;
+static P2_BTN8_DEFAULT: KeyCode = VK_NUMPAD7;
 
 // impl Default for Input {
 //     fn default() -> Self {
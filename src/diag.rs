@@ -0,0 +1,156 @@
+// Live diagnostic views (`diag` subcommand) drawn right in the terminal.
+//
+// Views draw into a Canvas, which is a plain character grid with a style per cell, so they
+// can be tested without a terminal. Screen owns the terminal while a view is running, logging
+// is turned off meanwhile, since log lines on stderr would land in the middle of the view.
+
+use std::io;
+use std::io::{Stdout, Write};
+use std::time::Duration;
+
+use crossterm::event::{Event, KeyEvent, KeyEventKind};
+use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use crossterm::{cursor, event, queue, terminal};
+use log::LevelFilter;
+
+pub mod touch;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellStyle {
+    Normal,
+    /// Inactive or unimportant, e.g. sensor that was never pressed
+    Dim,
+    /// Currently pressed
+    Active,
+    /// Needs attention, e.g. stuck sensor
+    Alert,
+}
+
+/// Character grid that views draw into
+pub struct Canvas {
+    width: usize,
+    height: usize,
+    cells: Vec<(char, CellStyle)>,
+}
+
+impl Canvas {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            cells: vec![(' ', CellStyle::Normal); width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Writes text starting at (x, y), whatever doesn't fit is cut off
+    pub fn put_str(&mut self, x: usize, y: usize, text: &str, style: CellStyle) {
+        if y >= self.height {
+            return;
+        }
+        for (i, ch) in text.chars().enumerate() {
+            if x + i >= self.width {
+                break;
+            }
+            self.cells[y * self.width + x + i] = (ch, style);
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> (char, CellStyle) {
+        self.cells[y * self.width + x]
+    }
+
+    /// Text of row `y`, without styles and trailing spaces
+    pub fn row_text(&self, y: usize) -> String {
+        let row: String = (0..self.width).map(|x| self.get(x, y).0).collect();
+        row.trim_end().to_string()
+    }
+}
+
+/// Terminal in raw mode on the alternate screen, with logging off. Both are restored when dropped
+pub struct Screen {
+    out: Stdout,
+    log_level: LevelFilter,
+}
+
+impl Screen {
+    pub fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        // From here on Drop puts everything back, even if entering fails halfway
+        let mut screen = Self {
+            out: io::stdout(),
+            log_level: log::max_level(),
+        };
+        log::set_max_level(LevelFilter::Off);
+        crossterm::execute!(
+            screen.out,
+            terminal::EnterAlternateScreen,
+            cursor::Hide,
+            terminal::Clear(terminal::ClearType::All)
+        )?;
+        Ok(screen)
+    }
+
+    /// Redraws the whole screen from `canvas`
+    pub fn draw(&mut self, canvas: &Canvas) -> io::Result<()> {
+        for y in 0..canvas.height() {
+            queue!(self.out, cursor::MoveTo(0, y as u16))?;
+            let mut current = None;
+            for x in 0..canvas.width() {
+                let (ch, style) = canvas.get(x, y);
+                if current != Some(style) {
+                    let (fg, bg) = colors(style);
+                    queue!(self.out, SetForegroundColor(fg), SetBackgroundColor(bg))?;
+                    current = Some(style);
+                }
+                queue!(self.out, Print(ch))?;
+            }
+            queue!(
+                self.out,
+                ResetColor,
+                terminal::Clear(terminal::ClearType::UntilNewLine)
+            )?;
+        }
+        self.out.flush()
+    }
+
+    /// Waits up to `timeout` for a key press
+    pub fn poll_key(&self, timeout: Duration) -> io::Result<Option<KeyEvent>> {
+        if !event::poll(timeout)? {
+            return Ok(None);
+        }
+        match event::read()? {
+            Event::Key(key) if key.kind == KeyEventKind::Press => Ok(Some(key)),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = crossterm::execute!(
+            self.out,
+            ResetColor,
+            cursor::Show,
+            terminal::LeaveAlternateScreen
+        );
+        let _ = terminal::disable_raw_mode();
+        log::set_max_level(self.log_level);
+    }
+}
+
+fn colors(style: CellStyle) -> (Color, Color) {
+    match style {
+        CellStyle::Normal => (Color::Reset, Color::Reset),
+        CellStyle::Dim => (Color::DarkGrey, Color::Reset),
+        CellStyle::Active => (Color::Black, Color::Green),
+        CellStyle::Alert => (Color::White, Color::Red),
+    }
+}
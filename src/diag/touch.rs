@@ -0,0 +1,345 @@
+// `diag touch`: Finale ring and the translated Deluxe ring of each player side by side,
+// lit in real time straight from the panel, plus press counters for every Finale sensor.
+//
+// Sensor that never gets pressed shows up dimmed in counters (dead), sensor that is held for
+// longer than `stuck_secs` shows up red (stuck), together with every Deluxe zone it drives.
+// Deluxe ring shows exactly what the bridge would send, so with `mask_stuck` stuck zones are
+// red but not lit.
+
+use std::io;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crossterm::event::{KeyCode, KeyModifiers};
+
+use crate::config::{Config, DiagTouchArgs};
+use crate::diag::{Canvas, CellStyle, Screen};
+use crate::helper_funcs::bit_read;
+use crate::touch::frame::{FrameParser, FrameStats, FRAME_LEN, PLAYER_DATA};
+use crate::touch::mapping::{
+    finale_sensors, ZoneMapping, DEFAULT_DELUXE_WRITE_BUFFER, DELUXE_ZONES,
+};
+use crate::touch::stuck::StuckDetector;
+use crate::touch::{zone_mapping, HALT, STAT};
+use crate::transport;
+
+const PANEL_WIDTH: usize = 30;
+const PANEL_HEIGHT: usize = 13;
+const PANEL_GAP: usize = 4;
+/// Terminal cells are about twice as tall as wide
+const ASPECT: f64 = 2.2;
+
+const OUTER_RADIUS: f64 = 6.0;
+const FINALE_B_RADIUS: f64 = 3.5;
+const DELUXE_E_RADIUS: f64 = 4.0;
+const DELUXE_B_RADIUS: f64 = 2.5;
+
+const COUNTERS_PER_ROW: usize = 9;
+const COUNTER_WIDTH: usize = 9;
+/// Title, then per player: header, ring, 2 counter rows and stuck sensors, then stats
+const CANVAS_HEIGHT: usize = 1 + 2 * (PANEL_HEIGHT + 4) + 1;
+const CANVAS_WIDTH: usize = COUNTERS_PER_ROW * COUNTER_WIDTH;
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(30);
+
+#[derive(Clone)]
+struct PlayerState {
+    finale: [u8; 4],
+    deluxe: [u8; 9],
+    /// Per Finale sensor, in touch frame order
+    presses: Vec<u32>,
+}
+
+/// Touch state that diag view draws, fed with raw Finale frames
+pub struct TouchDiag {
+    mapping: ZoneMapping,
+    stuck: StuckDetector,
+    /// (byte, bit, name) of every Finale sensor
+    sensors: Vec<(usize, usize, &'static str)>,
+    players: [PlayerState; 2],
+}
+
+impl TouchDiag {
+    pub fn new(mapping: ZoneMapping, mut stuck: StuckDetector) -> Self {
+        // Warnings would be drawn over the view
+        stuck.set_logging(false);
+        let sensors: Vec<_> = finale_sensors().collect();
+        let player = PlayerState {
+            finale: [0; 4],
+            deluxe: DEFAULT_DELUXE_WRITE_BUFFER,
+            presses: vec![0; sensors.len()],
+        };
+        Self {
+            mapping,
+            stuck,
+            sensors,
+            players: [player.clone(), player],
+        }
+    }
+
+    pub fn update(&mut self, frame: &[u8; FRAME_LEN], now: Instant) {
+        for (player_num, data) in PLAYER_DATA.iter().enumerate() {
+            let finale = &frame[data.clone()];
+            let player = &mut self.players[player_num];
+            for (i, &(byte, bit, _)) in self.sensors.iter().enumerate() {
+                if bit_read(&finale[byte], bit) && !bit_read(&player.finale[byte], bit) {
+                    player.presses[i] += 1;
+                }
+            }
+            player.finale.copy_from_slice(finale);
+            // Deluxe side shows what the bridge would send, with stuck sensors masked if enabled
+            let sensors = self.stuck.filter(player_num, finale, now);
+            player.deluxe = self.mapping.translate(&sensors);
+        }
+    }
+
+    pub fn reset_counters(&mut self) {
+        for player in &mut self.players {
+            player.presses.iter_mut().for_each(|p| *p = 0);
+        }
+    }
+
+    /// How many times Finale sensor (index in touch frame order) was pressed
+    pub fn presses(&self, player_num: usize, sensor: usize) -> u32 {
+        self.players[player_num].presses[sensor]
+    }
+
+    pub fn is_stuck(&self, player_num: usize, sensor: usize) -> bool {
+        self.stuck.is_stuck(player_num, sensor)
+    }
+
+    pub fn render(&self, stats: FrameStats) -> Canvas {
+        let mut canvas = Canvas::new(CANVAS_WIDTH, CANVAS_HEIGHT);
+        canvas.put_str(
+            0,
+            0,
+            "Touch diagnostics, q: quit, r: reset counters",
+            CellStyle::Normal,
+        );
+
+        for player_num in 0..2 {
+            let top = 1 + player_num * (PANEL_HEIGHT + 4);
+            let player = &self.players[player_num];
+            let stuck: Vec<bool> = (0..self.sensors.len())
+                .map(|i| self.is_stuck(player_num, i))
+                .collect();
+
+            let finale_x = 0;
+            let deluxe_x = PANEL_WIDTH + PANEL_GAP;
+            let header = format!("P{} Finale", player_num + 1);
+            canvas.put_str(finale_x, top, &header, CellStyle::Normal);
+            let header = format!("P{} Deluxe", player_num + 1);
+            canvas.put_str(deluxe_x, top, &header, CellStyle::Normal);
+
+            for (i, &(byte, bit, name)) in self.sensors.iter().enumerate() {
+                let style = zone_style(bit_read(&player.finale[byte], bit), stuck[i]);
+                let (x, y) = zone_position(name, FINALE_B_RADIUS);
+                canvas.put_str(finale_x + x, top + 1 + y, name, style);
+            }
+
+            for (zone, &(name, (index, bit_mask))) in DELUXE_ZONES.iter().enumerate() {
+                let zone_stuck = self.sensors.iter().enumerate().any(|(i, &(byte, bit, _))| {
+                    stuck[i] && self.mapping.deluxe_zones_of(byte, bit).contains(&zone)
+                });
+                let style = zone_style(player.deluxe[index] & bit_mask != 0, zone_stuck);
+                let (x, y) = zone_position(name, DELUXE_B_RADIUS);
+                canvas.put_str(deluxe_x + x, top + 1 + y, name, style);
+            }
+
+            for (i, &(byte, bit, name)) in self.sensors.iter().enumerate() {
+                let x = (i % COUNTERS_PER_ROW) * COUNTER_WIDTH;
+                let y = top + 1 + PANEL_HEIGHT + i / COUNTERS_PER_ROW;
+                let pressed = bit_read(&player.finale[byte], bit);
+                let style = match (stuck[i], pressed, player.presses[i]) {
+                    (true, _, _) => CellStyle::Alert,
+                    (_, true, _) => CellStyle::Active,
+                    (_, _, 0) => CellStyle::Dim,
+                    _ => CellStyle::Normal,
+                };
+                let counter = format!("{:>2} {:<5}", name, player.presses[i]);
+                canvas.put_str(x, y, &counter, style);
+            }
+
+            let stuck_sensors = self.stuck.stuck_sensors(player_num);
+            let y = top + PANEL_HEIGHT + 3;
+            if stuck_sensors.is_empty() {
+                canvas.put_str(0, y, "Stuck: none", CellStyle::Dim);
+            } else {
+                let masked = if self.stuck.masks_stuck() {
+                    " (masked)"
+                } else {
+                    ""
+                };
+                let text = format!("Stuck: {}{}", stuck_sensors.join(" "), masked);
+                canvas.put_str(0, y, &text, CellStyle::Alert);
+            }
+        }
+
+        let stats = format!(
+            "{} frames, {} resyncs, {} bytes discarded",
+            stats.frames, stats.resyncs, stats.discarded_bytes
+        );
+        canvas.put_str(0, CANVAS_HEIGHT - 1, &stats, CellStyle::Dim);
+        canvas
+    }
+}
+
+fn zone_style(pressed: bool, stuck: bool) -> CellStyle {
+    match (pressed, stuck) {
+        (_, true) => CellStyle::Alert,
+        (true, false) => CellStyle::Active,
+        (false, false) => CellStyle::Dim,
+    }
+}
+
+/// Position of a zone label inside a ring panel. A/B sit in the middle of their sectors,
+/// D/E on the borders between them, clockwise from the top. B ring is smaller on Deluxe
+/// to leave room for E
+fn zone_position(name: &str, b_radius: f64) -> (usize, usize) {
+    let (cx, cy) = (PANEL_WIDTH / 2, PANEL_HEIGHT / 2);
+    let num: f64 = name[1..].parse().unwrap_or(0.0);
+    let (angle, radius) = match &name[..1] {
+        "A" => (22.5 + 45.0 * (num - 1.0), OUTER_RADIUS),
+        "B" => (22.5 + 45.0 * (num - 1.0), b_radius),
+        "D" => (45.0 * (num - 1.0), OUTER_RADIUS),
+        "E" => (45.0 * (num - 1.0), DELUXE_E_RADIUS),
+        // Finale C in the middle, Deluxe C2 and C1 on its left and right
+        _ => match name {
+            "C1" => return (cx + 1, cy),
+            "C2" => return (cx - 2, cy),
+            _ => return (cx, cy),
+        },
+    };
+
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let x = cx as f64 + (radius * sin * ASPECT).round() - 1.0;
+    let y = cy as f64 - (radius * cos).round();
+    (x as usize, y as usize)
+}
+
+/// Entry point of `diag touch` subcommand
+pub fn run_touch_diag(
+    config: &Config,
+    args: &DiagTouchArgs,
+    running: &AtomicBool,
+) -> io::Result<()> {
+    let mapping = zone_mapping(&config.touch)?;
+    let port_name = args.port.as_ref().unwrap_or(&config.settings.touch_re2_com);
+    let mut port = transport::open(port_name, 9600)?;
+    port.set_timeout(Duration::ZERO)?;
+    port.write_all(HALT)?;
+    port.write_all(STAT)?;
+
+    let mut touch_config = config.touch.clone();
+    if let Some(stuck_secs) = args.stuck_secs {
+        touch_config.stuck_secs = stuck_secs;
+    }
+    let mut parser = FrameParser::new();
+    let mut diag = TouchDiag::new(mapping, StuckDetector::from_config(&touch_config));
+    let mut read_buffer = [0u8; 64];
+    let mut screen = Screen::enter()?;
+
+    while running.load(Ordering::Acquire) {
+        loop {
+            match port.read(&mut read_buffer) {
+                Ok(0) => break,
+                Ok(len) => {
+                    let now = Instant::now();
+                    parser.push_slice(&read_buffer[..len], |frame| diag.update(frame, now));
+                }
+                Err(err) if transport::is_timeout(&err) => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        screen.draw(&diag.render(parser.stats()))?;
+
+        if let Some(key) = screen.poll_key(REDRAW_INTERVAL)? {
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => break,
+                // Raw mode swallows the signal, so Ctrl+C comes as a key
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break,
+                KeyCode::Char('r') => diag.reset_counters(),
+                _ => {}
+            }
+        }
+    }
+
+    drop(screen);
+    port.write_all(HALT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+    use std::time::{Duration, Instant};
+
+    use crate::diag::touch::TouchDiag;
+    use crate::diag::{Canvas, CellStyle};
+    use crate::touch::frame::{build_frame, FrameStats};
+    use crate::touch::mapping::ZoneMapping;
+    use crate::touch::stuck::StuckDetector;
+
+    /// Style of the first occurrence of `text` within columns `x_range`
+    fn find(canvas: &Canvas, x_range: Range<usize>, text: &str) -> CellStyle {
+        for y in 0..canvas.height() {
+            let row = canvas.row_text(y);
+            let end = x_range.end.min(row.len());
+            if let Some(x) = row.get(x_range.start..end).and_then(|r| r.find(text)) {
+                return canvas.get(x_range.start + x, y).1;
+            }
+        }
+        panic!("{text} not found");
+    }
+
+    fn diag() -> TouchDiag {
+        let stuck = StuckDetector::new(Some(Duration::from_secs(10)), true);
+        TouchDiag::new(ZoneMapping::default(), stuck)
+    }
+
+    #[test]
+    pub fn counts_presses_and_detects_stuck() {
+        let mut diag = diag();
+        let start = Instant::now();
+        let pressed = build_frame(&[[0b1, 0, 0, 0], [0; 4]]);
+        let released = build_frame(&[[0; 4]; 2]);
+
+        diag.update(&pressed, start);
+        diag.update(&pressed, start + Duration::from_millis(15));
+        diag.update(&released, start + Duration::from_millis(30));
+        diag.update(&pressed, start + Duration::from_millis(45));
+        assert_eq!(diag.presses(0, 0), 2);
+        assert_eq!(diag.presses(1, 0), 0);
+
+        diag.update(&pressed, start + Duration::from_secs(5));
+        assert!(!diag.is_stuck(0, 0));
+        diag.update(&pressed, start + Duration::from_secs(11));
+        assert!(diag.is_stuck(0, 0));
+
+        diag.reset_counters();
+        assert_eq!(diag.presses(0, 0), 0);
+    }
+
+    #[test]
+    pub fn renders_pressed_and_stuck_zones() {
+        let mut diag = diag();
+        let start = Instant::now();
+        let frame = build_frame(&[[0b10, 0, 0, 0], [0; 4]]);
+        diag.update(&frame, start);
+
+        let canvas = diag.render(FrameStats::default());
+        // P1 Finale panel, then P1 Deluxe panel
+        assert_eq!(find(&canvas, 0..30, "B1"), CellStyle::Active);
+        assert_eq!(find(&canvas, 0..30, "A1"), CellStyle::Dim);
+        assert_eq!(find(&canvas, 34..64, "E2"), CellStyle::Active);
+        assert_eq!(find(&canvas, 34..64, "D2"), CellStyle::Dim);
+        assert_eq!(find(&canvas, 0..30, "Stuck: none"), CellStyle::Dim);
+
+        diag.update(&frame, start + Duration::from_secs(11));
+        let canvas = diag.render(FrameStats::default());
+        assert_eq!(find(&canvas, 0..30, "B1"), CellStyle::Alert);
+        assert_eq!(find(&canvas, 34..64, "E1"), CellStyle::Alert);
+        assert_eq!(find(&canvas, 0..30, "Stuck: B1 (masked)"), CellStyle::Alert);
+    }
+}
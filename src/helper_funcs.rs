@@ -7,7 +7,7 @@ pub static MARK: u8 = 0xD0;
 pub trait ReadExt: Read {
     fn read_u8(&mut self) -> io::Result<u8> {
         let buf = &mut [0u8; 1];
-        self.read(buf)?;
+        self.read_exact(buf)?;
         Ok(buf[0])
     }
 
@@ -26,8 +26,7 @@ impl<R: Read> ReadExt for R {}
 
 pub trait WriteExt: Write {
     fn write_u8(&mut self, b: u8) -> io::Result<()> {
-        self.write(&[b])?;
-        Ok(())
+        self.write_all(&[b])
     }
 
     fn write_u8_escaped(&mut self, b: u8) -> io::Result<()> {
@@ -1,46 +1,128 @@
 use std::io::BufWriter;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{io, thread};
 
 use std::thread::JoinHandle;
 
-use log::{error, info};
-use serialport::SerialPort;
-use winapi::ctypes::c_int;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
 
 use crate::config;
 use crate::config::Config;
-use crate::helper_funcs::bit_read;
-use crate::keyboard::Keyboard;
+use crate::jvs::capabilities::Capabilities;
+use crate::jvs::coin::{CoinKey, CoinSlot, CoinTracker, COIN_POLL_INTERVAL, COIN_SLOTS, MAX_COINS};
+use crate::jvs::device::{JvsDevice, JvsState};
+use crate::jvs::lamp::{LampSource, Lamps};
+use crate::jvs::mapping::JvsMapping;
+use crate::keyboard::{KeyCode, Keyboard};
 use crate::packets::rs232;
 use crate::packets::rs232::Packet;
+use crate::transport;
+use crate::transport::Transport;
+
+pub mod capabilities;
+pub mod coin;
+pub mod device;
+pub mod lamp;
+pub mod mapping;
+
+const BROADCAST: u8 = 0xFF;
+
+const CMD_RESET: u8 = 0xF0;
+const CMD_RESET_ARGUMENT: u8 = 0xD9;
+const CMD_ASSIGN_ADDRESS: u8 = 0xF1;
+
+const CMD_IDENTIFY: u8 = 0x10;
+const CMD_COMMAND_REVISION: u8 = 0x11;
+const CMD_JVS_VERSION: u8 = 0x12;
+const CMD_COMMS_VERSION: u8 = 0x13;
+const CMD_CAPABILITIES: u8 = 0x14;
+const CMD_CONVEY_ID: u8 = 0x15;
+const CMD_READ_DIGITAL: u8 = 0x20;
+const CMD_READ_COIN: u8 = 0x21;
+const CMD_RETRANSMIT: u8 = 0x2F;
+const CMD_DECREASE_COIN: u8 = 0x30;
+const CMD_GPO_ALL: u8 = 0x32;
+const CMD_INCREASE_COIN: u8 = 0x35;
+const CMD_GPO_BYTE: u8 = 0x37;
+const CMD_GPO_BIT: u8 = 0x38;
+
+/// Address of the bus master (the game), responses go there
+const MASTER: u8 = 0x00;
+
+const STATUS_NORMAL: u8 = 0x01;
+const REPORT_NORMAL: u8 = 0x01;
+
+/// Where Finale buttons go
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JvsOutput {
+    /// Key presses from [input]
+    #[default]
+    Keyboard,
+    /// Emulated JVS I/O board on jvs_alls_com, see jvs::device
+    Device,
+    Both,
+}
 
-static BROADCAST: u8 = 0xFF;
+impl JvsOutput {
+    pub fn to_keyboard(&self) -> bool {
+        matches!(self, JvsOutput::Keyboard | JvsOutput::Both)
+    }
 
-static CMD_RESET: u8 = 0xF0;
-static CMD_RESET_ARGUMENT: u8 = 0xD9;
-static CMD_ASSIGN_ADDRESS: u8 = 0xF1;
+    pub fn to_device(&self) -> bool {
+        matches!(self, JvsOutput::Device | JvsOutput::Both)
+    }
+}
 
-static CMD_IDENTIFY: u8 = 0x10;
-static CMD_COMMAND_REVISION: u8 = 0x11;
-static CMD_JVS_VERSION: u8 = 0x12;
-static CMD_COMMS_VERSION: u8 = 0x13;
-static CMD_CAPABILITIES: u8 = 0x14;
-// static CMD_CONVEY_ID: u8 = 0x15;
-static CMD_READ_DIGITAL: u8 = 0x20;
-type InputMapping = [[Option<c_int>; 8]; 4];
+/// What the board answered during init. Versions are BCD, 0x13 is 1.3
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BoardInfo {
+    pub identify: String,
+    pub command_revision: u8,
+    pub jvs_version: u8,
+    pub comms_version: u8,
+    pub capabilities: Capabilities,
+}
+
+/// Most switch bytes per player that are read
+pub const MAX_PLAYER_BYTES: usize = 4;
+
+/// Longest request data: GPO_ALL with a byte count and 32 output bytes, enough for all 255
+/// outputs [jvs.lamps] can set
+const MAX_REQUEST_DATA: usize = 2 + 32;
+
+/// System switches and switch bytes of both players, as READ_DIGITAL reports them. Bytes the
+/// board doesn't have are 0
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SwitchState {
+    pub system: u8,
+    pub players: [[u8; MAX_PLAYER_BYTES]; 2],
+}
 
+/// JVS client for the Finale I/O board. Polls switches for key presses and the emulated board
 pub struct RingEdge2 {
-    pub buf_writer: BufWriter<serialport::COMPort>,
+    pub buf_writer: BufWriter<Box<dyn Transport>>,
     keyboard: Keyboard,
 
-    service_key: c_int,
-    test_key: c_int,
-    input_map: InputMapping,
+    /// Keys of inputs in mapping::INPUTS order
+    keys: [KeyCode; 18],
+    mapping: JvsMapping,
 
-    req_packet: rs232::RequestPacket<16>,
+    /// Players and bytes per player READ_DIGITAL asks for, coin slots READ_COIN asks for.
+    /// Taken from the board's capabilities by init
+    switch_players: u8,
+    switch_bytes: u8,
+    coin_slots: u8,
+
+    coin_key_code: KeyCode,
+    coin_key: CoinKey,
+    coins: CoinTracker,
+
+    /// Fits MAX_REQUEST_DATA, header and sum
+    req_packet: rs232::RequestPacket<{ MAX_REQUEST_DATA + 4 }>,
     res_packet: rs232::ResponsePacket<128>,
 }
 
@@ -48,16 +130,29 @@ impl RingEdge2 {
     pub fn new(
         port_name: String,
         input_settings: config::Input,
-    ) -> Result<Self, serialport::Error> {
-        let mut port = serialport::new(port_name, 115_200).open_native()?;
+        mapping: JvsMapping,
+    ) -> io::Result<Self> {
+        let port = transport::open(&port_name, 115_200)?;
+        Self::with_transport(port, input_settings, mapping)
+    }
+
+    pub fn with_transport(
+        mut port: Box<dyn Transport>,
+        input_settings: config::Input,
+        mapping: JvsMapping,
+    ) -> io::Result<Self> {
         port.set_timeout(Duration::from_millis(500))?;
-        let input_map = map_input_settings(&input_settings);
         Ok(Self {
             buf_writer: BufWriter::new(port),
             keyboard: Keyboard::new(),
-            service_key: input_settings.service,
-            test_key: input_settings.test,
-            input_map,
+            keys: input_keys(&input_settings),
+            mapping,
+            switch_players: 2,
+            switch_bytes: 2,
+            coin_slots: COIN_SLOTS,
+            coin_key_code: input_settings.coin,
+            coin_key: CoinKey::default(),
+            coins: CoinTracker::default(),
             req_packet: rs232::RequestPacket::default(),
             res_packet: rs232::ResponsePacket::default(),
         })
@@ -65,6 +160,16 @@ impl RingEdge2 {
 
     /// Writes a request packet to JVS Com port and immediately wait for a response, muting self.res_packet
     fn cmd(&mut self, dest: u8, data: &[u8]) -> io::Result<()> {
+        if data.len() > MAX_REQUEST_DATA {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "JVS request is {} bytes, at most {} are sent",
+                    data.len(),
+                    MAX_REQUEST_DATA
+                ),
+            ));
+        }
         self.req_packet
             .set_dest(dest)
             .set_data(data)
@@ -84,7 +189,29 @@ impl RingEdge2 {
         Ok(())
     }
 
-    pub fn init(&mut self, board: u8) -> io::Result<()> {
+    /// Sends a command and returns its report data, without the report byte
+    fn report(&mut self, board: u8, data: &[u8], min_len: usize) -> io::Result<&[u8]> {
+        self.cmd(board, data)?;
+        let status = self.res_packet.status();
+        let response = self.res_packet.data();
+        match response.split_first() {
+            Some((&REPORT_NORMAL, report))
+                if status == STATUS_NORMAL && report.len() >= min_len =>
+            {
+                Ok(report)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Board answered {:02X?} with status {:02X}, {:02X?}",
+                    data, status, response
+                ),
+            )),
+        }
+    }
+
+    /// Resets the JVS bus, assigns `board` address and reads the board information
+    pub fn init(&mut self, board: u8) -> io::Result<BoardInfo> {
         info!("JVS: Initializing");
 
         self.reset()?;
@@ -94,135 +221,286 @@ impl RingEdge2 {
         self.cmd(BROADCAST, &[CMD_ASSIGN_ADDRESS, board])?;
         info!("JVS: Assigned address {}", board,);
 
-        self.cmd(board, &[CMD_IDENTIFY])?;
-        info!(
-            "JVS: Board Info: {}",
-            std::str::from_utf8(self.res_packet.data()).unwrap()
-        );
+        let identify = self.report(board, &[CMD_IDENTIFY], 0)?;
+        let identify = identify.split(|&b| b == 0).next().unwrap_or_default();
+        let identify = String::from_utf8_lossy(identify).into_owned();
+        info!("JVS: Board Info: {}", identify);
 
-        self.cmd(board, &[CMD_COMMAND_REVISION])?;
+        let command_revision = self.report(board, &[CMD_COMMAND_REVISION], 1)?[0];
         info!(
             "JVS: Command Version Revision: REV{}.{}",
-            self.res_packet.data()[0] / 10,
-            self.res_packet.data()[0] % 10
+            command_revision >> 4,
+            command_revision & 0x0F
         );
 
-        self.cmd(board, &[CMD_JVS_VERSION])?;
+        let jvs_version = self.report(board, &[CMD_JVS_VERSION], 1)?[0];
         info!(
             "JVS: JVS Version: {}.{}",
-            self.res_packet.data()[0] / 10,
-            self.res_packet.data()[0] % 10
+            jvs_version >> 4,
+            jvs_version & 0x0F
         );
 
-        self.cmd(board, &[CMD_COMMS_VERSION])?;
+        let comms_version = self.report(board, &[CMD_COMMS_VERSION], 1)?[0];
         info!(
             "JVS: Communications Version: {}.{}",
-            self.res_packet.data()[0] / 10,
-            self.res_packet.data()[0] % 10
+            comms_version >> 4,
+            comms_version & 0x0F
         );
 
-        self.cmd(board, &[CMD_CAPABILITIES])?;
-        info!("JVS: Feature check: {:02X?}", self.res_packet.data());
-
-        Ok(())
+        let capabilities = Capabilities::parse(self.report(board, &[CMD_CAPABILITIES], 0)?)?;
+        info!("JVS: Features: {}", capabilities);
+        let (players, _) = capabilities.switches();
+        self.switch_players = players.min(2);
+        self.switch_bytes = capabilities.switch_bytes().min(MAX_PLAYER_BYTES as u8);
+        self.coin_slots = capabilities.coin_slots().min(COIN_SLOTS);
+
+        Ok(BoardInfo {
+            identify,
+            command_revision,
+            jvs_version,
+            comms_version,
+            capabilities,
+        })
     }
 
-    fn read_digital(&mut self, board: u8) -> io::Result<()> {
-        self.cmd(board, &[CMD_READ_DIGITAL, 0x02, 0x02])?;
+    /// Reads system and player switches from `board`, as many as it has
+    pub fn read_switches(&mut self, board: u8) -> io::Result<SwitchState> {
+        let (players, bytes) = (self.switch_players, self.switch_bytes);
+        let data = self.report(
+            board,
+            &[CMD_READ_DIGITAL, players, bytes],
+            1 + players as usize * bytes as usize,
+        )?;
+        let mut switches = SwitchState {
+            system: data[0],
+            ..SwitchState::default()
+        };
+        for (player, player_data) in data[1..]
+            .chunks(bytes.max(1) as usize)
+            .take(players as usize)
+            .enumerate()
+        {
+            switches.players[player][..player_data.len()].copy_from_slice(player_data);
+        }
+        Ok(switches)
+    }
 
-        // debug!("{:02X?}", self.res_packet.get_slice());
+    /// Reads switch inputs from `board` and presses/releases mapped keys
+    pub fn read_digital(&mut self, board: u8) -> io::Result<()> {
+        let switches = self.read_switches(board)?;
+        self.press_keys(&switches);
+        Ok(())
+    }
 
-        let data = self.res_packet.data();
-        if bit_read(&data[2], 6) {
-            self.keyboard.key_down(&self.test_key);
-        } else {
-            self.keyboard.key_up(&self.test_key);
+    /// Presses/releases keys of inputs by [jvs.mapping] for switches read from the board
+    pub fn press_keys(&mut self, switches: &SwitchState) {
+        for (key, pressed) in self.keys.iter().zip(self.mapping.pressed(switches)) {
+            if pressed {
+                self.keyboard.key_down(key);
+            } else {
+                self.keyboard.key_up(key);
+            }
         }
+    }
 
-        if bit_read(&data[1], 7) {
-            self.keyboard.key_down(&self.service_key);
-        } else {
-            self.keyboard.key_up(&self.service_key);
+    /// Reads counters and conditions of the coin slots, slots the board doesn't have are empty
+    pub fn read_coins(&mut self, board: u8) -> io::Result<[CoinSlot; COIN_SLOTS as usize]> {
+        let slots = self.coin_slots;
+        let data = self.report(board, &[CMD_READ_COIN, slots], 2 * slots as usize)?;
+        let mut coins = [CoinSlot::default(); COIN_SLOTS as usize];
+        for (coin, bytes) in coins.iter_mut().zip(data.chunks_exact(2)) {
+            *coin = CoinSlot::from_bytes([bytes[0], bytes[1]]);
         }
+        Ok(coins)
+    }
 
-        for (i, bit) in data[2..=5].iter().enumerate() {
-            for bit_pos in 0..=7 {
-                if let Some(key) = self.input_map[i][bit_pos] {
-                    if !bit_read(bit, bit_pos) {
-                        self.keyboard.key_down(&key);
-                    } else {
-                        self.keyboard.key_up(&key);
-                    }
-                }
+    /// Decreases the board's counter of `slot` (from 0) by `coins`
+    pub fn decrease_coins(&mut self, board: u8, slot: usize, coins: u16) -> io::Result<()> {
+        let [high, low] = coins.to_be_bytes();
+        self.report(board, &[CMD_DECREASE_COIN, slot as u8 + 1, high, low], 0)?;
+        Ok(())
+    }
+
+    /// Returns coins inserted into every slot since the last poll and takes them off the
+    /// board's counters, see jvs::coin. The first poll returns nothing and only clears the
+    /// counters
+    pub fn poll_coins(&mut self, board: u8) -> io::Result<[u16; COIN_SLOTS as usize]> {
+        let slots = self.read_coins(board)?;
+        let coins = self.coins.update(&slots);
+        for (slot, &count) in coins.iter().enumerate() {
+            if count > 0 {
+                info!("JVS: {} coin(s) inserted into slot {}", count, slot + 1);
+            }
+            // Also what's left from the first read or a decrease that failed
+            let count = self.coins.counted(slot);
+            if count == 0 {
+                continue;
+            }
+            match self.decrease_coins(board, slot, count) {
+                Ok(()) => self.coins.decreased(slot, count),
+                Err(err) => warn!("JVS: can't decrease coin counter: {}", err),
             }
         }
+        Ok(coins)
+    }
+
+    /// Queues a coin key press per coin and presses/releases it, call once per coin poll
+    pub fn press_coin_key(&mut self, coins: u16) {
+        self.coin_key.add(coins);
+        match self.coin_key.tick() {
+            Some(true) => self.keyboard.key_down(&self.coin_key_code),
+            Some(false) => self.keyboard.key_up(&self.coin_key_code),
+            None => {}
+        }
+    }
+
+    /// Sends a general purpose output command, see jvs::lamp
+    pub fn write_outputs(&mut self, board: u8, command: &[u8]) -> io::Result<()> {
+        self.report(board, command, 0)?;
         Ok(())
     }
+
+    /// [jvs.mapping] the client was created with
+    pub fn mapping(&self) -> &JvsMapping {
+        &self.mapping
+    }
 }
 
-fn map_input_settings(settings: &config::Input) -> InputMapping {
+/// Keys from [input] in mapping::INPUTS order
+fn input_keys(settings: &config::Input) -> [KeyCode; 18] {
     [
-        [
-            Some(settings.p1_btn3),
-            None,
-            Some(settings.p1_btn1),
-            Some(settings.p1_btn2),
-            None,
-            None,
-            None,
-            None,
-        ],
-        [
-            None,
-            None,
-            None,
-            Some(settings.p1_btn8),
-            Some(settings.p1_btn7),
-            Some(settings.p1_btn6),
-            Some(settings.p1_btn5),
-            Some(settings.p1_btn4),
-        ],
-        [
-            Some(settings.p2_btn3),
-            None,
-            Some(settings.p2_btn1),
-            Some(settings.p2_btn2),
-            None,
-            None,
-            None,
-            None,
-        ],
-        [
-            None,
-            None,
-            None,
-            Some(settings.p2_btn8),
-            Some(settings.p2_btn7),
-            Some(settings.p2_btn6),
-            Some(settings.p2_btn5),
-            Some(settings.p2_btn4),
-        ],
+        settings.test,
+        settings.service,
+        settings.p1_btn1,
+        settings.p1_btn2,
+        settings.p1_btn3,
+        settings.p1_btn4,
+        settings.p1_btn5,
+        settings.p1_btn6,
+        settings.p1_btn7,
+        settings.p1_btn8,
+        settings.p2_btn1,
+        settings.p2_btn2,
+        settings.p2_btn3,
+        settings.p2_btn4,
+        settings.p2_btn5,
+        settings.p2_btn6,
+        settings.p2_btn7,
+        settings.p2_btn8,
     ]
 }
 
+/// Spawns the Finale JVS polling thread and, if [jvs] output includes the device, the thread of
+/// the emulated board that Deluxe reads
 pub fn spawn_thread(
     args: &Config,
     running: Arc<AtomicBool>,
-) -> io::Result<JoinHandle<io::Result<()>>> {
-    let mut jvs = RingEdge2::new(args.settings.jvs_re2_com.clone(), args.input.clone())?;
-    jvs.init(1)?;
-
-    let jvs_handle = thread::Builder::new()
-        .name("Finale JVS Thread".to_string())
-        .spawn(move || -> io::Result<()> {
-            while running.load(Ordering::Acquire) {
-                if let Err(err) = jvs.read_digital(1) {
-                    error!("JVS: error: {}", err);
-                };
-            }
-            Ok(())
-        })
-        .unwrap();
+) -> io::Result<Vec<JoinHandle<io::Result<()>>>> {
+    let output = args.jvs.output;
+    let mapping = JvsMapping::from_mapping(&args.jvs.mapping)?;
+    let mut lamps = Lamps::from_config(&args.jvs.lamps)?;
+    if lamps.source() == LampSource::Deluxe && !output.to_device() {
+        warn!("JVS: lamps follow Deluxe, but [jvs] output doesn't include the emulated board");
+    }
+    let mut jvs = RingEdge2::new(
+        args.settings.jvs_re2_com.clone(),
+        args.input.clone(),
+        mapping,
+    )?;
+    let board_info = jvs.init(1)?;
+    jvs.mapping().check_board(&board_info.capabilities)?;
+    lamps.check_board(&board_info.capabilities)?;
+    let poll_coins = board_info.capabilities.coin_slots() > 0;
+    if !poll_coins {
+        warn!("JVS: board has no coin slots, coins are not read");
+    }
 
-    Ok(jvs_handle)
+    let mut handles = Vec::new();
+    let state = Arc::new(Mutex::new(JvsState::default()));
+    if output.to_device() {
+        let mut device = JvsDevice::new(
+            args.settings.jvs_alls_com.clone(),
+            board_info,
+            state.clone(),
+        )?;
+        info!(
+            "JVS: Emulating I/O board for Deluxe on {}",
+            args.settings.jvs_alls_com
+        );
+        let running = running.clone();
+        handles.push(
+            thread::Builder::new()
+                .name("Deluxe JVS Thread".to_string())
+                .spawn(move || device.run(&running))
+                .unwrap(),
+        );
+    }
+
+    handles.push(
+        thread::Builder::new()
+            .name("Finale JVS Thread".to_string())
+            .spawn(move || -> io::Result<()> {
+                let mut coins_read = Instant::now();
+                while running.load(Ordering::Acquire) {
+                    if poll_coins && coins_read.elapsed() >= COIN_POLL_INTERVAL {
+                        coins_read = Instant::now();
+                        match jvs.poll_coins(1) {
+                            Ok(coins) => add_coins(&mut jvs, &state, output, coins),
+                            Err(err) => error!("JVS: error: {}", err),
+                        }
+                    }
+
+                    let switches = match jvs.read_switches(1) {
+                        Ok(switches) => switches,
+                        Err(err) => {
+                            error!("JVS: error: {}", err);
+                            continue;
+                        }
+                    };
+                    if output.to_keyboard() {
+                        jvs.press_keys(&switches);
+                    }
+                    if output.to_device() {
+                        state.lock().unwrap().switches = jvs.mapping().to_finale_layout(&switches);
+                    }
+
+                    let game_outputs = match lamps.source() {
+                        LampSource::Deluxe => state.lock().unwrap().outputs.clone(),
+                        _ => None,
+                    };
+                    if let Some(command) =
+                        lamps.next_command(Instant::now(), game_outputs.as_deref())
+                    {
+                        if let Err(err) = jvs.write_outputs(1, &command) {
+                            error!("JVS: can't set lamps: {}", err);
+                            lamps.write_failed();
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .unwrap(),
+    );
+
+    Ok(handles)
+}
+
+/// Passes coins from a poll on to where [jvs] output points
+fn add_coins(
+    jvs: &mut RingEdge2,
+    state: &Mutex<JvsState>,
+    output: JvsOutput,
+    coins: [u16; COIN_SLOTS as usize],
+) {
+    if output.to_keyboard() {
+        jvs.press_coin_key(coins.iter().sum());
+    }
+    if output.to_device() {
+        let conditions = jvs.coins.conditions();
+        let mut state = state.lock().unwrap();
+        for ((slot, added), condition) in state.coins.iter_mut().zip(coins).zip(conditions) {
+            slot.count = slot.count.saturating_add(added).min(MAX_COINS);
+            slot.condition = condition;
+        }
+    }
 }
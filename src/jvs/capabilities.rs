@@ -0,0 +1,282 @@
+// Feature list of a JVS board (CAPABILITIES, 0x14).
+//
+// The report is a list of 4 byte entries, a function code and 3 parameters, ended by a 0x00
+// code. The client sizes its requests by it (players and switch bytes of READ_DIGITAL, coin
+// slots) and checks at startup that the board has what [jvs.mapping] and [jvs.lamps] use.
+// The emulated board repeats the list to Deluxe as it was read.
+
+use std::fmt;
+use std::io;
+
+/// One entry of the feature list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Switches {
+        players: u8,
+        switches: u8,
+    },
+    Coins {
+        slots: u8,
+    },
+    Analog {
+        channels: u8,
+        bits: u8,
+    },
+    Rotary {
+        channels: u8,
+    },
+    Keycode,
+    ScreenPosition {
+        x_bits: u8,
+        y_bits: u8,
+        channels: u8,
+    },
+    MiscSwitches {
+        switches: u16,
+    },
+    Card {
+        slots: u8,
+    },
+    Hopper {
+        channels: u8,
+    },
+    Outputs {
+        outputs: u8,
+    },
+    AnalogOutputs {
+        channels: u8,
+    },
+    Characters {
+        width: u8,
+        height: u8,
+        kind: u8,
+    },
+    Backup,
+    /// Function code this doesn't know, with its parameters
+    Unknown([u8; 4]),
+}
+
+impl Feature {
+    pub fn from_bytes(entry: [u8; 4]) -> Self {
+        let [code, a, b, c] = entry;
+        match code {
+            0x01 => Feature::Switches {
+                players: a,
+                switches: b,
+            },
+            0x02 => Feature::Coins { slots: a },
+            0x03 => Feature::Analog {
+                channels: a,
+                bits: b,
+            },
+            0x04 => Feature::Rotary { channels: a },
+            0x05 => Feature::Keycode,
+            0x06 => Feature::ScreenPosition {
+                x_bits: a,
+                y_bits: b,
+                channels: c,
+            },
+            0x07 => Feature::MiscSwitches {
+                switches: u16::from_be_bytes([a, b]),
+            },
+            0x10 => Feature::Card { slots: a },
+            0x11 => Feature::Hopper { channels: a },
+            0x12 => Feature::Outputs { outputs: a },
+            0x13 => Feature::AnalogOutputs { channels: a },
+            0x14 => Feature::Characters {
+                width: a,
+                height: b,
+                kind: c,
+            },
+            0x15 => Feature::Backup,
+            _ => Feature::Unknown(entry),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 4] {
+        match *self {
+            Feature::Switches { players, switches } => [0x01, players, switches, 0],
+            Feature::Coins { slots } => [0x02, slots, 0, 0],
+            Feature::Analog { channels, bits } => [0x03, channels, bits, 0],
+            Feature::Rotary { channels } => [0x04, channels, 0, 0],
+            Feature::Keycode => [0x05, 0, 0, 0],
+            Feature::ScreenPosition {
+                x_bits,
+                y_bits,
+                channels,
+            } => [0x06, x_bits, y_bits, channels],
+            Feature::MiscSwitches { switches } => {
+                let [high, low] = switches.to_be_bytes();
+                [0x07, high, low, 0]
+            }
+            Feature::Card { slots } => [0x10, slots, 0, 0],
+            Feature::Hopper { channels } => [0x11, channels, 0, 0],
+            Feature::Outputs { outputs } => [0x12, outputs, 0, 0],
+            Feature::AnalogOutputs { channels } => [0x13, channels, 0, 0],
+            Feature::Characters {
+                width,
+                height,
+                kind,
+            } => [0x14, width, height, kind],
+            Feature::Backup => [0x15, 0, 0, 0],
+            Feature::Unknown(entry) => entry,
+        }
+    }
+}
+
+/// Decoded CAPABILITIES report, features in the order the board listed them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub features: Vec<Feature>,
+}
+
+impl Capabilities {
+    /// Decodes the report data (without the report byte), up to the 0x00 code
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        let mut features = Vec::new();
+        let mut entries = data.chunks(4);
+        loop {
+            match entries.next() {
+                Some([0x00, ..]) => break,
+                Some(&[code, a, b, c]) => features.push(Feature::from_bytes([code, a, b, c])),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Feature list {:02X?} is cut off", data),
+                    ))
+                }
+            }
+        }
+        Ok(Self { features })
+    }
+
+    /// Report data, with the 0x00 code at the end
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data: Vec<u8> = self.features.iter().flat_map(Feature::to_bytes).collect();
+        data.push(0x00);
+        data
+    }
+
+    /// (players, switches per player), (0, 0) without switch inputs
+    pub fn switches(&self) -> (u8, u8) {
+        self.features
+            .iter()
+            .find_map(|feature| match *feature {
+                Feature::Switches { players, switches } => Some((players, switches)),
+                _ => None,
+            })
+            .unwrap_or((0, 0))
+    }
+
+    /// Bytes READ_DIGITAL returns per player
+    pub fn switch_bytes(&self) -> u8 {
+        self.switches().1.div_ceil(8)
+    }
+
+    pub fn coin_slots(&self) -> u8 {
+        self.features
+            .iter()
+            .find_map(|feature| match *feature {
+                Feature::Coins { slots } => Some(slots),
+                _ => None,
+            })
+            .unwrap_or(0)
+    }
+
+    /// General purpose outputs
+    pub fn outputs(&self) -> u8 {
+        self.features
+            .iter()
+            .find_map(|feature| match *feature {
+                Feature::Outputs { outputs } => Some(outputs),
+                _ => None,
+            })
+            .unwrap_or(0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let features: Vec<_> = self
+            .features
+            .iter()
+            .map(|feature| match *feature {
+                Feature::Switches { players, switches } => {
+                    format!("{players} player(s) x {switches} switches")
+                }
+                Feature::Coins { slots } => format!("{slots} coin slot(s)"),
+                Feature::Analog { channels, bits } => {
+                    format!("{channels} analog input(s) of {bits} bits")
+                }
+                Feature::Rotary { channels } => format!("{channels} rotary input(s)"),
+                Feature::Keycode => "keycode input".to_string(),
+                Feature::ScreenPosition {
+                    x_bits,
+                    y_bits,
+                    channels,
+                } => format!("{channels} screen position input(s) of {x_bits}x{y_bits} bits"),
+                Feature::MiscSwitches { switches } => format!("{switches} misc switches"),
+                Feature::Card { slots } => format!("{slots} card slot(s)"),
+                Feature::Hopper { channels } => format!("{channels} medal hopper(s)"),
+                Feature::Outputs { outputs } => format!("{outputs} outputs"),
+                Feature::AnalogOutputs { channels } => format!("{channels} analog output(s)"),
+                Feature::Characters {
+                    width,
+                    height,
+                    kind,
+                } => format!("{width}x{height} character display (type {kind})"),
+                Feature::Backup => "backup".to_string(),
+                Feature::Unknown(entry) => format!("unknown {:02X?}", entry),
+            })
+            .collect();
+        write!(f, "{}", features.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jvs::capabilities::{Capabilities, Feature};
+
+    #[test]
+    pub fn parses_and_repeats_feature_list() {
+        let data = [
+            0x01, 2, 13, 0, 0x02, 2, 0, 0, 0x03, 8, 10, 0, 0x12, 22, 0, 0, 0x7E, 1, 2, 3, 0x00,
+        ];
+        let capabilities = Capabilities::parse(&data).unwrap();
+        assert_eq!(
+            capabilities.features,
+            [
+                Feature::Switches {
+                    players: 2,
+                    switches: 13
+                },
+                Feature::Coins { slots: 2 },
+                Feature::Analog {
+                    channels: 8,
+                    bits: 10
+                },
+                Feature::Outputs { outputs: 22 },
+                Feature::Unknown([0x7E, 1, 2, 3]),
+            ]
+        );
+        assert_eq!(capabilities.switches(), (2, 13));
+        assert_eq!(capabilities.switch_bytes(), 2);
+        assert_eq!(capabilities.coin_slots(), 2);
+        assert_eq!(capabilities.outputs(), 22);
+        assert_eq!(capabilities.to_bytes(), data);
+        assert_eq!(
+            capabilities.to_string(),
+            "2 player(s) x 13 switches, 2 coin slot(s), 8 analog input(s) of 10 bits, \
+             22 outputs, unknown [7E, 01, 02, 03]"
+        );
+    }
+
+    #[test]
+    pub fn missing_features_and_cut_off_list() {
+        let capabilities = Capabilities::parse(&[0x00]).unwrap();
+        assert_eq!(capabilities.switches(), (0, 0));
+        assert_eq!(capabilities.coin_slots(), 0);
+        assert!(Capabilities::parse(&[0x01, 2, 13]).is_err());
+        assert!(Capabilities::parse(&[0x01, 2, 13, 0]).is_err());
+    }
+}
@@ -0,0 +1,211 @@
+// Coins from the Finale coin mech.
+//
+// The board counts coins itself: READ_COIN gives a 14 bit counter per slot, with the slot
+// condition (jam, disconnected counter, busy) in the top two bits. The bridge reads it every
+// COIN_POLL_INTERVAL, takes what was added since the last read and decreases the board's
+// counter by the same amount, so it never fills up and a coin inserted in between is seen on
+// the next read. Coins already on the counters at the first read were inserted before the bridge
+// started (or left by a previous run): they are taken off the same way, but not credited. New
+// coins press the [input] coin key (once per coin) and/or go to the
+// emulated board's counter, which the game decreases itself, following [jvs] output.
+
+use std::time::Duration;
+
+use log::{info, warn};
+
+/// Slots READ_COIN asks for
+pub const COIN_SLOTS: u8 = 2;
+
+/// Most a coin counter holds, the other two bits are the condition
+pub const MAX_COINS: u16 = 0x3FFF;
+
+/// How often coins are read, also how long the coin key is held for a coin
+pub const COIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CoinCondition {
+    #[default]
+    Normal,
+    Jammed,
+    CounterDisconnected,
+    Busy,
+}
+
+/// Counter and condition of one coin slot, as READ_COIN reports them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CoinSlot {
+    pub condition: CoinCondition,
+    pub count: u16,
+}
+
+impl CoinSlot {
+    pub fn from_bytes(bytes: [u8; 2]) -> Self {
+        let condition = match bytes[0] >> 6 {
+            0 => CoinCondition::Normal,
+            1 => CoinCondition::Jammed,
+            2 => CoinCondition::CounterDisconnected,
+            _ => CoinCondition::Busy,
+        };
+        Self {
+            condition,
+            count: u16::from_be_bytes(bytes) & MAX_COINS,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 2] {
+        let condition = match self.condition {
+            CoinCondition::Normal => 0,
+            CoinCondition::Jammed => 1,
+            CoinCondition::CounterDisconnected => 2,
+            CoinCondition::Busy => 3,
+        };
+        ((condition << 14) | self.count.min(MAX_COINS)).to_be_bytes()
+    }
+}
+
+/// Finds coins added to the board counters between reads
+#[derive(Debug, Default)]
+pub struct CoinTracker {
+    last: [CoinSlot; COIN_SLOTS as usize],
+    /// First read was taken as the starting point
+    seeded: bool,
+}
+
+impl CoinTracker {
+    /// Coins added to every slot since the last read. A counter that went down (board reset,
+    /// or decreased by someone else) counts from zero. The first read only sets the starting
+    /// point, nothing is added. Condition changes are logged
+    pub fn update(
+        &mut self,
+        slots: &[CoinSlot; COIN_SLOTS as usize],
+    ) -> [u16; COIN_SLOTS as usize] {
+        let first = !std::mem::replace(&mut self.seeded, true);
+        let mut coins = [0; COIN_SLOTS as usize];
+        for (slot, (last, current)) in self.last.iter_mut().zip(slots).enumerate() {
+            if current.condition != last.condition {
+                match current.condition {
+                    CoinCondition::Normal => info!("JVS: coin slot {} is back to normal", slot + 1),
+                    condition => warn!("JVS: coin slot {}: {:?}", slot + 1, condition),
+                }
+            }
+            if first {
+                if current.count > 0 {
+                    info!(
+                        "JVS: coin slot {} already counted {} coin(s), they are not credited",
+                        slot + 1,
+                        current.count
+                    );
+                }
+            } else {
+                coins[slot] = current
+                    .count
+                    .checked_sub(last.count)
+                    .unwrap_or(current.count);
+            }
+            *last = *current;
+        }
+        coins
+    }
+
+    /// Coins seen on the board counter of `slot` that are still on it, what to take off
+    pub fn counted(&self, slot: usize) -> u16 {
+        self.last[slot].count
+    }
+
+    /// Conditions from the last read
+    pub fn conditions(&self) -> [CoinCondition; COIN_SLOTS as usize] {
+        self.last.map(|slot| slot.condition)
+    }
+
+    /// Board counter of `slot` was decreased by `coins`
+    pub fn decreased(&mut self, slot: usize, coins: u16) {
+        self.last[slot].count = self.last[slot].count.saturating_sub(coins);
+    }
+}
+
+/// Turns coins into presses of one key, a press and a release per poll each
+#[derive(Debug, Default)]
+pub struct CoinKey {
+    pending: u32,
+    held: bool,
+}
+
+impl CoinKey {
+    pub fn add(&mut self, coins: u16) {
+        self.pending += coins as u32;
+    }
+
+    /// Called every poll, returns Some(true) to press the key and Some(false) to release it
+    pub fn tick(&mut self) -> Option<bool> {
+        if self.held {
+            self.held = false;
+            Some(false)
+        } else if self.pending > 0 {
+            self.pending -= 1;
+            self.held = true;
+            Some(true)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jvs::coin::{CoinCondition, CoinKey, CoinSlot, CoinTracker};
+
+    fn slot(count: u16) -> CoinSlot {
+        CoinSlot {
+            condition: CoinCondition::Normal,
+            count,
+        }
+    }
+
+    #[test]
+    pub fn coin_slot_bytes() {
+        let jammed = CoinSlot::from_bytes([0x41, 0x02]);
+        assert_eq!(jammed.condition, CoinCondition::Jammed);
+        assert_eq!(jammed.count, 0x102);
+        assert_eq!(jammed.to_bytes(), [0x41, 0x02]);
+        assert_eq!(slot(0xFFFF).to_bytes(), [0x3F, 0xFF]);
+    }
+
+    #[test]
+    pub fn tracker_starts_from_first_read() {
+        let mut tracker = CoinTracker::default();
+        // Coins inserted before the bridge started are taken off, but not credited
+        assert_eq!(tracker.update(&[slot(7), slot(0)]), [0, 0]);
+        assert_eq!(tracker.counted(0), 7);
+        tracker.decreased(0, 7);
+        assert_eq!(tracker.counted(0), 0);
+        assert_eq!(tracker.update(&[slot(1), slot(0)]), [1, 0]);
+        assert_eq!(tracker.counted(0), 1);
+    }
+
+    #[test]
+    pub fn tracker_counts_new_coins_only() {
+        let mut tracker = CoinTracker::default();
+        assert_eq!(tracker.update(&[slot(0), slot(0)]), [0, 0]);
+        assert_eq!(tracker.update(&[slot(2), slot(0)]), [2, 0]);
+        tracker.decreased(0, 2);
+        // Decreased to 0, then one more came in
+        assert_eq!(tracker.update(&[slot(1), slot(1)]), [1, 1]);
+        assert_eq!(tracker.update(&[slot(1), slot(1)]), [0, 0]);
+        // Board restarted with its own count
+        tracker.update(&[slot(5), slot(1)]);
+        assert_eq!(tracker.update(&[slot(3), slot(1)]), [3, 0]);
+
+        let mut jammed = slot(3);
+        jammed.condition = CoinCondition::Jammed;
+        assert_eq!(tracker.update(&[jammed, slot(1)]), [0, 0]);
+    }
+
+    #[test]
+    pub fn key_is_pressed_once_per_coin() {
+        let mut key = CoinKey::default();
+        assert_eq!(key.tick(), None);
+        key.add(2);
+        let presses: Vec<_> = std::iter::from_fn(|| key.tick()).collect();
+        assert_eq!(presses, [true, false, true, false]);
+    }
+}
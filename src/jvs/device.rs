@@ -0,0 +1,517 @@
+// JVS I/O board emulation for Deluxe.
+//
+// With `output = "device"` (or "both") in [jvs] the bridge is the I/O board Deluxe talks to:
+// it answers the game's JVS requests on `jvs_alls_com` with the switches last read from the
+// Finale board, so buttons work without key presses and without the game window in focus.
+// Deluxe cabinets are converted Finale cabinets, so the game gets what the Finale board itself
+// would say: its identity, versions and feature list from init, and its switch bytes as they
+// are. There is no sense line on a virtual port, the board is always the only one on the bus.
+
+use std::io;
+use std::io::{BufReader, BufWriter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use crate::helper_funcs::{ReadExt, SYNC};
+use crate::jvs::coin::{CoinSlot, COIN_SLOTS, MAX_COINS};
+use crate::jvs::{
+    BoardInfo, SwitchState, BROADCAST, CMD_ASSIGN_ADDRESS, CMD_CAPABILITIES, CMD_COMMAND_REVISION,
+    CMD_COMMS_VERSION, CMD_CONVEY_ID, CMD_DECREASE_COIN, CMD_GPO_ALL, CMD_GPO_BIT, CMD_GPO_BYTE,
+    CMD_IDENTIFY, CMD_INCREASE_COIN, CMD_JVS_VERSION, CMD_READ_COIN, CMD_READ_DIGITAL, CMD_RESET,
+    CMD_RESET_ARGUMENT, CMD_RETRANSMIT, MASTER, REPORT_NORMAL, STATUS_NORMAL,
+};
+use crate::packets::rs232::write_packet;
+use crate::transport;
+use crate::transport::Transport;
+
+/// How long a read waits for the game before checking whether the bridge is stopping
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+const STATUS_UNKNOWN_COMMAND: u8 = 0x02;
+const STATUS_SUM_ERROR: u8 = 0x03;
+const STATUS_OVERFLOW: u8 = 0x04;
+const REPORT_PARAMETER_ERROR: u8 = 0x02;
+const REPORT_DATA_ERROR: u8 = 0x03;
+
+/// Most output bytes the game can set
+const MAX_OUTPUT_BYTES: usize = 32;
+
+/// Most a response can carry after the status byte: size is one byte and counts status and sum
+const MAX_REPORT_LEN: usize = 253;
+
+/// Inputs the emulated board reports, written by the Finale JVS thread, and outputs the game
+/// sets on it, read by it (see jvs::lamp)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JvsState {
+    pub switches: SwitchState,
+    /// Coin counters per slot, the game takes coins off with DECREASE_COIN
+    pub coins: [CoinSlot; COIN_SLOTS as usize],
+    /// General purpose output bytes, None until the game writes any
+    pub outputs: Option<Vec<u8>>,
+}
+
+/// Status byte and report bytes of one response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u8,
+    pub reports: Vec<u8>,
+}
+
+/// Emulated I/O board on Deluxe's JVS port, see module docs
+pub struct JvsDevice {
+    reader: BufReader<Box<dyn Transport>>,
+    writer: BufWriter<Box<dyn Transport>>,
+    info: BoardInfo,
+    state: Arc<Mutex<JvsState>>,
+    address: Option<u8>,
+    /// Last response sent, for RETRANSMIT
+    last_response: Option<Vec<u8>>,
+}
+
+impl JvsDevice {
+    pub fn new(
+        port_name: String,
+        info: BoardInfo,
+        state: Arc<Mutex<JvsState>>,
+    ) -> io::Result<Self> {
+        let port = transport::open(&port_name, 115_200)?;
+        Self::with_transport(port, info, state)
+    }
+
+    pub fn with_transport(
+        mut port: Box<dyn Transport>,
+        info: BoardInfo,
+        state: Arc<Mutex<JvsState>>,
+    ) -> io::Result<Self> {
+        port.set_timeout(READ_TIMEOUT)?;
+        port.clear()?;
+        Ok(Self {
+            reader: BufReader::new(port.try_clone()?),
+            writer: BufWriter::new(port),
+            info,
+            state,
+            address: None,
+            last_response: None,
+        })
+    }
+
+    /// Address the game assigned, None until ASSIGN_ADDRESS and after RESET
+    pub fn address(&self) -> Option<u8> {
+        self.address
+    }
+
+    /// Answers requests until `running` is cleared
+    pub fn run(&mut self, running: &AtomicBool) -> io::Result<()> {
+        while running.load(Ordering::Acquire) {
+            match self.poll() {
+                Ok(()) => {}
+                Err(err) if transport::is_timeout(&err) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for one request and answers it if it is for this board
+    pub fn poll(&mut self) -> io::Result<()> {
+        while self.reader.read_u8()? != SYNC {}
+        let dest = self.reader.read_u8_escaped()?;
+        let size = self.reader.read_u8_escaped()?;
+        let mut data = Vec::with_capacity(size as usize);
+        for _ in 0..size {
+            data.push(self.reader.read_u8_escaped()?);
+        }
+        let sum = match data.pop() {
+            Some(sum) => sum,
+            None => return Ok(()),
+        };
+
+        let expected = data
+            .iter()
+            .fold(dest.wrapping_add(size), |sum, &b| sum.wrapping_add(b));
+        let response = if sum != expected {
+            warn!(
+                "JVS device: bad checksum in request {:02X?} to {:02X}, got {:02X} instead of {:02X}",
+                data, dest, sum, expected
+            );
+            (Some(dest) == self.address).then(|| Response {
+                status: STATUS_SUM_ERROR,
+                reports: Vec::new(),
+            })
+        } else if data == [CMD_RETRANSMIT] && Some(dest) == self.address {
+            if let Some(packet) = &self.last_response {
+                write_packet(&mut self.writer, packet)?;
+            }
+            return Ok(());
+        } else {
+            self.respond(dest, &data)
+        };
+
+        if let Some(response) = response {
+            let mut packet = vec![
+                SYNC,
+                MASTER,
+                response.reports.len() as u8 + 2,
+                response.status,
+            ];
+            packet.extend(response.reports);
+            // Checksum placeholder, write_packet counts it
+            packet.push(0);
+            write_packet(&mut self.writer, &packet)?;
+            self.last_response = Some(packet);
+        }
+        Ok(())
+    }
+
+    /// Handles the commands of one request, None if it isn't for this board or needs no answer
+    pub fn respond(&mut self, dest: u8, data: &[u8]) -> Option<Response> {
+        match *data {
+            [CMD_RESET, CMD_RESET_ARGUMENT, ..] if dest == BROADCAST => {
+                if self.address.take().is_some() {
+                    info!("JVS device: reset by Deluxe");
+                }
+                return None;
+            }
+            [CMD_ASSIGN_ADDRESS, address, ..] if dest == BROADCAST => {
+                // Already addressed boards pass it on down the chain, there is nobody there
+                if self.address.is_some() {
+                    return None;
+                }
+                info!("JVS device: Deluxe assigned address {}", address);
+                self.address = Some(address);
+                return Some(Response {
+                    status: STATUS_NORMAL,
+                    reports: vec![REPORT_NORMAL],
+                });
+            }
+            _ if Some(dest) != self.address => return None,
+            _ => {}
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let mut reports = Vec::new();
+        let mut rest = data;
+        while let Some(&cmd) = rest.first() {
+            let len = match cmd {
+                CMD_IDENTIFY => {
+                    reports.push(REPORT_NORMAL);
+                    reports.extend(self.info.identify.bytes());
+                    reports.push(0);
+                    1
+                }
+                CMD_COMMAND_REVISION | CMD_JVS_VERSION | CMD_COMMS_VERSION => {
+                    let version = match cmd {
+                        CMD_COMMAND_REVISION => self.info.command_revision,
+                        CMD_JVS_VERSION => self.info.jvs_version,
+                        _ => self.info.comms_version,
+                    };
+                    reports.extend([REPORT_NORMAL, version]);
+                    1
+                }
+                CMD_CAPABILITIES => {
+                    reports.push(REPORT_NORMAL);
+                    reports.extend(self.info.capabilities.to_bytes());
+                    1
+                }
+                CMD_CONVEY_ID => {
+                    reports.push(REPORT_NORMAL);
+                    // Main board ID, NUL terminated
+                    rest.iter()
+                        .position(|&b| b == 0)
+                        .map_or(rest.len(), |end| end + 1)
+                }
+                CMD_READ_DIGITAL if rest.len() >= 3 => {
+                    let (players, bytes) = (rest[1] as usize, rest[2] as usize);
+                    reports.extend([REPORT_NORMAL, state.switches.system]);
+                    for player in 0..players {
+                        for byte in 0..bytes {
+                            let switches = state.switches.players.get(player);
+                            reports.push(switches.and_then(|p| p.get(byte)).copied().unwrap_or(0));
+                        }
+                    }
+                    3
+                }
+                CMD_READ_COIN if rest.len() >= 2 => {
+                    reports.push(REPORT_NORMAL);
+                    for slot in 0..rest[1] as usize {
+                        let coins = state.coins.get(slot).copied().unwrap_or_default();
+                        reports.extend(coins.to_bytes());
+                    }
+                    2
+                }
+                CMD_DECREASE_COIN | CMD_INCREASE_COIN if rest.len() >= 4 => {
+                    let coins = u16::from_be_bytes([rest[2], rest[3]]);
+                    let slot = (rest[1] as usize)
+                        .checked_sub(1)
+                        .and_then(|slot| state.coins.get_mut(slot));
+                    match slot {
+                        Some(slot) if cmd == CMD_DECREASE_COIN => {
+                            slot.count = slot.count.saturating_sub(coins);
+                            reports.push(REPORT_NORMAL);
+                        }
+                        Some(slot) => {
+                            slot.count = slot.count.saturating_add(coins).min(MAX_COINS);
+                            reports.push(REPORT_NORMAL);
+                        }
+                        None => reports.push(REPORT_DATA_ERROR),
+                    }
+                    4
+                }
+                CMD_GPO_ALL if rest.len() >= 2 && rest.len() >= 2 + rest[1] as usize => {
+                    let bytes = rest[1] as usize;
+                    if bytes <= MAX_OUTPUT_BYTES {
+                        state.outputs = Some(rest[2..2 + bytes].to_vec());
+                        reports.push(REPORT_NORMAL);
+                    } else {
+                        reports.push(REPORT_DATA_ERROR);
+                    }
+                    2 + bytes
+                }
+                CMD_GPO_BYTE | CMD_GPO_BIT if rest.len() >= 3 => {
+                    let (index, value) = (rest[1] as usize, rest[2]);
+                    let byte = if cmd == CMD_GPO_BYTE {
+                        index
+                    } else {
+                        index / 8
+                    };
+                    if byte < MAX_OUTPUT_BYTES && (cmd == CMD_GPO_BYTE || value <= 2) {
+                        let outputs = state.outputs.get_or_insert_with(Vec::new);
+                        if outputs.len() <= byte {
+                            outputs.resize(byte + 1, 0);
+                        }
+                        let mask = 0x80 >> (index % 8);
+                        match (cmd, value) {
+                            (CMD_GPO_BYTE, _) => outputs[byte] = value,
+                            (_, 0) => outputs[byte] &= !mask,
+                            (_, 1) => outputs[byte] |= mask,
+                            _ => outputs[byte] ^= mask,
+                        }
+                        reports.push(REPORT_NORMAL);
+                    } else {
+                        reports.push(REPORT_DATA_ERROR);
+                    }
+                    3
+                }
+                CMD_READ_DIGITAL | CMD_READ_COIN | CMD_DECREASE_COIN | CMD_INCREASE_COIN
+                | CMD_GPO_ALL | CMD_GPO_BYTE | CMD_GPO_BIT => {
+                    warn!("JVS device: command {:02X?} from Deluxe is too short", rest);
+                    reports.push(REPORT_PARAMETER_ERROR);
+                    break;
+                }
+                _ => {
+                    warn!(
+                        "JVS device: unknown command {:02X} from Deluxe in {:02X?}",
+                        cmd, data
+                    );
+                    return Some(Response {
+                        status: STATUS_UNKNOWN_COMMAND,
+                        reports,
+                    });
+                }
+            };
+            rest = &rest[len..];
+        }
+
+        if reports.len() > MAX_REPORT_LEN {
+            debug!("JVS device: response to {:02X?} doesn't fit a packet", data);
+            return Some(Response {
+                status: STATUS_OVERFLOW,
+                reports: Vec::new(),
+            });
+        }
+        Some(Response {
+            status: STATUS_NORMAL,
+            reports,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use crate::config;
+    use crate::jvs::capabilities::{Capabilities, Feature};
+    use crate::jvs::device::{JvsDevice, JvsState, Response};
+    use crate::jvs::mapping::JvsMapping;
+    use crate::jvs::{BoardInfo, RingEdge2, SwitchState};
+    use crate::transport::memory::MemoryTransport;
+
+    fn board_info() -> BoardInfo {
+        BoardInfo {
+            identify: "SEGA CORPORATION;I/O BD JVS;837-15257-01;Ver1.00;98/10".to_string(),
+            command_revision: 0x13,
+            jvs_version: 0x30,
+            comms_version: 0x10,
+            capabilities: Capabilities {
+                features: vec![
+                    Feature::Switches {
+                        players: 2,
+                        switches: 13,
+                    },
+                    Feature::Coins { slots: 2 },
+                    Feature::Outputs { outputs: 22 },
+                ],
+            },
+        }
+    }
+
+    fn device() -> (JvsDevice, Arc<Mutex<JvsState>>, MemoryTransport) {
+        let (port, game) = MemoryTransport::pair("jvs");
+        let state = Arc::new(Mutex::new(JvsState::default()));
+        let device =
+            JvsDevice::with_transport(Box::new(port), board_info(), state.clone()).unwrap();
+        (device, state, game)
+    }
+
+    fn normal(reports: &[u8]) -> Option<Response> {
+        Some(Response {
+            status: 0x01,
+            reports: reports.to_vec(),
+        })
+    }
+
+    #[test]
+    pub fn answers_only_after_address_is_assigned() {
+        let (mut device, _, _game) = device();
+        assert_eq!(device.respond(1, &[0x11]), None);
+        assert_eq!(device.respond(0xFF, &[0xF1, 1]), normal(&[0x01]));
+        assert_eq!(device.address(), Some(1));
+        // Nobody further down the chain
+        assert_eq!(device.respond(0xFF, &[0xF1, 2]), None);
+        assert_eq!(device.respond(2, &[0x11]), None);
+        assert_eq!(device.respond(1, &[0x11]), normal(&[0x01, 0x13]));
+
+        assert_eq!(device.respond(0xFF, &[0xF0, 0xD9]), None);
+        assert_eq!(device.address(), None);
+        assert_eq!(device.respond(1, &[0x11]), None);
+    }
+
+    #[test]
+    pub fn several_commands_in_one_request() {
+        let (mut device, state, _game) = device();
+        device.respond(0xFF, &[0xF1, 1]);
+        state.lock().unwrap().switches = SwitchState {
+            system: 0x80,
+            players: [[0x01, 0x02, 0, 0], [0x03, 0x04, 0, 0]],
+        };
+        state.lock().unwrap().coins[0].count = 5;
+
+        // Asking for more than the board has is padded with zeros
+        assert_eq!(
+            device.respond(1, &[0x20, 0x02, 0x03, 0x21, 0x02, 0x12]),
+            normal(&[0x01, 0x80, 0x01, 0x02, 0x00, 0x03, 0x04, 0x00, 0x01, 0, 5, 0, 0, 0x01, 0x30])
+        );
+        assert_eq!(
+            device.respond(1, &[0x15, b'I', b'D', 0, 0x13]),
+            normal(&[0x01, 0x01, 0x10])
+        );
+    }
+
+    #[test]
+    pub fn game_decreases_and_increases_coins() {
+        let (mut device, state, _game) = device();
+        device.respond(0xFF, &[0xF1, 1]);
+        state.lock().unwrap().coins[0].count = 5;
+        assert_eq!(
+            device.respond(1, &[0x30, 0x01, 0x00, 0x02, 0x35, 0x02, 0x00, 0x01]),
+            normal(&[0x01, 0x01])
+        );
+        assert_eq!(state.lock().unwrap().coins[0].count, 3);
+        assert_eq!(state.lock().unwrap().coins[1].count, 1);
+        // No slot 3, more than there is stops at zero
+        assert_eq!(
+            device.respond(1, &[0x30, 0x03, 0x00, 0x01, 0x30, 0x01, 0x01, 0x00]),
+            normal(&[0x03, 0x01])
+        );
+        assert_eq!(state.lock().unwrap().coins[0].count, 0);
+    }
+
+    #[test]
+    pub fn game_sets_outputs() {
+        let (mut device, state, _game) = device();
+        device.respond(0xFF, &[0xF1, 1]);
+        assert_eq!(
+            device.respond(1, &[0x32, 0x02, 0xF0, 0x0F, 0x37, 0x02, 0x55]),
+            normal(&[0x01, 0x01])
+        );
+        assert_eq!(state.lock().unwrap().outputs, Some(vec![0xF0, 0x0F, 0x55]));
+        // Output 0 off, 15 toggled, mode 3 doesn't exist
+        assert_eq!(
+            device.respond(1, &[0x38, 0, 0, 0x38, 15, 2, 0x38, 1, 3]),
+            normal(&[0x01, 0x01, 0x03])
+        );
+        assert_eq!(state.lock().unwrap().outputs, Some(vec![0x70, 0x0E, 0x55]));
+    }
+
+    #[test]
+    pub fn reports_bad_commands() {
+        let (mut device, _, _game) = device();
+        device.respond(0xFF, &[0xF1, 1]);
+        // Reports before the unknown command are kept
+        assert_eq!(
+            device.respond(1, &[0x12, 0x7F, 0x12]),
+            Some(Response {
+                status: 0x02,
+                reports: vec![0x01, 0x30],
+            })
+        );
+        assert_eq!(device.respond(1, &[0x20, 0x02]), normal(&[0x02]));
+        assert_eq!(device.respond(1, &[0x20, 0xFF, 0xFF]).unwrap().status, 0x04);
+    }
+
+    #[test]
+    pub fn finale_client_reads_emulated_board() {
+        let (mut device, state, game) = device();
+        let running = Arc::new(AtomicBool::new(true));
+        let device_thread = {
+            let running = running.clone();
+            thread::spawn(move || device.run(&running))
+        };
+
+        // The bridge's own Finale client is a JVS master too
+        let mut client = RingEdge2::with_transport(
+            Box::new(game),
+            config::Input::default(),
+            JvsMapping::default(),
+        )
+        .unwrap();
+        assert_eq!(client.init(1).unwrap(), board_info());
+
+        let switches = SwitchState {
+            system: 0x80,
+            players: [[0xFB, 0xFF, 0, 0], [0xFF, 0x7F, 0, 0]],
+        };
+        state.lock().unwrap().switches = switches;
+        assert_eq!(client.read_switches(1).unwrap(), switches);
+
+        // Coins counted before the first poll are taken off without being credited
+        state.lock().unwrap().coins[0].count = 2;
+        assert_eq!(client.poll_coins(1).unwrap(), [0, 0]);
+        assert_eq!(state.lock().unwrap().coins[0].count, 0);
+
+        // New coins are taken off the board's counter once they are seen
+        state.lock().unwrap().coins[1].count = 3;
+        assert_eq!(client.poll_coins(1).unwrap(), [0, 3]);
+        assert_eq!(state.lock().unwrap().coins[1].count, 0);
+        assert_eq!(client.poll_coins(1).unwrap(), [0, 0]);
+
+        client.write_outputs(1, &[0x32, 0x01, 0xA0]).unwrap();
+        assert_eq!(state.lock().unwrap().outputs, Some(vec![0xA0]));
+        // Every output [jvs.lamps] can have fits a request
+        let mut gpo_all = vec![0x32, 32];
+        gpo_all.extend([0x55; 32]);
+        client.write_outputs(1, &gpo_all).unwrap();
+        assert_eq!(state.lock().unwrap().outputs, Some(vec![0x55; 32]));
+        gpo_all.push(0x55);
+        assert!(client.write_outputs(1, &gpo_all).is_err());
+
+        running.store(false, Ordering::Release);
+        device_thread.join().unwrap().unwrap();
+    }
+}
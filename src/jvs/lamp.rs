@@ -0,0 +1,296 @@
+// Button lamps through the Finale board's general purpose outputs.
+//
+// The lamps are JVS outputs, numbered from 0 as bits of the output bytes (output 0 is the top
+// bit of the first byte). `source` in [jvs.lamps] picks what drives them: "idle" plays
+// `idle_pattern`, one list of lit outputs per `idle_step_ms`, "deluxe" copies the outputs the
+// game writes to the emulated board (jvs::device), with the idle pattern until it writes any.
+//
+// Writes share the bus with input polling, so they are only sent when the lamps change and at
+// most once per `min_interval_ms`, with the shortest command that does it: GPO3 for one output,
+// GPO2 for one byte, GPO1 for everything.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::jvs::capabilities::Capabilities;
+use crate::jvs::{CMD_GPO_ALL, CMD_GPO_BIT, CMD_GPO_BYTE};
+
+/// What drives the lamps
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LampSource {
+    /// Outputs are never written
+    #[default]
+    Off,
+    Idle,
+    Deluxe,
+}
+
+/// `[jvs.lamps]` config section
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LampConfig {
+    pub source: LampSource,
+    /// Outputs written to the board
+    pub outputs: u8,
+    /// Steps of the idle pattern, each a list of lit outputs
+    pub idle_pattern: Vec<Vec<u8>>,
+    pub idle_step_ms: u64,
+    /// Shortest time between two writes
+    pub min_interval_ms: u64,
+}
+
+impl Default for LampConfig {
+    fn default() -> Self {
+        Self {
+            source: LampSource::Off,
+            outputs: 16,
+            idle_pattern: vec![(0..16).collect()],
+            idle_step_ms: 500,
+            min_interval_ms: 50,
+        }
+    }
+}
+
+/// Decides what to write to the board's outputs and when, see module docs
+pub struct Lamps {
+    source: LampSource,
+    outputs: u8,
+    /// Output bytes of every idle pattern step
+    idle_steps: Vec<Vec<u8>>,
+    idle_step: Duration,
+    min_interval: Duration,
+    started: Instant,
+    /// What the board has now, None before the first write or after a failed one
+    written: Option<Vec<u8>>,
+    last_write: Option<Instant>,
+}
+
+impl Lamps {
+    pub fn from_config(config: &LampConfig) -> io::Result<Self> {
+        let bytes = (config.outputs as usize).div_ceil(8);
+        let mut idle_steps = Vec::new();
+        for step in &config.idle_pattern {
+            let mut data = vec![0; bytes];
+            for &output in step {
+                if output >= config.outputs {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Invalid [jvs.lamps]: idle_pattern lights output {}, there are {} outputs",
+                            output, config.outputs
+                        ),
+                    ));
+                }
+                data[output as usize / 8] |= 0x80 >> (output % 8);
+            }
+            idle_steps.push(data);
+        }
+        if idle_steps.is_empty() {
+            idle_steps.push(vec![0; bytes]);
+        }
+
+        Ok(Self {
+            source: config.source,
+            outputs: config.outputs,
+            idle_steps,
+            idle_step: Duration::from_millis(config.idle_step_ms.max(1)),
+            min_interval: Duration::from_millis(config.min_interval_ms),
+            started: Instant::now(),
+            written: None,
+            last_write: None,
+        })
+    }
+
+    pub fn source(&self) -> LampSource {
+        self.source
+    }
+
+    /// Checks that the board has every output the lamps are written to, by its feature list
+    pub fn check_board(&self, capabilities: &Capabilities) -> io::Result<()> {
+        if self.source != LampSource::Off && capabilities.outputs() < self.outputs {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid [jvs.lamps]: {} outputs are written, but the board has {}",
+                    self.outputs,
+                    capabilities.outputs()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Output bytes the lamps should show at `now`, `game` is what Deluxe wrote to the
+    /// emulated board, if anything
+    pub fn wanted(&self, now: Instant, game: Option<&[u8]>) -> Vec<u8> {
+        let bytes = self.idle_steps[0].len();
+        match game {
+            Some(game) if self.source == LampSource::Deluxe => {
+                let mut data = game.to_vec();
+                data.resize(bytes, 0);
+                data
+            }
+            _ => {
+                let steps =
+                    now.duration_since(self.started).as_millis() / self.idle_step.as_millis();
+                self.idle_steps[(steps % self.idle_steps.len() as u128) as usize].clone()
+            }
+        }
+    }
+
+    /// JVS command to send at `now`, None if lamps are off, didn't change or were written too
+    /// recently. The command counts as written, call `write_failed` if it wasn't
+    pub fn next_command(&mut self, now: Instant, game: Option<&[u8]>) -> Option<Vec<u8>> {
+        if self.source == LampSource::Off
+            || self
+                .last_write
+                .is_some_and(|last| now.duration_since(last) < self.min_interval)
+        {
+            return None;
+        }
+
+        let wanted = self.wanted(now, game);
+        let command = gpo_command(self.written.as_deref(), &wanted)?;
+        self.written = Some(wanted);
+        self.last_write = Some(now);
+        Some(command)
+    }
+
+    /// The last command didn't make it to the board, the next write sets every output
+    pub fn write_failed(&mut self) {
+        self.written = None;
+    }
+}
+
+/// Shortest command that turns `current` outputs into `wanted`, None if they are the same
+pub fn gpo_command(current: Option<&[u8]>, wanted: &[u8]) -> Option<Vec<u8>> {
+    let all = || [&[CMD_GPO_ALL, wanted.len() as u8], wanted].concat();
+    let current = match current {
+        Some(current) if current.len() == wanted.len() => current,
+        _ => return Some(all()),
+    };
+
+    let changed: Vec<_> = (0..wanted.len())
+        .filter(|&i| current[i] != wanted[i])
+        .collect();
+    match changed[..] {
+        [] => None,
+        [byte] => {
+            let diff = current[byte] ^ wanted[byte];
+            if diff.count_ones() == 1 {
+                let bit = diff.leading_zeros() as u8;
+                let lit = wanted[byte] & diff != 0;
+                Some(vec![CMD_GPO_BIT, byte as u8 * 8 + bit, lit as u8])
+            } else {
+                Some(vec![CMD_GPO_BYTE, byte as u8, wanted[byte]])
+            }
+        }
+        _ => Some(all()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::jvs::capabilities::{Capabilities, Feature};
+    use crate::jvs::lamp::{gpo_command, LampConfig, LampSource, Lamps};
+
+    fn make_lamps(source: LampSource, idle_pattern: Vec<Vec<u8>>) -> Lamps {
+        Lamps::from_config(&LampConfig {
+            source,
+            idle_pattern,
+            ..LampConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    pub fn shortest_command_for_the_change() {
+        assert_eq!(
+            gpo_command(None, &[0x80, 0x01]),
+            Some(vec![0x32, 2, 0x80, 0x01])
+        );
+        assert_eq!(gpo_command(Some(&[0x80, 0x01]), &[0x80, 0x01]), None);
+        // Output 15 off
+        assert_eq!(
+            gpo_command(Some(&[0x80, 0x01]), &[0x80, 0x00]),
+            Some(vec![0x38, 15, 0])
+        );
+        assert_eq!(
+            gpo_command(Some(&[0x80, 0x01]), &[0x80, 0x06]),
+            Some(vec![0x37, 1, 0x06])
+        );
+        assert_eq!(
+            gpo_command(Some(&[0x80, 0x01]), &[0x00, 0x00]),
+            Some(vec![0x32, 2, 0x00, 0x00])
+        );
+    }
+
+    #[test]
+    pub fn idle_pattern_steps_and_rate_limit() {
+        let mut lamps = make_lamps(LampSource::Idle, vec![vec![0], vec![1, 8]]);
+        let start = lamps.started;
+        assert_eq!(
+            lamps.next_command(start, None),
+            Some(vec![0x32, 2, 0x80, 0x00])
+        );
+        // Same step, nothing to write
+        assert_eq!(
+            lamps.next_command(start + Duration::from_millis(100), None),
+            None
+        );
+
+        let next_step = start + Duration::from_millis(500);
+        assert_eq!(
+            lamps.next_command(next_step, None),
+            Some(vec![0x32, 2, 0x40, 0x80])
+        );
+        // Back to the first step, but too soon after the last write
+        let wrapped = start + Duration::from_millis(1000);
+        lamps.last_write = Some(wrapped);
+        assert_eq!(lamps.next_command(wrapped, None), None);
+        lamps.write_failed();
+        assert_eq!(
+            lamps.next_command(wrapped + Duration::from_millis(50), None),
+            Some(vec![0x32, 2, 0x80, 0x00])
+        );
+    }
+
+    #[test]
+    pub fn deluxe_outputs_once_the_game_writes_them() {
+        let mut lamps = make_lamps(LampSource::Deluxe, vec![vec![]]);
+        let now = Instant::now();
+        assert_eq!(lamps.wanted(now, None), [0, 0]);
+        assert_eq!(lamps.wanted(now, Some(&[0xFF, 0x01, 0x02])), [0xFF, 0x01]);
+        assert_eq!(
+            lamps.next_command(now, Some(&[0x20])),
+            Some(vec![0x32, 2, 0x20, 0x00])
+        );
+
+        assert!(make_lamps(LampSource::Off, vec![vec![0]])
+            .next_command(now, None)
+            .is_none());
+        assert!(Lamps::from_config(&LampConfig {
+            idle_pattern: vec![vec![16]],
+            ..LampConfig::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    pub fn board_needs_every_written_output() {
+        let board = |outputs| Capabilities {
+            features: vec![Feature::Outputs { outputs }],
+        };
+        let lamps = make_lamps(LampSource::Idle, vec![vec![0]]);
+        assert!(lamps.check_board(&board(22)).is_ok());
+        assert!(lamps.check_board(&board(8)).is_err());
+        assert!(make_lamps(LampSource::Off, vec![vec![0]])
+            .check_board(&Capabilities::default())
+            .is_ok());
+    }
+}
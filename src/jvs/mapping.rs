@@ -0,0 +1,321 @@
+// Which JVS switch bit is which button.
+//
+// [jvs.mapping] places every logical input (test, service, p1_btn1..p2_btn8, the same names as in
+// [input]) on a switch bit: `player` 0 is the system byte, 1 and 2 are the players' switch bytes,
+// `byte` is the byte within them and `bit` 0..7. `active_low` inputs read 0 while pressed, as the
+// Finale buttons do. Inputs that are not listed keep the Finale layout.
+//
+// Keys are pressed by logical input, and the emulated board always reports the Finale layout,
+// so Deluxe sees a rewired cabinet as a stock one.
+
+use std::collections::BTreeMap;
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::helper_funcs::bit_read;
+use crate::jvs::capabilities::Capabilities;
+use crate::jvs::{SwitchState, MAX_PLAYER_BYTES};
+
+/// Logical inputs, in the order JvsMapping reports them
+pub static INPUTS: [&str; 18] = [
+    "test", "service", "p1_btn1", "p1_btn2", "p1_btn3", "p1_btn4", "p1_btn5", "p1_btn6", "p1_btn7",
+    "p1_btn8", "p2_btn1", "p2_btn2", "p2_btn3", "p2_btn4", "p2_btn5", "p2_btn6", "p2_btn7",
+    "p2_btn8",
+];
+
+/// Position of an input in READ_DIGITAL data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SwitchBit {
+    /// 0 for the system byte, 1 or 2 for a player
+    pub player: u8,
+    #[serde(default)]
+    pub byte: u8,
+    pub bit: u8,
+    #[serde(default)]
+    pub active_low: bool,
+}
+
+impl SwitchBit {
+    const fn new(player: u8, byte: u8, bit: u8, active_low: bool) -> Self {
+        Self {
+            player,
+            byte,
+            bit,
+            active_low,
+        }
+    }
+
+    fn switch_byte(&self, switches: &SwitchState) -> u8 {
+        match self.player {
+            0 => switches.system,
+            player => switches.players[player as usize - 1][self.byte as usize],
+        }
+    }
+
+    fn switch_byte_mut<'a>(&self, switches: &'a mut SwitchState) -> &'a mut u8 {
+        match self.player {
+            0 => &mut switches.system,
+            player => &mut switches.players[player as usize - 1][self.byte as usize],
+        }
+    }
+
+    pub fn is_pressed(&self, switches: &SwitchState) -> bool {
+        bit_read(&self.switch_byte(switches), self.bit as usize) != self.active_low
+    }
+
+    pub fn set_pressed(&self, switches: &mut SwitchState, pressed: bool) {
+        let byte = self.switch_byte_mut(switches);
+        if pressed != self.active_low {
+            *byte |= 1 << self.bit;
+        } else {
+            *byte &= !(1 << self.bit);
+        }
+    }
+}
+
+impl std::fmt::Display for SwitchBit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.player {
+            0 => write!(f, "system bit {}", self.bit),
+            player => write!(f, "player {} byte {} bit {}", player, self.byte, self.bit),
+        }
+    }
+}
+
+/// Layout of the Finale I/O board, indexed like INPUTS
+static FINALE_LAYOUT: [SwitchBit; 18] = [
+    SwitchBit::new(1, 0, 6, false),
+    SwitchBit::new(0, 0, 7, false),
+    SwitchBit::new(1, 0, 2, true),
+    SwitchBit::new(1, 0, 3, true),
+    SwitchBit::new(1, 0, 0, true),
+    SwitchBit::new(1, 1, 7, true),
+    SwitchBit::new(1, 1, 6, true),
+    SwitchBit::new(1, 1, 5, true),
+    SwitchBit::new(1, 1, 4, true),
+    SwitchBit::new(1, 1, 3, true),
+    SwitchBit::new(2, 0, 2, true),
+    SwitchBit::new(2, 0, 3, true),
+    SwitchBit::new(2, 0, 0, true),
+    SwitchBit::new(2, 1, 7, true),
+    SwitchBit::new(2, 1, 6, true),
+    SwitchBit::new(2, 1, 5, true),
+    SwitchBit::new(2, 1, 4, true),
+    SwitchBit::new(2, 1, 3, true),
+];
+
+/// Built-in mapping in the form used by config
+pub fn default_mapping() -> BTreeMap<String, SwitchBit> {
+    INPUTS
+        .iter()
+        .zip(FINALE_LAYOUT)
+        .map(|(input, bit)| (input.to_string(), bit))
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JvsMapping {
+    bits: [SwitchBit; 18],
+}
+
+impl Default for JvsMapping {
+    fn default() -> Self {
+        Self {
+            bits: FINALE_LAYOUT,
+        }
+    }
+}
+
+impl JvsMapping {
+    /// Builds mapping from `[jvs.mapping]`. Inputs that are not listed keep the Finale layout,
+    /// two inputs on the same bit are an error
+    pub fn from_mapping(mapping: &BTreeMap<String, SwitchBit>) -> io::Result<Self> {
+        let mut jvs_mapping = Self::default();
+        for (input, &bit) in mapping {
+            let index = INPUTS
+                .iter()
+                .position(|name| name == input)
+                .ok_or_else(|| invalid_mapping(format!("unknown input \"{input}\"")))?;
+            let valid = match bit.player {
+                0 => bit.byte == 0,
+                1 | 2 => (bit.byte as usize) < MAX_PLAYER_BYTES,
+                _ => false,
+            };
+            if !valid || bit.bit > 7 {
+                return Err(invalid_mapping(format!(
+                    "{input} is on player {} byte {} bit {}, players are 0 (system) to 2, \
+                     bytes 0 to {} (0 for system) and bits 0 to 7",
+                    bit.player,
+                    bit.byte,
+                    bit.bit,
+                    MAX_PLAYER_BYTES - 1
+                )));
+            }
+            jvs_mapping.bits[index] = bit;
+        }
+
+        for (i, bit) in jvs_mapping.bits.iter().enumerate() {
+            if let Some(other) = jvs_mapping.bits[..i]
+                .iter()
+                .position(|b| (b.player, b.byte, b.bit) == (bit.player, bit.byte, bit.bit))
+            {
+                return Err(invalid_mapping(format!(
+                    "{} and {} are both on {}",
+                    INPUTS[other], INPUTS[i], bit
+                )));
+            }
+        }
+        Ok(jvs_mapping)
+    }
+
+    /// Checks that the board has a switch for every input, by its feature list
+    pub fn check_board(&self, capabilities: &Capabilities) -> io::Result<()> {
+        let (players, _) = capabilities.switches();
+        let bytes = capabilities.switch_bytes();
+        for (input, bit) in INPUTS.iter().zip(&self.bits) {
+            if bit.player > players.min(2) || (bit.player > 0 && bit.byte >= bytes) {
+                return Err(invalid_mapping(format!(
+                    "{input} is on {bit}, but the board has {players} player(s) with {bytes} \
+                     switch byte(s)"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Pressed state of every input, in INPUTS order
+    pub fn pressed(&self, switches: &SwitchState) -> [bool; 18] {
+        self.bits.map(|bit| bit.is_pressed(switches))
+    }
+
+    /// Moves inputs from this mapping to the Finale layout. Bits the inputs are wired to read as
+    /// released, so they don't show up a second time, other bits are left as they are
+    pub fn to_finale_layout(&self, switches: &SwitchState) -> SwitchState {
+        let mut finale = *switches;
+        for bit in &self.bits {
+            bit.set_pressed(&mut finale, false);
+        }
+        for (bit, pressed) in FINALE_LAYOUT.iter().zip(self.pressed(switches)) {
+            bit.set_pressed(&mut finale, pressed);
+        }
+        finale
+    }
+}
+
+fn invalid_mapping(msg: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid [jvs.mapping]: {msg}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::jvs::capabilities::{Capabilities, Feature};
+    use crate::jvs::mapping::{default_mapping, JvsMapping, SwitchBit, INPUTS};
+    use crate::jvs::SwitchState;
+
+    /// Finale board with nothing pressed, buttons are active low and test is active high
+    const IDLE: SwitchState = SwitchState {
+        system: 0x00,
+        players: [[0xBF, 0xFF, 0, 0], [0xFF, 0xFF, 0, 0]],
+    };
+
+    fn pressed_names(mapping: &JvsMapping, switches: &SwitchState) -> Vec<&'static str> {
+        INPUTS
+            .iter()
+            .zip(mapping.pressed(switches))
+            .filter_map(|(name, pressed)| pressed.then_some(*name))
+            .collect()
+    }
+
+    #[test]
+    pub fn finale_layout_by_default() {
+        let mapping = JvsMapping::from_mapping(&default_mapping()).unwrap();
+        assert_eq!(mapping, JvsMapping::default());
+        assert!(pressed_names(&mapping, &IDLE).is_empty());
+
+        let mut switches = IDLE;
+        switches.system = 0x80;
+        switches.players[0][0] &= !0b100;
+        switches.players[1][1] &= !0b1000;
+        assert_eq!(
+            pressed_names(&mapping, &switches),
+            ["service", "p1_btn1", "p2_btn8"]
+        );
+        assert_eq!(mapping.to_finale_layout(&switches), switches);
+    }
+
+    #[test]
+    pub fn rewired_inputs_are_reported_in_finale_layout() {
+        // P1 button 1 and 2 swapped, button 3 moved to a bit Finale doesn't use, test moved to
+        // an active low system bit
+        let mapping = JvsMapping::from_mapping(&BTreeMap::from([
+            ("p1_btn1".to_string(), SwitchBit::new(1, 0, 3, true)),
+            ("p1_btn2".to_string(), SwitchBit::new(1, 0, 2, true)),
+            ("p1_btn3".to_string(), SwitchBit::new(1, 0, 1, true)),
+            ("test".to_string(), SwitchBit::new(0, 0, 1, true)),
+        ]))
+        .unwrap();
+
+        let mut switches = IDLE;
+        switches.system = 0b10;
+        switches.players[0][0] &= !0b1010;
+        assert_eq!(pressed_names(&mapping, &switches), ["p1_btn1", "p1_btn3"]);
+
+        // Button 3 is only on its Finale bit, the bit it's wired to reads released
+        let mut finale = switches;
+        finale.players[0][0] = 0xBF & !0b101;
+        assert_eq!(mapping.to_finale_layout(&switches), finale);
+        assert_eq!(
+            pressed_names(&JvsMapping::default(), &finale),
+            ["p1_btn1", "p1_btn3"]
+        );
+
+        switches.system = 0;
+        assert_eq!(
+            pressed_names(&mapping, &switches),
+            ["test", "p1_btn1", "p1_btn3"]
+        );
+    }
+
+    #[test]
+    pub fn checks_inputs_against_board_features() {
+        let board = |players, switches| Capabilities {
+            features: vec![Feature::Switches { players, switches }],
+        };
+        let mapping = JvsMapping::default();
+        assert!(mapping.check_board(&board(2, 13)).is_ok());
+        // P2 buttons are missing
+        let err = mapping.check_board(&board(1, 13)).unwrap_err();
+        assert!(err.to_string().contains("p2_btn1"));
+        // Second switch byte is missing
+        assert!(mapping.check_board(&board(2, 8)).is_err());
+        assert!(mapping.check_board(&Capabilities::default()).is_err());
+    }
+
+    #[test]
+    pub fn rejects_duplicates_and_bad_positions() {
+        let map = |entries: &[(&str, SwitchBit)]| {
+            let entries = entries.iter().map(|&(input, bit)| (input.to_string(), bit));
+            JvsMapping::from_mapping(&entries.collect())
+        };
+        // Test is on player 1 byte 0 bit 6 by default
+        let err = map(&[("p2_btn1", SwitchBit::new(1, 0, 6, true))]).unwrap_err();
+        assert!(err.to_string().contains("test and p2_btn1"));
+        assert!(map(&[("p1_btn9", SwitchBit::new(1, 0, 1, true))]).is_err());
+        assert!(map(&[("p1_btn1", SwitchBit::new(3, 0, 1, true))]).is_err());
+        assert!(map(&[("p1_btn1", SwitchBit::new(1, 4, 1, true))]).is_err());
+        assert!(map(&[("p1_btn1", SwitchBit::new(1, 0, 8, true))]).is_err());
+        assert!(map(&[("p1_btn1", SwitchBit::new(0, 1, 1, true))]).is_err());
+        // Swapping keeps every bit unique
+        assert!(map(&[
+            ("p1_btn1", SwitchBit::new(1, 0, 3, true)),
+            ("p1_btn2", SwitchBit::new(1, 0, 2, true)),
+        ])
+        .is_ok());
+    }
+}
@@ -1,70 +1,56 @@
 use log::error;
 use std::collections::HashMap;
-use std::mem::size_of;
 
-use winapi::ctypes::c_int;
-use winapi::shared::minwindef::{DWORD, UINT, WORD};
-use winapi::um::winuser::{INPUT_u, SendInput, INPUT, INPUT_KEYBOARD, KEYBDINPUT, KEYEVENTF_KEYUP};
+use crate::platform;
 
+/// Windows virtual-key code. Values are the same on every platform so configs stay portable
+pub type KeyCode = i32;
+
+// Virtual-key codes that are used by default settings, see
+// https://learn.microsoft.com/en-us/windows/win32/inputdev/virtual-key-codes
+pub const VK_RETURN: KeyCode = 0x0D;
+pub const VK_NUMPAD1: KeyCode = 0x61;
+pub const VK_NUMPAD2: KeyCode = 0x62;
+pub const VK_NUMPAD3: KeyCode = 0x63;
+pub const VK_NUMPAD4: KeyCode = 0x64;
+pub const VK_NUMPAD6: KeyCode = 0x66;
+pub const VK_NUMPAD7: KeyCode = 0x67;
+pub const VK_NUMPAD8: KeyCode = 0x68;
+pub const VK_NUMPAD9: KeyCode = 0x69;
+
+#[derive(Default)]
 pub struct Keyboard {
-    pressed_keys: HashMap<c_int, bool>,
+    pressed_keys: HashMap<KeyCode, bool>,
 }
 
 impl Keyboard {
     pub fn new() -> Self {
-        Self {
-            pressed_keys: HashMap::new(),
-        }
+        Self::default()
     }
 
-    fn send_input(flags: DWORD, vk: WORD, scan: WORD) -> Result<(), ()> {
-        let mut union: INPUT_u = unsafe { std::mem::zeroed() };
-        let inner_union = unsafe { union.ki_mut() };
-
-        *inner_union = KEYBDINPUT {
-            wVk: vk,
-            wScan: scan,
-            dwFlags: flags,
-            time: 0,
-            dwExtraInfo: 0,
-        };
-        let mut input = [INPUT {
-            type_: INPUT_KEYBOARD,
-            u: union,
-        }; 1];
-
-        let value = unsafe {
-            SendInput(
-                input.len() as UINT,
-                input.as_mut_ptr(),
-                size_of::<INPUT>() as c_int,
-            )
-        };
-        if value != 1 {
-            error!("Keyboard error, check your privileges and try again");
-            Err(())
-        } else {
-            Ok(())
+    fn send_input(key_code: KeyCode, pressed: bool) {
+        if let Err(err) = platform::send_key(key_code, pressed) {
+            error!(
+                "Keyboard error, check your privileges and try again: {}",
+                err
+            );
         }
     }
 
-    pub fn key_down(&mut self, &key_code: &c_int) {
+    pub fn key_down(&mut self, &key_code: &KeyCode) {
         match self.pressed_keys.get(&key_code) {
             Some(false) | None => {
-                let _ = Self::send_input(0, key_code as WORD, 0);
+                Self::send_input(key_code, true);
                 self.pressed_keys.insert(key_code, true);
             }
-            _ => return,
+            _ => {}
         }
     }
 
-    pub fn key_up(&mut self, &key_code: &c_int) {
-        match self.pressed_keys.get(&key_code) {
-            Some(true) => {
-                let _ = Self::send_input(KEYEVENTF_KEYUP, key_code as WORD, 0);
-                self.pressed_keys.insert(key_code, false);
-            }
-            _ => return,
+    pub fn key_up(&mut self, &key_code: &KeyCode) {
+        if let Some(true) = self.pressed_keys.get(&key_code) {
+            Self::send_input(key_code, false);
+            self.pressed_keys.insert(key_code, false);
         }
     }
 }
@@ -0,0 +1,20 @@
+//! Library side of the Maimai Finale to Maimai Deluxe wrapper.
+//!
+//! The binary is a thin CLI over these modules, but they can be used on their own:
+//! - [`packets`] - JVS (`rs232`) and card reader (`rs232c`) packet codecs
+//! - [`touch`] - Finale touch panel driver and Finale to Deluxe touch translator
+//! - [`jvs`] - JVS client for the Finale I/O board and the I/O board emulated for Deluxe
+//! - [`card_reader`] - client for the Finale Aime card reader
+//! - [`diag`] - live terminal diagnostic views
+//! - [`transport`] - serial/pty/TCP/in-memory ports every subsystem talks through
+
+pub mod card_reader;
+pub mod config;
+pub mod diag;
+pub mod helper_funcs;
+pub mod jvs;
+pub mod keyboard;
+pub mod packets;
+pub mod platform;
+pub mod touch;
+pub mod transport;
@@ -1,4 +1,3 @@
-use crate::config::Config;
 use clap::Parser;
 use clap_serde_derive::ClapSerde;
 use flexi_logger::{colored_opt_format, Logger};
@@ -10,22 +9,13 @@ use std::io::{Read, Write};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread::JoinHandle;
-use winapi::um::timeapi;
 
-mod card_reader;
-mod config;
-mod helper_funcs;
-mod jvs;
-mod keyboard;
-mod packets;
-mod touch;
+use mai_finale_to_deluxe::config::{Cli, CliOpt, Command, Config, DiagTarget};
+use mai_finale_to_deluxe::{card_reader, diag, jvs, touch};
 
 fn main() {
-    unsafe {
-        timeapi::timeBeginPeriod(1);
-    }
-
-    let args = Config::parse();
+    let cli = Cli::parse();
+    let args = cli.config;
     if args.create_config {
         let mut file =
             File::create(args.config_path.clone()).expect("Couldn't create a config file");
@@ -39,7 +29,7 @@ fn main() {
         let mut data = String::new();
         f.read_to_string(&mut data).expect("Unable to parse a file");
         match toml::from_str::<<Config as ClapSerde>::Opt>(data.as_str()) {
-            Ok(config) => Config::from(config).merge_clap(),
+            Ok(config) => Config::from(config).merge(CliOpt::parse().config),
             Err(err) => panic!("Error in configuration file:\n{}", err),
         }
     } else {
@@ -54,12 +44,37 @@ fn main() {
         .unwrap();
 
     let running = Arc::new(AtomicBool::new(true));
-    if !config.settings.disable_touch {
-        match touch::spawn_thread(&config.settings, &running) {
-            Ok((finale, deluxe)) => {
-                handles.push(finale);
-                handles.push(deluxe);
+    let ctrlc_running = running.clone();
+    ctrlc::set_handler(move || {
+        info!("Exiting...");
+        ctrlc_running.store(false, Ordering::Release);
+    })
+    .unwrap();
+
+    if let Some(command) = &cli.command {
+        let result = match command {
+            Command::Replay(replay_args) => touch::record::run_replay(&config, replay_args),
+            Command::SimulateFinaleTouch(sim_args) => {
+                touch::simulator::run_simulator(sim_args, &running)
             }
+            Command::EmulateAllsTouch(alls_args) => touch::alls::run_emulator(alls_args, &running),
+            Command::BenchTouch(bench_args) => touch::latency::run_bench(&config.touch, bench_args),
+            Command::Diag(diag_args) => match &diag_args.target {
+                DiagTarget::Touch(touch_args) => {
+                    diag::touch::run_touch_diag(&config, touch_args, &running)
+                }
+            },
+        };
+        if let Err(err) = result {
+            error!("Command failed: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if !config.settings.disable_touch {
+        match touch::spawn_thread(&config, &running) {
+            Ok(touch) => handles.extend(touch),
             Err(err) => error!("Touchscreen initialization failed: {}", err),
         };
     } else {
@@ -68,7 +83,7 @@ fn main() {
 
     if !config.settings.disable_jvs {
         match jvs::spawn_thread(&config, running.clone()) {
-            Ok(jvs) => handles.push(jvs),
+            Ok(jvs) => handles.extend(jvs),
             Err(err) => error!("JVS initialization failed: {}", err),
         }
     } else {
@@ -84,15 +99,11 @@ fn main() {
         warn!("\"disable_reader\" was set to True. NFC reader proxy disabled")
     }
 
-    ctrlc::set_handler(move || {
-        info!("Exiting...");
-        running.store(false, Ordering::Release);
-    })
-    .unwrap();
-
     for _ in 0..handles.len() {
-        if let Err(e) = handles.pop().unwrap().join() {
-            error!("Thread panicked, {:?}", e);
+        match handles.pop().unwrap().join() {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => error!("Thread failed: {}", err),
+            Err(e) => error!("Thread panicked, {:?}", e),
         }
     }
 }
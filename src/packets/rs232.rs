@@ -8,6 +8,8 @@ const DESTINATION_INDEX: usize = 1;
 const SIZE_INDEX: usize = 2;
 const LEN_OF_HEADER: usize = 3;
 
+// Packet is never empty, it always contains at least a header
+#[allow(clippy::len_without_is_empty)]
 pub trait Packet {
     const DATA_BEGIN_INDEX: usize;
 
@@ -49,7 +51,7 @@ pub trait Packet {
     }
 
     fn read(&mut self, reader: &mut dyn ReadExt) -> io::Result<&mut Self> {
-        read_packet(reader, &mut self.get_mut_buf())?;
+        read_packet(reader, self.get_mut_buf())?;
         Ok(self)
     }
 
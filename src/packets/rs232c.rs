@@ -13,6 +13,8 @@ const REPORT_INDEX: usize = 5;
 
 const LEN_OF_HEADER: usize = 2;
 
+// Packet is never empty, it always contains at least a header
+#[allow(clippy::len_without_is_empty)]
 pub trait Packet {
     const DATA_BEGIN_INDEX: usize;
 
@@ -0,0 +1,12 @@
+// OS specific bits. Windows gets the real implementations (SendInput),
+// everything else gets portable stand-ins so the rest of the bridge builds and tests anywhere.
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+pub use self::windows::*;
+
+#[cfg(not(windows))]
+mod portable;
+#[cfg(not(windows))]
+pub use self::portable::*;
@@ -0,0 +1,43 @@
+use std::io;
+use std::mem::size_of;
+
+use winapi::ctypes::c_int;
+use winapi::shared::minwindef::{DWORD, UINT, WORD};
+use winapi::um::winuser::{INPUT_u, SendInput, INPUT, INPUT_KEYBOARD, KEYBDINPUT, KEYEVENTF_KEYUP};
+
+use crate::keyboard::KeyCode;
+
+pub fn send_key(key_code: KeyCode, pressed: bool) -> io::Result<()> {
+    let flags: DWORD = if pressed { 0 } else { KEYEVENTF_KEYUP };
+    send_input(flags, key_code as WORD, 0)
+}
+
+fn send_input(flags: DWORD, vk: WORD, scan: WORD) -> io::Result<()> {
+    let mut union: INPUT_u = unsafe { std::mem::zeroed() };
+    let inner_union = unsafe { union.ki_mut() };
+
+    *inner_union = KEYBDINPUT {
+        wVk: vk,
+        wScan: scan,
+        dwFlags: flags,
+        time: 0,
+        dwExtraInfo: 0,
+    };
+    let mut input = [INPUT {
+        type_: INPUT_KEYBOARD,
+        u: union,
+    }; 1];
+
+    let value = unsafe {
+        SendInput(
+            input.len() as UINT,
+            input.as_mut_ptr(),
+            size_of::<INPUT>() as c_int,
+        )
+    };
+    if value != 1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
@@ -2,75 +2,220 @@
 // wrapping it in the way that Maimai DX (based on ALLs system) can read it.
 //
 // Since Finale cabinet touch lacks some Touch areas that Deluxe touch has, we basically map them to
-// existing ones (see touch::deluxe::)
+// existing ones (see touch::mapping, configurable via [touch.mapping])
 // So if you press, for example, B1 area in Maimai DX, it will also press E1 and E2 (which is is close to B1)
 
-use crate::config::Settings;
-use log::info;
+use crate::config::{Config, Touch};
+use log::{error, info, warn};
 
-use std::io::Write;
+use std::io::{Read, Write};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use std::{io, thread};
 
+use crate::touch::debounce::Debouncer;
 use crate::touch::deluxe::*;
 use crate::touch::finale::*;
+use crate::touch::keys::TouchKeys;
+use crate::touch::latency::LatencyMonitor;
+use crate::touch::layout::Layout;
+use crate::touch::mapping::ZoneMapping;
+use crate::touch::profile::{ProfileSaver, SensitivityProfile, SAVE_DELAY};
+use crate::touch::record::TouchRecorder;
+use crate::touch::stuck::StuckDetector;
+use crate::transport;
+use crate::transport::queued::QueuedWriter;
+use crate::transport::Transport;
 
-mod deluxe;
-mod finale;
+pub mod alls;
+pub mod debounce;
+pub mod deluxe;
+pub mod finale;
+pub mod frame;
+pub mod keys;
+pub mod latency;
+pub mod layout;
+pub mod mapping;
+pub mod profile;
+pub mod record;
+pub mod sensitivity;
+pub mod simulator;
+pub mod stuck;
 
-// pub const RSET: &[u8] = "{RSET}".as_bytes();
+pub const RSET: &[u8] = "{RSET}".as_bytes();
 pub const HALT: &[u8] = "{HALT}".as_bytes();
 pub const STAT: &[u8] = "{STAT}".as_bytes();
 
-pub fn spawn_thread(
-    args: &Settings,
-    exit_sig: &Arc<AtomicBool>,
-) -> io::Result<(JoinHandle<io::Result<()>>, JoinHandle<io::Result<()>>)> {
-    let (sender, receiver) = crossbeam_channel::bounded::<MessageCmd>(10);
+type TouchHandle = JoinHandle<io::Result<()>>;
 
-    let mut dx_p1_touch = Deluxe::new(args.touch_alls_p1_com.clone(), 0, sender.clone())?;
-    let mut dx_p2_touch = Deluxe::new(args.touch_alls_p2_com.clone(), 1, sender.clone())?;
+/// How long blocking port reads wait for data before checking if the bridge is exiting.
+/// Reads return as soon as data arrives, so this only bounds how long shutdown takes
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
 
-    let dx_p1_port = dx_p1_touch.port.try_clone_native()?;
-    let dx_p2_port = dx_p2_touch.port.try_clone_native()?;
+/// Writes waiting for a port's writer thread, about a second of Deluxe frames
+const WRITE_QUEUE: usize = 64;
+/// How long a port write may block its writer thread before the data is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
 
-    let mut fe_touch = RingEdge2::new(args.touch_re2_com.clone(), dx_p1_port, dx_p2_port)?;
+/// Zone mapping of the `[touch]` config section
+pub fn zone_mapping(touch: &Touch) -> io::Result<ZoneMapping> {
+    ZoneMapping::new(&touch.mapping, touch.d_zones, touch.e_zones)
+}
+
+/// Spawns the touch bridge. Every port is read by its own thread that sleeps in a blocking read
+/// and handles what it got right away, so there is no polling: the Finale reader forwards
+/// frames to Deluxe ports, Deluxe readers apply game commands. Both go through the same
+/// RingEdge2 behind a mutex. Every port is also written by its own thread (QueuedWriter), so the
+/// mutex is never held during a port write and one stalled port doesn't hold up the others
+pub fn spawn_thread(config: &Config, exit_sig: &Arc<AtomicBool>) -> io::Result<Vec<TouchHandle>> {
+    let args = &config.settings;
+    let mapping = zone_mapping(&config.touch)?;
+    let mode = config.touch.mode;
+    let output = config.touch.output;
+    let alls_ports = [&args.touch_alls_p1_com, &args.touch_alls_p2_com];
+    let mut handles = Vec::new();
+    let mut dx_touches = Vec::new();
+    let mut dx_ports = [None, None];
+    for (player_num, port_name) in alls_ports.into_iter().enumerate() {
+        if !mode.uses_deluxe(player_num) || !output.to_deluxe() {
+            continue;
+        }
+        let mut dx_touch = Deluxe::new(port_name.clone(), player_num)?;
+        let (writer, writer_handle) =
+            QueuedWriter::spawn(dx_touch.port.try_clone()?, WRITE_QUEUE, WRITE_TIMEOUT)?;
+        dx_ports[player_num] = Some(Box::new(writer) as Box<dyn Transport>);
+        handles.push(writer_handle);
+        dx_touch.port.set_timeout(READ_TIMEOUT)?;
+        dx_touches.push((player_num, dx_touch));
+    }
+    let [dx_p1_port, dx_p2_port] = dx_ports;
+
+    let mut fe_touch = RingEdge2::new(args.touch_re2_com.clone(), dx_p1_port, dx_p2_port, mapping)?;
+    fe_touch.set_mode(mode);
+    fe_touch.set_layout(Layout::from_config(&config.touch)?);
+    fe_touch.set_debouncer(Debouncer::from_config(&config.touch.debounce)?);
+    fe_touch.set_stuck_detector(StuckDetector::from_config(&config.touch));
+    if config.touch.sensitivity.forward {
+        warn!("Touch: forwarding sensitivity to the Finale panel, this is experimental");
+        fe_touch.set_sensitivity_forwarding(true);
+    }
+    if output.to_keyboard() {
+        let keys = TouchKeys::from_config(&config.touch.keys)?;
+        if keys.is_empty() {
+            warn!("Touch: output includes keyboard, but [touch.keys] has no keys bound");
+        }
+        fe_touch.set_keys(keys);
+    }
+    if let Some(latency) = LatencyMonitor::from_config(&config.touch) {
+        fe_touch.set_latency_monitor(latency);
+    }
+    if let Some(path) = &args.record_touch {
+        fe_touch.set_recorder(TouchRecorder::create(path)?);
+    }
+    // RingEdge2 only writes to its handle from now on, so reads don't block it
+    let mut fe_reader = fe_touch.port.try_clone()?;
+    fe_reader.set_timeout(READ_TIMEOUT)?;
+    let (fe_writer, fe_writer_handle) =
+        QueuedWriter::spawn(fe_touch.port.try_clone()?, WRITE_QUEUE, WRITE_TIMEOUT)?;
+    fe_touch.port = Box::new(fe_writer);
+    handles.push(fe_writer_handle);
     fe_touch.port.write_all(HALT)?;
+    // Stored thresholds only matter for the panel, so like the game's ones they need forward.
+    // Before STAT, so the panel is calibrated before the first frame
+    let sensitivity = config.touch.sensitivity.relative_to(&config.config_path);
+    if sensitivity.forward {
+        let mut profile = SensitivityProfile::load(&sensitivity)?;
+        let (saver, saver_handle) = ProfileSaver::spawn(SAVE_DELAY)?;
+        profile.set_saver(saver);
+        handles.push(saver_handle);
+        fe_touch.set_sensitivity_profile(profile)?;
+    }
     fe_touch.port.write_all(STAT)?;
-    let dx_sig = exit_sig.clone();
-    let fe_sig = exit_sig.clone();
+    let fe_touch = Arc::new(Mutex::new(fe_touch));
 
-    let deluxe_handle = thread::Builder::new()
-        .name("Deluxe Touch Thread".to_string())
-        .spawn(move || -> io::Result<()> {
-            while dx_sig.load(Ordering::Acquire) {
-                dx_p1_touch.read();
-                dx_p2_touch.read();
-            }
-            Ok(())
-        })
-        .unwrap();
+    for (player_num, mut dx_touch) in dx_touches {
+        let dx_sig = exit_sig.clone();
+        let dx_fe_touch = fe_touch.clone();
+        let handle = thread::Builder::new()
+            .name(format!("Deluxe Touch P{} Thread", player_num + 1))
+            .spawn(move || -> io::Result<()> {
+                while dx_sig.load(Ordering::Acquire) {
+                    let commands = match dx_touch.read() {
+                        Ok(commands) => commands,
+                        Err(err) => {
+                            error!("Touch: reading {} failed: {}", dx_touch.port.name(), err);
+                            return Err(err);
+                        }
+                    };
+                    // Applied right away, one lock per command
+                    for c in commands {
+                        dx_fe_touch.lock().unwrap().parse_command_from_alls(c)?;
+                    }
+                }
+
+                let stats = dx_touch.command_stats();
+                info!(
+                    "Touch: P{} {} commands received ({} unknown), {} resyncs, {} bytes discarded",
+                    player_num + 1,
+                    stats.commands,
+                    stats.unknown,
+                    stats.resyncs,
+                    stats.discarded_bytes
+                );
+                Ok(())
+            })
+            .unwrap();
+        handles.push(handle);
+    }
+
+    let fe_sig = exit_sig.clone();
     let finale_handle = thread::Builder::new()
         .name("Finale Touch Thread".to_string())
         .spawn(move || -> io::Result<()> {
-            let rcv = receiver.clone();
+            let mut buffer = [0u8; 64];
             while fe_sig.load(Ordering::Acquire) {
-                for c in rcv.try_iter() {
-                    fe_touch.parse_command_from_alls(c)?;
-                }
-                fe_touch.read();
+                let len = match fe_reader.read(&mut buffer) {
+                    Ok(len) => len,
+                    Err(err) if transport::is_timeout(&err) => continue,
+                    Err(err) => {
+                        error!("Touch: reading {} failed: {}", fe_reader.name(), err);
+                        return Err(err);
+                    }
+                };
+                let received = Instant::now();
+                fe_touch.lock().unwrap().feed(&buffer[..len], received);
             }
 
+            let mut fe_touch = fe_touch.lock().unwrap();
+            fe_touch.save_sensitivity_profile();
             fe_touch.port.write_all(HALT)?;
 
+            let stats = fe_touch.frame_stats();
+            info!(
+                "Touch: {} frames received, {} resyncs, {} bytes discarded",
+                stats.frames, stats.resyncs, stats.discarded_bytes
+            );
+            let stats = fe_touch.debounce_stats();
+            info!(
+                "Touch: debounce suppressed {} presses and {} releases, extended {} holds",
+                stats.suppressed_presses, stats.suppressed_releases, stats.extended_holds
+            );
+            for player_num in 0..2 {
+                let stuck = fe_touch.stuck_detector();
+                if !stuck.stuck_sensors(player_num).is_empty() {
+                    warn!("Touch: P{} has sensors stuck on exit", player_num + 1);
+                    stuck.log_mask(player_num);
+                }
+            }
+
             Ok(())
         })
         .unwrap();
 
     info!("Touchscreen is ready, good luck touchin'!");
-    info!("If touchscreen doesn't work, restart the application, go in test menu and exit it so checks run again");
 
-    Ok((finale_handle, deluxe_handle))
+    handles.push(finale_handle);
+    Ok(handles)
 }
@@ -0,0 +1,292 @@
+// Emulator of the game host (ALLS) side of a Deluxe touch port.
+//
+// Does what Deluxe does on startup: `{RSET}`, `{HALT}`, then `{ L/R Area r Ratio }` and
+// `{ L/R Area k Threshold }` for every zone, each one waiting for its `( ... )` acknowledge,
+// and finally `{STAT}`. After that the panel streams 9 bytes long `( ... )` frames, which are
+// validated and decoded back into Deluxe zone names. Every unexpected byte is an error, so this
+// is strict on purpose: it's meant for testing the bridge, not for being forgiving.
+
+use std::io;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use log::{debug, info};
+
+use crate::config::EmulateAllsArgs;
+use crate::touch::mapping::{deluxe_zones_in, DELUXE_ZONES};
+use crate::touch::sensitivity::{AREA_BASE, PLAYER_SIDE, SENS_CMD};
+use crate::touch::{HALT, RSET, STAT};
+use crate::transport;
+use crate::transport::Transport;
+
+pub const RATIO_CMD: u8 = b'r';
+
+/// How long to wait for an acknowledge or a frame
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Deluxe frame bytes carry 5 zones each
+const FRAME_DATA_MASK: u8 = 0b1_1111;
+
+pub struct AllsTouchHost {
+    port: Box<dyn Transport>,
+    player_num: usize,
+}
+
+impl AllsTouchHost {
+    pub fn new(port_name: &str, player_num: usize) -> io::Result<Self> {
+        Self::with_transport(transport::open(port_name, 115_200)?, player_num)
+    }
+
+    pub fn with_transport(mut port: Box<dyn Transport>, player_num: usize) -> io::Result<Self> {
+        port.set_timeout(RESPONSE_TIMEOUT)?;
+        Ok(Self { port, player_num })
+    }
+
+    pub fn set_response_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.port.set_timeout(timeout)
+    }
+
+    /// Game's startup sequence, ends with the panel streaming frames
+    pub fn handshake(&mut self, ratio: u8, sensitivity: u8) -> io::Result<()> {
+        self.port.write_all(RSET)?;
+        self.halt()?;
+        for zone in 0..DELUXE_ZONES.len() {
+            self.set_zone(RATIO_CMD, zone, ratio)?;
+        }
+        for zone in 0..DELUXE_ZONES.len() {
+            self.set_zone(SENS_CMD, zone, sensitivity)?;
+        }
+        self.port.write_all(STAT)
+    }
+
+    /// Sends `{HALT}` and throws away frames that were already on the way
+    pub fn halt(&mut self) -> io::Result<()> {
+        self.port.write_all(HALT)?;
+        let mut buf = [0u8; 64];
+        loop {
+            match self.port.read(&mut buf) {
+                Ok(len) => debug!("ALLS: discarded {} bytes after HALT", len),
+                Err(err) if transport::is_timeout(&err) => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Sends a ratio (`r`) or sensitivity (`k`) command for zone (index in `DELUXE_ZONES`)
+    /// and checks the acknowledge
+    pub fn set_zone(&mut self, cmd: u8, zone: usize, value: u8) -> io::Result<()> {
+        let side = PLAYER_SIDE[self.player_num];
+        let area = AREA_BASE + zone as u8;
+        self.port.write_all(&[b'{', side, area, cmd, value, b'}'])?;
+
+        let expected = [b'(', side, area, cmd, value, b')'];
+        let mut response = [0u8; 6];
+        self.read_response(&mut response)?;
+        if response != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "ALLS: expected {:02X?} as acknowledge of {} {}, got {:02X?}",
+                    expected, DELUXE_ZONES[zone].0, cmd as char, response
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads and validates one touch frame
+    pub fn read_frame(&mut self) -> io::Result<[u8; 9]> {
+        let mut frame = [0u8; 9];
+        self.read_response(&mut frame)?;
+        validate_frame(&frame)?;
+        Ok(frame)
+    }
+
+    /// Reads one touch frame and returns names of pressed zones
+    pub fn read_touch(&mut self) -> io::Result<Vec<&'static str>> {
+        Ok(deluxe_zones_in(&self.read_frame()?))
+    }
+
+    fn read_response(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.port.read_exact(buf).map_err(|err| {
+            if transport::is_timeout(&err) {
+                io::Error::new(io::ErrorKind::TimedOut, "ALLS: touch panel didn't respond")
+            } else {
+                err
+            }
+        })
+    }
+}
+
+/// Checks that a Deluxe touch frame is `(`, 7 data bytes with 5 bits each, `)`
+pub fn validate_frame(frame: &[u8; 9]) -> io::Result<()> {
+    let data_ok = frame[1..8].iter().all(|b| b & !FRAME_DATA_MASK == 0);
+    if frame[0] != b'(' || frame[8] != b')' || !data_ok {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("ALLS: malformed touch frame {:02X?}", frame),
+        ));
+    }
+    Ok(())
+}
+
+/// Entry point of `emulate-alls-touch` subcommand
+pub fn run_emulator(args: &EmulateAllsArgs, running: &AtomicBool) -> io::Result<()> {
+    let mut host = AllsTouchHost::new(&args.port, args.player as usize - 1)?;
+    host.handshake(args.ratio, args.sensitivity)?;
+    info!("ALLS: handshake with {} passed, reading touches", args.port);
+
+    let mut frames = 0u64;
+    let mut pressed = Vec::new();
+    while running.load(Ordering::Acquire) && args.frames.is_none_or(|max| frames < max) {
+        let zones = match host.read_touch() {
+            Ok(zones) => zones,
+            Err(err) if transport::is_timeout(&err) => continue,
+            Err(err) => return Err(err),
+        };
+        frames += 1;
+        if zones != pressed {
+            info!("P{} touch: {}", args.player, zones.join(" "));
+            pressed = zones;
+        }
+    }
+
+    host.halt()?;
+    info!("ALLS: {} frames received", frames);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::touch::alls::{validate_frame, AllsTouchHost};
+    use crate::touch::deluxe::Deluxe;
+    use crate::touch::finale::RingEdge2;
+    use crate::touch::mapping::ZoneMapping;
+    use crate::touch::simulator::{parse_script, FinaleSimulator};
+    use crate::touch::{RSET, STAT};
+    use crate::transport::memory::MemoryTransport;
+
+    /// Bridge with P1's Deluxe port and a simulated panel playing `script`, run by a thread
+    /// until the flag is cleared. Returns the game's end of P1's port
+    fn spawn_bridge(
+        script: &str,
+    ) -> (
+        AllsTouchHost,
+        Arc<AtomicBool>,
+        thread::JoinHandle<FinaleSimulator>,
+    ) {
+        let (panel_end, bridge_end) = MemoryTransport::pair("finale");
+        let (dx_p1, game_p1) = MemoryTransport::pair("p1");
+        let (dx_p2, _game_p2) = MemoryTransport::pair("p2");
+
+        let mut deluxe = Deluxe::with_transport(Box::new(dx_p1), 0).unwrap();
+        let dx_p1_port = deluxe.port.try_clone().unwrap();
+        let mut bridge = RingEdge2::with_transport(
+            Box::new(bridge_end),
+            Some(dx_p1_port),
+            Some(Box::new(dx_p2)),
+            ZoneMapping::default(),
+        )
+        .unwrap();
+        bridge.set_sensitivity_forwarding(true);
+        bridge.port.write_all(STAT).unwrap();
+
+        let script = parse_script(script).unwrap();
+        let mut sim = FinaleSimulator::with_transport(Box::new(panel_end), script).unwrap();
+        sim.set_frame_interval(Duration::from_millis(1));
+
+        let running = Arc::new(AtomicBool::new(true));
+        let bridge_running = running.clone();
+        let handle = thread::spawn(move || {
+            while bridge_running.load(Ordering::Acquire) {
+                for c in deluxe.read().unwrap() {
+                    bridge.parse_command_from_alls(c).unwrap();
+                }
+                bridge.read().unwrap();
+                sim.poll().unwrap();
+            }
+            sim
+        });
+
+        let mut host = AllsTouchHost::with_transport(Box::new(game_p1), 0).unwrap();
+        host.set_response_timeout(Duration::from_millis(200))
+            .unwrap();
+        (host, running, handle)
+    }
+
+    /// Reads frames until something is pressed
+    fn wait_for_touch(host: &mut AllsTouchHost) -> Vec<&'static str> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut zones = host.read_touch().unwrap();
+        while zones.is_empty() && Instant::now() < deadline {
+            zones = host.read_touch().unwrap();
+        }
+        zones
+    }
+
+    #[test]
+    pub fn handshake_and_touch_through_bridge() {
+        let (mut host, running, handle) = spawn_bridge("0 1 B1");
+        host.handshake(50, 20).unwrap();
+        assert_eq!(wait_for_touch(&mut host), ["B1", "E1", "E2"]);
+        host.halt().unwrap();
+
+        running.store(false, Ordering::Release);
+        let sim = handle.join().unwrap();
+        // Same threshold everywhere, so every Finale sensor of P1 gets it exactly once
+        assert_eq!(sim.sensitivity.len(), 17);
+        assert!(sim
+            .sensitivity
+            .iter()
+            .all(|&(side, _, value)| side == b'L' && value == 20));
+    }
+
+    #[test]
+    pub fn test_menu_check_passes_through_bridge() {
+        let (mut host, running, handle) = spawn_bridge("0 1 B1");
+        host.handshake(50, 20).unwrap();
+        assert_eq!(wait_for_touch(&mut host), ["B1", "E1", "E2"]);
+
+        // Test menu touch check while the game is running. The game expects no reply to RSET
+        host.halt().unwrap();
+        host.port.write_all(RSET).unwrap();
+        let err = host.read_response(&mut [0u8; 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        // Then the whole sequence again, every acknowledge is checked byte for byte
+        host.handshake(40, 30).unwrap();
+        assert_eq!(wait_for_touch(&mut host), ["B1", "E1", "E2"]);
+        host.halt().unwrap();
+
+        running.store(false, Ordering::Release);
+        let sim = handle.join().unwrap();
+        // Panel was reset and got the new thresholds only
+        assert_eq!(sim.sensitivity.len(), 17);
+        assert!(sim.sensitivity.iter().all(|&(_, _, value)| value == 30));
+    }
+
+    #[test]
+    pub fn wrong_acknowledge_is_an_error() {
+        let (host_end, mut panel) = MemoryTransport::pair("p1");
+        let mut host = AllsTouchHost::with_transport(Box::new(host_end), 0).unwrap();
+        panel.write_all(b"(LAk\x15)").unwrap();
+        assert!(host.set_zone(b'k', 0, 0x14).is_err());
+
+        panel.write_all(b"(LAk\x14)").unwrap();
+        host.set_zone(b'k', 0, 0x14).unwrap();
+    }
+
+    #[test]
+    pub fn validates_frames() {
+        assert!(validate_frame(&[b'(', 0, 8, 0, 0, 0, 6, 0, b')']).is_ok());
+        assert!(validate_frame(&[b'(', 0, 8, 0, 0, 0, 6, 0, b'(']).is_err());
+        assert!(validate_frame(&[b'(', 0, 0x20, 0, 0, 0, 0, 0, b')']).is_err());
+    }
+}
@@ -0,0 +1,264 @@
+// Debouncing of Finale touch sensors.
+//
+// Sensors chatter at the edges, a press can flicker off for a single frame (or on, when a hand
+// just passes by), which breaks slides on Deluxe. Every sensor is filtered before translation:
+// a change only goes through after it is seen for `press_frames`/`release_frames` frames in a
+// row, and a registered press is held for at least `hold_frames` frames. All counts are in
+// touch frames, which come every ~15 ms. Defaults (1, 1, 0) pass everything through unchanged.
+
+use std::collections::BTreeMap;
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::helper_funcs::bit_read;
+use crate::touch::mapping::{finale_sensors, finale_zone_position};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DebounceSettings {
+    /// Frames a sensor has to be seen pressed in a row before the press goes through
+    pub press_frames: u8,
+    /// Frames a sensor has to be seen released in a row before the release goes through
+    pub release_frames: u8,
+    /// Frames a registered press is held at least, even if the sensor is released earlier
+    pub hold_frames: u8,
+}
+
+impl Default for DebounceSettings {
+    fn default() -> Self {
+        Self {
+            press_frames: 1,
+            release_frames: 1,
+            hold_frames: 0,
+        }
+    }
+}
+
+/// Per sensor override, unset values are taken from the section
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DebounceOverride {
+    pub press_frames: Option<u8>,
+    pub release_frames: Option<u8>,
+    pub hold_frames: Option<u8>,
+}
+
+/// `[touch.debounce]` config section
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DebounceConfig {
+    #[serde(flatten)]
+    pub settings: DebounceSettings,
+    /// Finale sensor name (A1..A8, B1..B8, C) to its own settings
+    pub zones: BTreeMap<String, DebounceOverride>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DebounceStats {
+    /// Presses shorter than press_frames that never went through
+    pub suppressed_presses: u64,
+    /// Releases shorter than release_frames (or within hold_frames) that never went through
+    pub suppressed_releases: u64,
+    /// Presses that were kept pressed longer because of hold_frames
+    pub extended_holds: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct SensorState {
+    pressed: bool,
+    /// Frames in a row the raw state differs from `pressed`
+    pending: u8,
+    /// Frames since the press was registered
+    held: u8,
+    /// Release was delayed by hold_frames
+    extended: bool,
+}
+
+/// Filters one player's half of Finale touch frames, sensor by sensor
+pub struct Debouncer {
+    /// (byte, bit, settings) of every Finale sensor
+    sensors: Vec<(usize, usize, DebounceSettings)>,
+    state: [Vec<SensorState>; 2],
+    stats: DebounceStats,
+}
+
+impl Debouncer {
+    pub fn from_config(config: &DebounceConfig) -> io::Result<Self> {
+        let mut sensors: Vec<_> = finale_sensors()
+            .map(|(byte, bit, _)| (byte, bit, config.settings))
+            .collect();
+
+        for (zone, over) in &config.zones {
+            let position = finale_zone_position(zone)
+                .ok_or_else(|| invalid_debounce(format!("unknown Finale touch zone \"{zone}\"")))?;
+            let (_, _, settings) = sensors
+                .iter_mut()
+                .find(|(byte, bit, _)| (*byte, *bit) == position)
+                .unwrap();
+            settings.press_frames = over.press_frames.unwrap_or(settings.press_frames);
+            settings.release_frames = over.release_frames.unwrap_or(settings.release_frames);
+            settings.hold_frames = over.hold_frames.unwrap_or(settings.hold_frames);
+        }
+
+        if sensors
+            .iter()
+            .any(|(_, _, s)| s.press_frames == 0 || s.release_frames == 0)
+        {
+            return Err(invalid_debounce(
+                "press_frames and release_frames have to be at least 1".to_string(),
+            ));
+        }
+
+        let state = vec![SensorState::default(); sensors.len()];
+        Ok(Self {
+            sensors,
+            state: [state.clone(), state],
+            stats: DebounceStats::default(),
+        })
+    }
+
+    /// Takes one player's 4 sensor bytes and returns them debounced
+    pub fn filter(&mut self, player_num: usize, finale: &[u8]) -> [u8; 4] {
+        let mut output = [0u8; 4];
+        for (i, &(byte, bit, settings)) in self.sensors.iter().enumerate() {
+            let raw = finale.get(byte).is_some_and(|b| bit_read(b, bit));
+            let state = &mut self.state[player_num][i];
+
+            if raw == state.pressed {
+                // Change didn't last long enough
+                if state.pending > 0 {
+                    if state.pressed {
+                        self.stats.suppressed_releases += 1;
+                    } else {
+                        self.stats.suppressed_presses += 1;
+                    }
+                }
+                state.pending = 0;
+            } else {
+                state.pending = state.pending.saturating_add(1);
+                if raw && state.pending >= settings.press_frames {
+                    state.pressed = true;
+                    state.pending = 0;
+                    state.held = 0;
+                    state.extended = false;
+                } else if !raw && state.pending >= settings.release_frames {
+                    if state.held >= settings.hold_frames {
+                        state.pressed = false;
+                        state.pending = 0;
+                    } else if !state.extended {
+                        state.extended = true;
+                        self.stats.extended_holds += 1;
+                    }
+                }
+            }
+
+            if state.pressed {
+                state.held = state.held.saturating_add(1);
+                output[byte] |= 1 << bit;
+            }
+        }
+        output
+    }
+
+    pub fn stats(&self) -> DebounceStats {
+        self.stats
+    }
+}
+
+impl Default for Debouncer {
+    fn default() -> Self {
+        Self::from_config(&DebounceConfig::default()).unwrap()
+    }
+}
+
+fn invalid_debounce(msg: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid [touch.debounce]: {msg}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::touch::debounce::{DebounceConfig, DebounceOverride, DebounceSettings, Debouncer};
+
+    const A1: [u8; 4] = [0b1, 0, 0, 0];
+    const B1: [u8; 4] = [0b10, 0, 0, 0];
+    const NONE: [u8; 4] = [0; 4];
+
+    fn run(debouncer: &mut Debouncer, frames: &[[u8; 4]]) -> Vec<[u8; 4]> {
+        frames.iter().map(|f| debouncer.filter(0, f)).collect()
+    }
+
+    fn debouncer(press_frames: u8, release_frames: u8, hold_frames: u8) -> Debouncer {
+        Debouncer::from_config(&DebounceConfig {
+            settings: DebounceSettings {
+                press_frames,
+                release_frames,
+                hold_frames,
+            },
+            ..DebounceConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    pub fn default_passes_through() {
+        let mut d = Debouncer::default();
+        let frames = [A1, NONE, B1, A1, NONE];
+        assert_eq!(run(&mut d, &frames), frames);
+        assert_eq!(d.stats().suppressed_presses, 0);
+    }
+
+    #[test]
+    pub fn release_flicker_is_suppressed() {
+        let mut d = debouncer(1, 2, 0);
+        assert_eq!(
+            run(&mut d, &[A1, NONE, A1, NONE, NONE, NONE]),
+            [A1, A1, A1, A1, NONE, NONE]
+        );
+        assert_eq!(d.stats().suppressed_releases, 1);
+    }
+
+    #[test]
+    pub fn short_press_is_suppressed() {
+        let mut d = debouncer(2, 1, 0);
+        assert_eq!(
+            run(&mut d, &[A1, NONE, A1, A1, A1]),
+            [NONE, NONE, NONE, A1, A1]
+        );
+        assert_eq!(d.stats().suppressed_presses, 1);
+    }
+
+    #[test]
+    pub fn hold_extends_short_press() {
+        let mut d = debouncer(1, 1, 3);
+        assert_eq!(
+            run(&mut d, &[A1, NONE, NONE, NONE, NONE]),
+            [A1, A1, A1, NONE, NONE]
+        );
+        assert_eq!(d.stats().extended_holds, 1);
+    }
+
+    #[test]
+    pub fn zone_override_and_players_are_separate() {
+        let mut config = DebounceConfig::default();
+        config.zones.insert(
+            "B1".to_string(),
+            DebounceOverride {
+                release_frames: Some(2),
+                ..DebounceOverride::default()
+            },
+        );
+        let mut d = Debouncer::from_config(&config).unwrap();
+        // A1 keeps defaults, B1 rides over a one frame release
+        assert_eq!(run(&mut d, &[A1, NONE]), [A1, NONE]);
+        assert_eq!(run(&mut d, &[B1, NONE, B1]), [B1, B1, B1]);
+        assert_eq!(d.filter(1, &NONE), NONE);
+
+        config
+            .zones
+            .insert("A9".to_string(), DebounceOverride::default());
+        assert!(Debouncer::from_config(&config).is_err());
+    }
+}
@@ -1,81 +1,324 @@
+use log::warn;
+use std::io;
 use std::io::Read;
 use std::time::Duration;
 
-use serialport::{ClearBuffer, SerialPort};
+use crate::transport;
+use crate::transport::Transport;
 
+/// Every command is `{`, 4 bytes and `}`
+pub const COMMAND_LEN: usize = 6;
+
+/// Command received from Deluxe on one of the players' touch ports
 pub struct MessageCmd {
     pub player_num: usize,
     pub cmd: TouchMasterCommand,
 }
 
+/// Commands that Deluxe (ALLS) sends to its touch panel, in `{....}` form
 #[repr(u8)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TouchMasterCommand {
-    // { R S E T } Tells Touchscreen to reset, have no idea what to do with it
+    // { R S E T } Tells Touchscreen to reset, resets Finale panel too (see RingEdge2::reset)
     Reset = b'E',
     // { H A L T } Tells Touchscreen to stop sending data
     Halt = b'L',
     // { S T A T } Tells Touchscreen to start sending data
     Stat = b'A',
-    // { L/R TouchArea k Threshold }
+    // { L/R TouchArea k Threshold }, can be forwarded to Finale panel (see touch::sensitivity)
     Sens(u8, u8, u8) = b'k',
     // There is also Ratio, but its useless on an actual cabinet (todo: verify this)
     Ratio(u8, u8, u8) = b'r',
-    Unknown,
+    // Anything else, 4 bytes between the braces
+    Unknown([u8; 4]),
 }
 
 impl TouchMasterCommand {
-    pub fn from_buf(buf: &[u8]) -> TouchMasterCommand {
-        match buf[3] {
-            b'E' => TouchMasterCommand::Reset,
-            b'L' => TouchMasterCommand::Halt,
-            b'A' => TouchMasterCommand::Stat,
-            b'k' => TouchMasterCommand::Sens(buf[1], buf[2], buf[4]),
-            b'r' => TouchMasterCommand::Ratio(buf[1], buf[2], buf[4]),
-            _ => TouchMasterCommand::Unknown,
+    /// Decodes the 4 bytes between `{` and `}`
+    pub fn from_body(body: &[u8; 4]) -> TouchMasterCommand {
+        match *body {
+            [b'R', b'S', b'E', b'T'] => TouchMasterCommand::Reset,
+            [b'H', b'A', b'L', b'T'] => TouchMasterCommand::Halt,
+            [b'S', b'T', b'A', b'T'] => TouchMasterCommand::Stat,
+            [l_r @ (b'L' | b'R'), area, b'k', value] => TouchMasterCommand::Sens(l_r, area, value),
+            [l_r @ (b'L' | b'R'), area, b'r', value] => TouchMasterCommand::Ratio(l_r, area, value),
+            _ => TouchMasterCommand::Unknown(*body),
         }
     }
 }
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CommandStats {
+    /// Complete commands, unknown ones included
+    pub commands: u64,
+    pub unknown: u64,
+    /// How many times the stream lost alignment and had to be resynchronised
+    pub resyncs: u64,
+    /// Bytes thrown away while looking for a command start
+    pub discarded_bytes: u64,
+}
+
+/// Byte-by-byte parser of `{....}` commands. Hunts for `{`, checks that `}` closes the command
+/// and returns complete commands only. Bytes between the braces can be anything (a threshold
+/// can be 0x7B too), so a broken command is only noticed at its end, then the stream is
+/// rescanned from the byte after its `{`
+pub struct CommandParser {
+    command: [u8; COMMAND_LEN],
+    pos: usize,
+    in_sync: bool,
+    stats: CommandStats,
+}
+
+impl CommandParser {
+    pub fn new() -> Self {
+        Self {
+            command: [0; COMMAND_LEN],
+            pos: 0,
+            in_sync: true,
+            stats: CommandStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> CommandStats {
+        self.stats
+    }
+
+    /// Feeds one byte, returns a command once it's complete
+    pub fn push(&mut self, byte: u8) -> Option<TouchMasterCommand> {
+        let valid = match self.pos {
+            0 => byte == b'{',
+            p if p == COMMAND_LEN - 1 => byte == b'}',
+            _ => true,
+        };
+        if !valid {
+            self.lose_sync(byte);
+            return None;
+        }
+
+        self.command[self.pos] = byte;
+        self.pos += 1;
+        if self.pos < COMMAND_LEN {
+            return None;
+        }
+
+        self.pos = 0;
+        self.in_sync = true;
+        self.stats.commands += 1;
+        let mut body = [0u8; 4];
+        body.copy_from_slice(&self.command[1..COMMAND_LEN - 1]);
+        let cmd = TouchMasterCommand::from_body(&body);
+        if let TouchMasterCommand::Unknown(_) = cmd {
+            self.stats.unknown += 1;
+        }
+        Some(cmd)
+    }
+
+    fn lose_sync(&mut self, byte: u8) {
+        // Everything after the `{` (and the byte itself) may hold the start of the next command
+        let mut pending = self.command[1..self.pos.max(1)].to_vec();
+        pending.push(byte);
+        let discarded = &self.command[..self.pos];
+
+        if self.in_sync {
+            self.in_sync = false;
+            self.stats.resyncs += 1;
+            warn!(
+                "Touch: Deluxe command stream out of sync, got {:02X?} after {:02X?}, \
+                 resynchronising (total: {})",
+                byte, discarded, self.stats.resyncs
+            );
+        }
+
+        let start = pending.iter().position(|&b| b == b'{');
+        // The `{` that started the broken command and everything up to the next `{`
+        let dropped = usize::from(self.pos > 0) + start.unwrap_or(pending.len());
+        self.stats.discarded_bytes += dropped as u64;
+        self.pos = 0;
+        if let Some(start) = start {
+            // Less than a full command, can't complete one
+            for &b in &pending[start..] {
+                self.push(b);
+            }
+        }
+    }
+}
+
+impl Default for CommandParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One player's virtual Deluxe touch port. Reads commands from the game, the caller applies
+/// them to the Finale panel (see RingEdge2::parse_command_from_alls)
 pub struct Deluxe {
-    pub port: serialport::COMPort,
+    pub port: Box<dyn Transport>,
     player_num: usize,
-    read_buffer: [u8; 6],
-    pub sender_channel: crossbeam_channel::Sender<MessageCmd>,
+    read_buffer: [u8; 64],
+    parser: CommandParser,
 }
 
 impl Deluxe {
-    pub fn new(
-        port_name: String,
-        player_num: usize,
-        sender_channel: crossbeam_channel::Sender<MessageCmd>,
-    ) -> Result<Self, serialport::Error> {
-        let mut port = serialport::new(port_name, 115_200).open_native()?;
+    pub fn new(port_name: String, player_num: usize) -> io::Result<Self> {
+        let port = transport::open(&port_name, 115_200)?;
+        Self::with_transport(port, player_num)
+    }
+
+    pub fn with_transport(mut port: Box<dyn Transport>, player_num: usize) -> io::Result<Self> {
         port.set_timeout(Duration::from_millis(1))?;
-        port.clear(ClearBuffer::All)?;
+        port.clear()?;
         Ok(Self {
             port,
             player_num,
-            read_buffer: [0; 6],
-            sender_channel,
+            read_buffer: [0; 64],
+            parser: CommandParser::new(),
         })
     }
 
-    pub fn read(&mut self) {
-        if let Err(err) = self.port.read_exact(self.read_buffer.as_mut()) {
-            if err.kind() == std::io::ErrorKind::TimedOut {
-                return;
-            } else {
-                panic!("{}", err);
-            }
-        }
-        let cmd = TouchMasterCommand::from_buf(self.read_buffer.as_ref());
+    /// Reads whatever the game has sent so far and returns every complete command in it
+    pub fn read(&mut self) -> io::Result<Vec<MessageCmd>> {
+        let len = match self.port.read(self.read_buffer.as_mut()) {
+            Ok(len) => len,
+            Err(err) if transport::is_timeout(&err) => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
 
-        self.sender_channel
-            .send(MessageCmd {
+        let mut commands = Vec::new();
+        for i in 0..len {
+            let cmd = match self.parser.push(self.read_buffer[i]) {
+                Some(TouchMasterCommand::Unknown(body)) => {
+                    warn!(
+                        "Touch: P{} unknown command from Deluxe {:02X?} ({:?})",
+                        self.player_num + 1,
+                        body,
+                        String::from_utf8_lossy(&body)
+                    );
+                    continue;
+                }
+                Some(cmd) => cmd,
+                None => continue,
+            };
+            commands.push(MessageCmd {
                 player_num: self.player_num,
                 cmd,
-            })
-            .unwrap();
+            });
+        }
+        Ok(commands)
+    }
+
+    pub fn command_stats(&self) -> CommandStats {
+        self.parser.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::touch::deluxe::{CommandParser, Deluxe, TouchMasterCommand};
+    use crate::touch::sensitivity::{AREA_BASE, SENS_CMD};
+    use crate::touch::{HALT, RSET, STAT};
+    use crate::transport::memory::MemoryTransport;
+
+    fn parse(bytes: &[u8]) -> (Vec<TouchMasterCommand>, CommandParser) {
+        let mut parser = CommandParser::new();
+        let commands = bytes.iter().filter_map(|&b| parser.push(b)).collect();
+        (commands, parser)
+    }
+
+    #[test]
+    pub fn parses_every_game_command() {
+        let mut stream = [RSET, HALT].concat();
+        let mut expected = vec![TouchMasterCommand::Reset, TouchMasterCommand::Halt];
+        // Startup check sets ratio and then sensitivity of all 34 zones, on both sides
+        for side in [b'L', b'R'] {
+            for area in AREA_BASE..AREA_BASE + 34 {
+                stream.extend([b'{', side, area, b'r', 50, b'}']);
+                expected.push(TouchMasterCommand::Ratio(side, area, 50));
+            }
+            for area in AREA_BASE..AREA_BASE + 34 {
+                stream.extend([b'{', side, area, SENS_CMD, 20, b'}']);
+                expected.push(TouchMasterCommand::Sens(side, area, 20));
+            }
+        }
+        stream.extend(STAT);
+        expected.push(TouchMasterCommand::Stat);
+
+        let (commands, parser) = parse(&stream);
+        assert_eq!(commands, expected);
+        assert_eq!(parser.stats().commands, expected.len() as u64);
+        assert_eq!(parser.stats().resyncs, 0);
+    }
+
+    #[test]
+    pub fn one_read_returns_every_command_in_it() {
+        let (port, mut game) = MemoryTransport::pair("p1");
+        let mut deluxe = Deluxe::with_transport(Box::new(port), 0).unwrap();
+        // 5 bytes of a command are already buffered, the next read completes it and 10 more
+        game.write_all(b"{LAk\x14").unwrap();
+        assert!(deluxe.read().unwrap().is_empty());
+        let mut stream = b"}".to_vec();
+        for area in AREA_BASE + 1..AREA_BASE + 11 {
+            stream.extend([b'{', b'L', area, SENS_CMD, 20, b'}']);
+        }
+        stream.extend(b"{HA");
+        assert_eq!(stream.len(), 64);
+        game.write_all(&stream).unwrap();
+
+        let commands = deluxe.read().unwrap();
+        assert_eq!(commands.len(), 11);
+        assert!(commands.iter().all(|msg| msg.player_num == 0));
+        assert_eq!(
+            commands[10].cmd,
+            TouchMasterCommand::Sens(b'L', AREA_BASE + 10, 20)
+        );
+        game.write_all(b"LT}").unwrap();
+        assert_eq!(deluxe.read().unwrap()[0].cmd, TouchMasterCommand::Halt);
+    }
+
+    #[test]
+    pub fn value_can_look_like_a_brace() {
+        let (commands, _) = parse(b"{LAk{}{RAr}}");
+        assert_eq!(
+            commands,
+            [
+                TouchMasterCommand::Sens(b'L', b'A', b'{'),
+                TouchMasterCommand::Ratio(b'R', b'A', b'}')
+            ]
+        );
+    }
+
+    #[test]
+    pub fn resyncs_after_short_command_and_garbage() {
+        // Command missing a byte is dropped, the next one is not shifted
+        let (commands, parser) = parse(b"\x00\x13{HAL}{STAT}xx{HALT}");
+        assert_eq!(
+            commands,
+            [TouchMasterCommand::Stat, TouchMasterCommand::Halt]
+        );
+        assert_eq!(parser.stats().resyncs, 2);
+        assert_eq!(parser.stats().discarded_bytes, 9);
+    }
+
+    #[test]
+    pub fn restarts_at_brace_inside_broken_command() {
+        // `{RS` got cut off, the next command starts right inside it
+        let (commands, parser) = parse(b"{RS{HALT}");
+        assert_eq!(commands, [TouchMasterCommand::Halt]);
+        assert_eq!(parser.stats().discarded_bytes, 3);
+    }
+
+    #[test]
+    pub fn reports_unknown_commands() {
+        let (commands, parser) = parse(b"{ABCD}{XAk\x14}");
+        assert_eq!(
+            commands,
+            [
+                TouchMasterCommand::Unknown(*b"ABCD"),
+                TouchMasterCommand::Unknown(*b"XAk\x14")
+            ]
+        );
+        assert_eq!(parser.stats().unknown, 2);
+        assert_eq!(parser.stats().resyncs, 0);
     }
 }
@@ -1,130 +1,347 @@
 use log::{debug, error};
 use std::io;
 use std::io::{Read, Write};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use serialport::{COMPort, SerialPort};
-
-use crate::helper_funcs::bit_read;
+use crate::touch::debounce::{DebounceStats, Debouncer};
 use crate::touch::deluxe::TouchMasterCommand;
-use crate::touch::{MessageCmd, HALT};
+use crate::touch::frame::{FrameParser, FrameStats, FRAME_LEN, PLAYER_DATA};
+use crate::touch::keys::TouchKeys;
+use crate::touch::latency::LatencyMonitor;
+use crate::touch::layout::{Layout, TouchMode};
+use crate::touch::mapping::{ZoneMapping, DEFAULT_DELUXE_WRITE_BUFFER};
+use crate::touch::profile::SensitivityProfile;
+use crate::touch::record::{RecordKind, TouchRecorder};
+use crate::touch::sensitivity::{SensitivityTranslator, DELUXE_SIDES};
+use crate::touch::stuck::StuckDetector;
+use crate::touch::{MessageCmd, HALT, RSET, STAT};
+use crate::transport;
+use crate::transport::Transport;
 
+/// Driver for the RingEdge 2 (Finale) touch panel. Reads both players' sensor frames and
+/// forwards them, translated, to the Deluxe touch ports of the players that are active.
+/// A Deluxe port can be missing, when only one player is used (see touch::layout)
 pub struct RingEdge2 {
-    pub port: COMPort,
+    pub port: Box<dyn Transport>,
 
-    read_buffer: [u8; 14],
-    pub deluxe_ports: [COMPort; 2],
+    read_buffer: [u8; 64],
+    parser: FrameParser,
+    pub deluxe_ports: [Option<Box<dyn Transport>>; 2],
     pub deluxe_active: [bool; 2],
+    mode: TouchMode,
+    layout: Layout,
+    mapping: ZoneMapping,
+    debouncer: Debouncer,
+    stuck: StuckDetector,
+    sensitivity: SensitivityTranslator,
+    /// Thresholds go to the panel, off by default (see touch::sensitivity)
+    sensitivity_forwarding: bool,
+    recorder: Option<TouchRecorder>,
+    latency: Option<LatencyMonitor>,
+    keys: Option<TouchKeys>,
+    profile: Option<SensitivityProfile>,
+    /// Panel was halted by a reset while no player was active, the next STAT restarts it
+    panel_halted: bool,
 }
 
-// TODO: Send data over channel to
-
 impl RingEdge2 {
     pub fn new(
         port_name: String,
-        deluxe_p1_port: COMPort,
-        deluxe_p2_port: COMPort,
-    ) -> Result<Self, serialport::Error> {
-        let mut port = serialport::new(port_name, 9600).open_native()?;
+        deluxe_p1_port: Option<Box<dyn Transport>>,
+        deluxe_p2_port: Option<Box<dyn Transport>>,
+        mapping: ZoneMapping,
+    ) -> io::Result<Self> {
+        let port = transport::open(&port_name, 9600)?;
+        Self::with_transport(port, deluxe_p1_port, deluxe_p2_port, mapping)
+    }
+
+    /// Same as [`RingEdge2::new`], but uses an already opened port
+    pub fn with_transport(
+        mut port: Box<dyn Transport>,
+        deluxe_p1_port: Option<Box<dyn Transport>>,
+        deluxe_p2_port: Option<Box<dyn Transport>>,
+        mapping: ZoneMapping,
+    ) -> io::Result<Self> {
         port.set_timeout(Duration::from_millis(0))?;
 
         Ok(Self {
             port,
-            read_buffer: [0; 14],
+            read_buffer: [0; 64],
+            parser: FrameParser::new(),
             deluxe_ports: [deluxe_p1_port, deluxe_p2_port],
             deluxe_active: [false, false],
+            mode: TouchMode::Both,
+            layout: Layout::default(),
+            debouncer: Debouncer::default(),
+            stuck: StuckDetector::default(),
+            sensitivity: SensitivityTranslator::new(&mapping),
+            sensitivity_forwarding: false,
+            recorder: None,
+            latency: None,
+            keys: None,
+            profile: None,
+            panel_halted: false,
+            mapping,
         })
     }
 
-    pub fn read(&mut self) {
-        if let Err(err) = self.port.read_exact(self.read_buffer[0..14].as_mut()) {
-            if err.kind() == io::ErrorKind::TimedOut {
-                return;
-            } else {
-                panic!("{}", err);
+    /// Reads whatever the panel has sent so far and forwards every complete frame
+    pub fn read(&mut self) -> io::Result<()> {
+        let len = match self.port.read(self.read_buffer.as_mut()) {
+            Ok(len) => len,
+            Err(err) if transport::is_timeout(&err) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        let read_buffer = self.read_buffer;
+        self.feed(&read_buffer[..len], Instant::now());
+        Ok(())
+    }
+
+    /// Forwards every complete frame in `data`, which was read from the panel elsewhere
+    /// (see touch::spawn_thread) at `received`
+    pub fn feed(&mut self, data: &[u8], received: Instant) {
+        for &b in data {
+            if let Some(frame) = self.parser.push(b) {
+                let started = Instant::now();
+                self.forward_frame(&frame, started);
+                if let Some(latency) = self.latency.as_mut() {
+                    latency.record(received, started, Instant::now());
+                }
             }
         }
+    }
+
+    fn forward_frame(&mut self, frame: &[u8; FRAME_LEN], now: Instant) {
+        self.record(RecordKind::Finale, frame);
+        let mut sides = [[0u8; 4]; 2];
+        for (side, data) in PLAYER_DATA.iter().enumerate() {
+            // Filter both sides every frame, so their state is right once a player starts
+            let sensors = self.layout.apply(&frame[data.clone()]);
+            let sensors = self.debouncer.filter(side, &sensors);
+            sides[side] = self.stuck.filter(side, &sensors, now);
+        }
 
-        // if self.read_buffer[0] != b'(' {
-        //     todo!();
-        // }
-        //
-        // if self.read_buffer[5] == b')' {
-        //     todo!();
-        // }
-        //
-        // if let Err(err) = self.port.read_exact(self.read_buffer[6..14].as_mut()) {
-        //     panic!("{}", err);
-        // }
+        for player in 0..2 {
+            let port = match self.deluxe_ports[player].as_mut() {
+                Some(port) if self.deluxe_active[player] => port,
+                _ => continue,
+            };
+            let sensors = &sides[self.mode.finale_side(player)];
+            match Self::send_to_deluxe(&self.mapping, sensors, port) {
+                Ok(write_buffer) => self.record(RecordKind::deluxe(player), &write_buffer),
+                Err(err) => {
+                    // Its writer has stopped, the Deluxe thread of the port reports it too
+                    error!(
+                        "Touch: writing {} failed, it gets no more frames: {}",
+                        port.name(),
+                        err
+                    );
+                    self.deluxe_ports[player] = None;
+                }
+            }
+        }
 
-        if self.deluxe_active[0] {
-            Self::send_to_deluxe(self.read_buffer[1..5].as_mut(), &mut self.deluxe_ports[0]);
+        if let Some(keys) = self.keys.as_mut() {
+            let players = [0, 1].map(|player| {
+                self.mode
+                    .uses_deluxe(player)
+                    .then(|| &sides[self.mode.finale_side(player)])
+            });
+            keys.update(&self.mapping, players);
         }
+    }
+
+    /// Sets which Finale side drives which Deluxe player
+    pub fn set_mode(&mut self, mode: TouchMode) {
+        self.mode = mode;
+    }
+
+    /// Also addresses sensitivity commands to the rotated/flipped panel sensors
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.sensitivity.set_layout(&layout);
+        self.layout = layout;
+    }
+
+    pub fn set_debouncer(&mut self, debouncer: Debouncer) {
+        self.debouncer = debouncer;
+    }
+
+    pub fn debounce_stats(&self) -> DebounceStats {
+        self.debouncer.stats()
+    }
+
+    pub fn set_stuck_detector(&mut self, stuck: StuckDetector) {
+        self.stuck = stuck;
+    }
+
+    /// Current stuck state and mask of the sensors, see touch::stuck
+    pub fn stuck_detector(&self) -> &StuckDetector {
+        &self.stuck
+    }
+
+    /// Sends the game's thresholds to the panel from now on, instead of only acknowledging them.
+    /// Experimental, see touch::sensitivity
+    pub fn set_sensitivity_forwarding(&mut self, forwarding: bool) {
+        self.sensitivity_forwarding = forwarding;
+    }
+
+    /// Starts pressing keys bound to touch zones, see touch::keys
+    pub fn set_keys(&mut self, keys: TouchKeys) {
+        self.keys = Some(keys);
+    }
+
+    /// Starts measuring latency of every frame, see touch::latency
+    pub fn set_latency_monitor(&mut self, latency: LatencyMonitor) {
+        self.latency = Some(latency);
+    }
+
+    pub fn latency_monitor(&self) -> Option<&LatencyMonitor> {
+        self.latency.as_ref()
+    }
+
+    /// Starts recording every raw and translated frame, see touch::record
+    pub fn set_recorder(&mut self, recorder: TouchRecorder) {
+        self.recorder = Some(recorder);
+    }
 
-        if self.deluxe_active[1] {
-            Self::send_to_deluxe(self.read_buffer[7..11].as_mut(), &mut self.deluxe_ports[1]);
+    fn record(&mut self, kind: RecordKind, data: &[u8]) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(err) = recorder.record(kind, data) {
+                error!("Touch: recording failed, stopping it: {}", err);
+                self.recorder = None;
+            }
         }
     }
 
+    pub fn frame_stats(&self) -> FrameStats {
+        self.parser.stats()
+    }
+
+    /// Applies a command that Deluxe sent to one of the player's touch ports
     pub fn parse_command_from_alls(&mut self, msg: MessageCmd) -> io::Result<()> {
         debug!("P{}: {:?}", msg.player_num + 1, msg.cmd);
+        let port = match self.deluxe_ports[msg.player_num].as_mut() {
+            Some(port) => port,
+            None => return Ok(()),
+        };
         match msg.cmd {
-            TouchMasterCommand::Reset => {
-                self.deluxe_active[msg.player_num] = false;
-            }
+            TouchMasterCommand::Reset => self.reset(msg.player_num)?,
             TouchMasterCommand::Halt => {
                 self.deluxe_active[msg.player_num] = false;
             }
             TouchMasterCommand::Stat => {
                 self.deluxe_active[msg.player_num] = true;
+                if std::mem::take(&mut self.panel_halted) {
+                    self.port.write_all(STAT)?;
+                }
+                // Game starts streaming once it's done with calibration
+                self.save_sensitivity_profile();
             }
             TouchMasterCommand::Ratio(l_r, area, value) => {
-                self.deluxe_ports[msg.player_num].write(&[
-                    b'(',
-                    l_r,
-                    area,
-                    TouchMasterCommand::Ratio as u8,
-                    value,
-                    b')',
-                ])?;
+                port.write_all(&[b'(', l_r, area, b'r', value, b')'])?;
             }
             TouchMasterCommand::Sens(l_r, area, value) => {
-                self.deluxe_ports[msg.player_num].write(&[
-                    b'(',
-                    l_r,
-                    area,
-                    TouchMasterCommand::Sens as u8,
-                    value,
-                    b')',
-                ])?;
+                // Game waits for the acknowledge, so reply first and then recalibrate the panel
+                port.write_all(&[b'(', l_r, area, b'k', value, b')'])?;
+                let value = match self.profile.as_mut() {
+                    Some(profile) => profile.update(msg.player_num, area, value),
+                    None => value,
+                };
+                self.forward_sensitivity(msg.player_num, l_r, area, value)?;
             }
             _ => {}
         };
         Ok(())
     }
 
-    fn send_to_deluxe(buf: &mut [u8], port: &mut dyn SerialPort) {
-        let mut write_buffer = DEFAULT_DELUXE_WRITE_BUFFER;
-        for (i, bit) in buf.iter().enumerate() {
-            for pos in 0..5 as usize {
-                if !bit_read(bit, pos) {
-                    continue;
-                }
+    /// Recalibrates the Finale sensors behind a Deluxe player's zone (`l_r` and `area` as Deluxe
+    /// sends them)
+    fn forward_sensitivity(
+        &mut self,
+        player_num: usize,
+        l_r: u8,
+        area: u8,
+        value: u8,
+    ) -> io::Result<()> {
+        if !self.sensitivity_forwarding || !self.mode.forwards_sensitivity(player_num) {
+            return Ok(());
+        }
+        let side = self.mode.finale_side(player_num);
+        for cmd in self.sensitivity.set(side, l_r, area, value) {
+            debug!("Finale sensitivity: {:?}", String::from_utf8_lossy(&cmd));
+            self.port.write_all(&cmd)?;
+        }
+        Ok(())
+    }
 
-                if let Some(areas) = FINALE_AREAS[i][pos] {
-                    areas.iter().for_each(|a| write_buffer[a.0] |= a.1);
+    /// Sends stored and configured thresholds to the panel right away and keeps track of
+    /// the ones the game sets from now on, see touch::profile
+    pub fn set_sensitivity_profile(&mut self, profile: SensitivityProfile) -> io::Result<()> {
+        for player_num in 0..2 {
+            // Stored per zone, as if the game sent it on both sides
+            for (area, value) in profile.thresholds(player_num) {
+                for l_r in DELUXE_SIDES {
+                    self.forward_sensitivity(player_num, l_r, area, value)?;
                 }
             }
         }
+        self.profile = Some(profile);
+        Ok(())
+    }
+
+    /// Saves thresholds set by the game, if they changed. Only takes a snapshot, the profile's
+    /// saver writes it (see touch::profile)
+    pub fn save_sensitivity_profile(&mut self) {
+        if let Some(profile) = self.profile.as_mut() {
+            if let Err(err) = profile.save() {
+                error!("Touch: saving sensitivity failed: {}", err);
+            }
+        }
+    }
+
+    /// `{RSET}` from Deluxe: its panel stops streaming until `{STAT}` and goes back to default
+    /// thresholds, the game then sends HALT and all of them again, waiting for each
+    /// acknowledge, and STAT. Nothing is sent back for RSET itself, the test menu's touch check
+    /// passes with that (see the ALLS emulator test test_menu_check_passes_through_bridge).
+    /// Finale panel is shared by both players, so it is reset as a whole: HALT, RSET, the other
+    /// side gets its thresholds back and, if the other player is active, STAT restarts
+    /// streaming so they keep touch. Otherwise the panel waits for the game's STAT
+    fn reset(&mut self, player_num: usize) -> io::Result<()> {
+        self.deluxe_active[player_num] = false;
+        if !self.mode.forwards_sensitivity(player_num) {
+            // Mirrored player, the panel side belongs to the other one
+            return Ok(());
+        }
+
+        let side = self.mode.finale_side(player_num);
+        self.sensitivity.reset(side);
+        self.port.write_all(HALT)?;
+        self.port.write_all(RSET)?;
+        for cmd in self.sensitivity.commands(1 - side) {
+            debug!("Finale sensitivity: {:?}", String::from_utf8_lossy(&cmd));
+            self.port.write_all(&cmd)?;
+        }
+        if self.deluxe_active.contains(&true) {
+            self.port.write_all(STAT)
+        } else {
+            self.panel_halted = true;
+            Ok(())
+        }
+    }
+
+    fn send_to_deluxe(
+        mapping: &ZoneMapping,
+        buf: &[u8],
+        port: &mut Box<dyn Transport>,
+    ) -> io::Result<[u8; 9]> {
+        let write_buffer = mapping.translate(buf);
         // debug!("{:02X?} {:02X?}", &write_buffer, &DEFAULT_ALLS_WRITE_BUFFER);
         if write_buffer.ne(&DEFAULT_DELUXE_WRITE_BUFFER) {
-            debug!(
-                "Touch pressed on {}, {:?}",
-                port.name().unwrap(),
-                &write_buffer
-            );
+            debug!("Touch pressed on {}, {:?}", port.name(), &write_buffer);
         }
-        port.write(&write_buffer).unwrap();
+        port.write_all(&write_buffer)?;
+        Ok(write_buffer)
     }
 }
 
@@ -136,78 +353,212 @@ impl Drop for RingEdge2 {
     }
 }
 
-static DEFAULT_DELUXE_WRITE_BUFFER: [u8; 9] = [b'(', 0, 0, 0, 0, 0, 0, 0, b')'];
-
-static FINALE_AREAS: [[Option<[(usize, u8); 3]>; 5]; 4] = [
-    [
-        Some([A1, D1, D2]),
-        Some([B1, E1, E2]),
-        Some([A2, D2, D3]),
-        Some([B2, E2, E3]),
-        None,
-    ],
-    [
-        Some([A3, D3, D4]),
-        Some([B3, E3, E4]),
-        Some([A4, D4, D5]),
-        Some([B4, E4, E5]),
-        None,
-    ],
-    [
-        Some([A5, D5, D6]),
-        Some([B5, E5, E6]),
-        Some([A6, D6, D7]),
-        Some([B6, E6, E7]),
-        None,
-    ],
-    [
-        Some([A7, D7, D8]),
-        Some([B7, E7, E8]),
-        Some([A8, D8, D1]),
-        Some([B8, E8, E1]),
-        Some([C1, C2, (0, 0)]),
-    ],
-];
-
-/// Mapping for Deluxe touch areas
-/// (usize, u8) = (Index of DELUXE_WRITE_BUFFER, Bit Position)
-static A1: (usize, u8) = (1, 1);
-static A2: (usize, u8) = (1, 2);
-static A3: (usize, u8) = (1, 4);
-static A4: (usize, u8) = (1, 8);
-static A5: (usize, u8) = (1, 16);
-
-static A6: (usize, u8) = (2, 1);
-static A7: (usize, u8) = (2, 2);
-static A8: (usize, u8) = (2, 4);
-static B1: (usize, u8) = (2, 8);
-static B2: (usize, u8) = (2, 16);
-
-static B3: (usize, u8) = (3, 1);
-static B4: (usize, u8) = (3, 2);
-static B5: (usize, u8) = (3, 4);
-static B6: (usize, u8) = (3, 8);
-static B7: (usize, u8) = (3, 16);
-
-static B8: (usize, u8) = (4, 1);
-static C1: (usize, u8) = (4, 2);
-static C2: (usize, u8) = (4, 4);
-static D1: (usize, u8) = (4, 8);
-static D2: (usize, u8) = (4, 16);
-
-static D3: (usize, u8) = (5, 1);
-static D4: (usize, u8) = (5, 2);
-static D5: (usize, u8) = (5, 4);
-static D6: (usize, u8) = (5, 8);
-static D7: (usize, u8) = (5, 16);
-
-static D8: (usize, u8) = (6, 1);
-static E1: (usize, u8) = (6, 2);
-static E2: (usize, u8) = (6, 4);
-static E3: (usize, u8) = (6, 8);
-static E4: (usize, u8) = (6, 16);
-
-static E5: (usize, u8) = (7, 1);
-static E6: (usize, u8) = (7, 2);
-static E7: (usize, u8) = (7, 4);
-static E8: (usize, u8) = (7, 8);
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::{Read, Write};
+    use std::time::{Duration, Instant};
+
+    use crate::touch::deluxe::{MessageCmd, TouchMasterCommand};
+    use crate::touch::finale::RingEdge2;
+    use crate::touch::layout::TouchMode;
+    use crate::touch::mapping::{ZoneMapping, DEFAULT_DELUXE_WRITE_BUFFER};
+    use crate::touch::profile::{SensitivityConfig, SensitivityProfile};
+    use crate::touch::simulator::FinaleSimulator;
+    use crate::touch::STAT;
+    use crate::transport::memory::MemoryTransport;
+    use crate::transport::Transport;
+
+    fn command(player_num: usize, cmd: TouchMasterCommand) -> MessageCmd {
+        MessageCmd { player_num, cmd }
+    }
+
+    fn bridge_with_panel(mode: TouchMode) -> (RingEdge2, FinaleSimulator) {
+        let (panel_end, bridge_end) = MemoryTransport::pair("finale");
+        let (dx_p1, _game_p1) = MemoryTransport::pair("p1");
+        let (dx_p2, _game_p2) = MemoryTransport::pair("p2");
+        let mut bridge = RingEdge2::with_transport(
+            Box::new(bridge_end),
+            Some(Box::new(dx_p1)),
+            Some(Box::new(dx_p2)),
+            ZoneMapping::default(),
+        )
+        .unwrap();
+        bridge.set_mode(mode);
+        bridge.set_sensitivity_forwarding(true);
+        bridge.port.write_all(STAT).unwrap();
+        let sim = FinaleSimulator::with_transport(Box::new(panel_end), Vec::new()).unwrap();
+        (bridge, sim)
+    }
+
+    /// Lets the simulator take in everything the bridge sent
+    fn settle(sim: &mut FinaleSimulator) {
+        let deadline = Instant::now() + Duration::from_millis(50);
+        while Instant::now() < deadline {
+            sim.poll().unwrap();
+        }
+    }
+
+    #[test]
+    pub fn failed_deluxe_write_drops_only_that_port() {
+        // Port whose writer thread has stopped
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::TimedOut.into())
+            }
+        }
+        impl Write for Broken {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        impl Transport for Broken {
+            fn name(&self) -> String {
+                "broken".to_string()
+            }
+            fn timeout(&self) -> Duration {
+                Duration::ZERO
+            }
+            fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+                Ok(())
+            }
+            fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+                Ok(Box::new(Broken))
+            }
+        }
+
+        let (_panel_end, bridge_end) = MemoryTransport::pair("finale");
+        let (dx_p2, mut game_p2) = MemoryTransport::pair("p2");
+        let mut bridge = RingEdge2::with_transport(
+            Box::new(bridge_end),
+            Some(Box::new(Broken)),
+            Some(Box::new(dx_p2)),
+            ZoneMapping::default(),
+        )
+        .unwrap();
+        bridge.deluxe_active = [true, true];
+        let frame = [b'(', 0, 0, 0, 0, b')', b'(', 0, 0, 0, 0, b')', 0, 0];
+        bridge.feed(&frame, Instant::now());
+        assert!(bridge.deluxe_ports[0].is_none());
+        bridge.feed(&frame, Instant::now());
+
+        let mut frames = [0u8; 18];
+        game_p2.read_exact(&mut frames).unwrap();
+        assert_eq!(frames[..9], DEFAULT_DELUXE_WRITE_BUFFER);
+    }
+
+    #[test]
+    pub fn sensitivity_is_only_acknowledged_by_default() {
+        let (panel_end, bridge_end) = MemoryTransport::pair("finale");
+        let (dx_p1, mut game_p1) = MemoryTransport::pair("p1");
+        let mut bridge = RingEdge2::with_transport(
+            Box::new(bridge_end),
+            Some(Box::new(dx_p1)),
+            None,
+            ZoneMapping::default(),
+        )
+        .unwrap();
+        bridge
+            .parse_command_from_alls(command(0, TouchMasterCommand::Sens(b'L', b'A', 20)))
+            .unwrap();
+
+        let mut ack = [0u8; 6];
+        game_p1.read_exact(&mut ack).unwrap();
+        assert_eq!(&ack, b"(LAk\x14)");
+        assert_eq!(panel_end.available(), 0);
+    }
+
+    #[test]
+    pub fn reset_restarts_panel_and_keeps_other_side() {
+        let (mut bridge, mut sim) = bridge_with_panel(TouchMode::Both);
+        for (player_num, side, value) in [(0, b'L', 20), (1, b'R', 30)] {
+            bridge
+                .parse_command_from_alls(command(player_num, TouchMasterCommand::Stat))
+                .unwrap();
+            bridge
+                .parse_command_from_alls(command(
+                    player_num,
+                    TouchMasterCommand::Sens(side, b'A', value),
+                ))
+                .unwrap();
+        }
+
+        bridge
+            .parse_command_from_alls(command(0, TouchMasterCommand::Reset))
+            .unwrap();
+        settle(&mut sim);
+        assert!(!bridge.deluxe_active[0]);
+        assert!(bridge.deluxe_active[1]);
+        // Panel was reset, P2 got its threshold back and streaming goes on
+        assert_eq!(sim.sensitivity, vec![(b'R', b'A', 30)]);
+        assert!(sim.is_streaming());
+
+        // Game repeats the threshold it set before, it has to reach the panel again
+        bridge
+            .parse_command_from_alls(command(0, TouchMasterCommand::Sens(b'L', b'A', 20)))
+            .unwrap();
+        settle(&mut sim);
+        assert_eq!(sim.sensitivity, vec![(b'R', b'A', 30), (b'L', b'A', 20)]);
+    }
+
+    #[test]
+    pub fn reset_without_other_player_waits_for_stat() {
+        let (mut bridge, mut sim) = bridge_with_panel(TouchMode::Both);
+        bridge
+            .parse_command_from_alls(command(0, TouchMasterCommand::Stat))
+            .unwrap();
+        bridge
+            .parse_command_from_alls(command(0, TouchMasterCommand::Reset))
+            .unwrap();
+        settle(&mut sim);
+        // P2 never started, nobody needs the panel until P1 is done resetting
+        assert!(!sim.is_streaming());
+
+        bridge
+            .parse_command_from_alls(command(0, TouchMasterCommand::Stat))
+            .unwrap();
+        settle(&mut sim);
+        assert!(sim.is_streaming());
+    }
+
+    #[test]
+    pub fn profile_is_applied_on_startup_and_overrides_game() {
+        let (mut bridge, mut sim) = bridge_with_panel(TouchMode::Both);
+        let mut config = SensitivityConfig {
+            file: String::new(),
+            ..SensitivityConfig::default()
+        };
+        // C1 is driven by Finale C only
+        config.overrides.p1.insert("C1".to_string(), 15);
+        bridge
+            .set_sensitivity_profile(SensitivityProfile::load(&config).unwrap())
+            .unwrap();
+        settle(&mut sim);
+        assert_eq!(sim.sensitivity, vec![(b'L', b'Q', 15)]);
+
+        // Game asks for 30, gets it acknowledged, the panel keeps the configured 15
+        bridge
+            .parse_command_from_alls(command(0, TouchMasterCommand::Sens(b'L', b'A' + 16, 30)))
+            .unwrap();
+        settle(&mut sim);
+        assert_eq!(sim.sensitivity, vec![(b'L', b'Q', 15)]);
+    }
+
+    #[test]
+    pub fn mirrored_player_reset_leaves_panel_alone() {
+        let (mut bridge, mut sim) = bridge_with_panel(TouchMode::MirrorP1);
+        bridge
+            .parse_command_from_alls(command(0, TouchMasterCommand::Sens(b'L', b'A', 20)))
+            .unwrap();
+        bridge
+            .parse_command_from_alls(command(1, TouchMasterCommand::Reset))
+            .unwrap();
+        settle(&mut sim);
+        assert_eq!(sim.sensitivity, vec![(b'L', b'A', 20)]);
+        assert!(sim.is_streaming());
+    }
+}
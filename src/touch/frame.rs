@@ -0,0 +1,181 @@
+// Framing for the RingEdge 2 touch stream.
+//
+// The panel continuously sends 14 bytes long frames:
+//   ( P1 P1 P1 P1 ) ( P2 P2 P2 P2 ) X X
+// Each player half is wrapped in parentheses, followed by 2 bytes we don't use. If a byte gets
+// lost the stream has to be realigned, otherwise every following frame is garbage and turns
+// into phantom touches on Deluxe side.
+
+use log::warn;
+
+pub const FRAME_LEN: usize = 14;
+
+/// Position of the sensor data of each player in a frame
+pub const PLAYER_DATA: [std::ops::Range<usize>; 2] = [1..5, 7..11];
+
+const OPEN_POS: [usize; 2] = [0, 6];
+const CLOSE_POS: [usize; 2] = [5, 11];
+
+/// Builds a frame the way the panel sends it, from both players' sensor bytes
+pub fn build_frame(players: &[[u8; 4]; 2]) -> [u8; FRAME_LEN] {
+    let mut frame = [0u8; FRAME_LEN];
+    for (player, data) in PLAYER_DATA.iter().enumerate() {
+        frame[OPEN_POS[player]] = b'(';
+        frame[data.clone()].copy_from_slice(&players[player]);
+        frame[CLOSE_POS[player]] = b')';
+    }
+    frame
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Complete and valid frames
+    pub frames: u64,
+    /// How many times the stream lost alignment and had to be resynchronised
+    pub resyncs: u64,
+    /// Bytes thrown away while looking for a frame start
+    pub discarded_bytes: u64,
+}
+
+/// Byte-by-byte frame parser. Hunts for `(`, checks both player halves and only returns
+/// complete frames, malformed ones are dropped
+pub struct FrameParser {
+    frame: [u8; FRAME_LEN],
+    pos: usize,
+    in_sync: bool,
+    stats: FrameStats,
+}
+
+impl FrameParser {
+    pub fn new() -> Self {
+        Self {
+            frame: [0; FRAME_LEN],
+            pos: 0,
+            in_sync: true,
+            stats: FrameStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    /// Feeds one byte, returns a frame once it's complete
+    pub fn push(&mut self, byte: u8) -> Option<[u8; FRAME_LEN]> {
+        let valid = if OPEN_POS.contains(&self.pos) {
+            byte == b'('
+        } else if CLOSE_POS.contains(&self.pos) {
+            byte == b')'
+        } else {
+            true
+        };
+
+        if !valid {
+            self.lose_sync(byte);
+            return None;
+        }
+
+        self.frame[self.pos] = byte;
+        self.pos += 1;
+
+        if self.pos < FRAME_LEN {
+            return None;
+        }
+
+        self.pos = 0;
+        self.in_sync = true;
+        self.stats.frames += 1;
+        Some(self.frame)
+    }
+
+    /// Feeds a chunk of bytes, calling `on_frame` for every complete frame in it
+    pub fn push_slice(&mut self, bytes: &[u8], mut on_frame: impl FnMut(&[u8; FRAME_LEN])) {
+        for &b in bytes {
+            if let Some(frame) = self.push(b) {
+                on_frame(&frame);
+            }
+        }
+    }
+
+    fn lose_sync(&mut self, byte: u8) {
+        self.stats.discarded_bytes += self.pos as u64;
+
+        if self.in_sync {
+            self.in_sync = false;
+            self.stats.resyncs += 1;
+            warn!(
+                "Touch: Finale stream out of sync at byte {}, resynchronising (total: {})",
+                self.pos, self.stats.resyncs
+            );
+        }
+
+        // Unexpected byte may be the start of the next frame
+        if byte == b'(' {
+            self.frame[0] = byte;
+            self.pos = 1;
+        } else {
+            self.stats.discarded_bytes += 1;
+            self.pos = 0;
+        }
+    }
+}
+
+impl Default for FrameParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::touch::frame::{build_frame, FrameParser, FRAME_LEN};
+
+    const FRAME: [u8; FRAME_LEN] = [b'(', 1, 2, 3, 4, b')', b'(', 5, 6, 7, 8, b')', 0, 0];
+
+    fn parse(bytes: &[u8]) -> (Vec<[u8; FRAME_LEN]>, FrameParser) {
+        let mut parser = FrameParser::new();
+        let mut frames = Vec::new();
+        parser.push_slice(bytes, |f| frames.push(*f));
+        (frames, parser)
+    }
+
+    #[test]
+    pub fn parses_aligned_stream() {
+        let (frames, parser) = parse(&[FRAME, FRAME].concat());
+        assert_eq!(frames, vec![FRAME, FRAME]);
+        assert_eq!(parser.stats().resyncs, 0);
+        assert_eq!(parser.stats().discarded_bytes, 0);
+    }
+
+    #[test]
+    pub fn builds_frame() {
+        assert_eq!(build_frame(&[[1, 2, 3, 4], [5, 6, 7, 8]]), FRAME);
+    }
+
+    #[test]
+    pub fn skips_leading_garbage() {
+        let (frames, parser) = parse(&[&[0x00, 0x13, b')'], &FRAME[..]].concat());
+        assert_eq!(frames, vec![FRAME]);
+        assert_eq!(parser.stats().resyncs, 1);
+        assert_eq!(parser.stats().discarded_bytes, 3);
+    }
+
+    #[test]
+    pub fn drops_frame_with_missing_byte() {
+        let mut broken = FRAME.to_vec();
+        broken.remove(3);
+        let (frames, parser) = parse(&[&broken[..], &FRAME[..], &FRAME[..]].concat());
+        assert_eq!(frames, vec![FRAME, FRAME]);
+        assert_eq!(parser.stats().resyncs, 1);
+    }
+
+    #[test]
+    pub fn drops_frame_with_bad_second_half() {
+        let mut broken = FRAME;
+        broken[11] = 0x00;
+        let (frames, parser) = parse(&[broken, FRAME].concat());
+        assert_eq!(frames, vec![FRAME]);
+        assert_eq!(parser.stats().frames, 1);
+        assert_eq!(parser.stats().resyncs, 1);
+    }
+}
@@ -0,0 +1,220 @@
+// Touch zones as key presses.
+//
+// For other games or menus on the cabinet, the Finale ring can drive the keyboard: every zone
+// in `[touch.keys.p1]`/`[touch.keys.p2]` holds its key down while it is pressed. Zones are
+// either Finale sensors (A1..A8, B1..B8, C) or Deluxe zones after [touch.mapping]
+// (A1..E8, C1, C2), picked by `zones`. `output` in [touch] decides if keys are sent alongside
+// the Deluxe serial output or instead of it (Deluxe ports aren't opened then).
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::keyboard::{KeyCode, Keyboard};
+use crate::touch::mapping::{deluxe_zone_position, finale_zone_position, ZoneMapping};
+
+/// Where translated touches go
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TouchOutput {
+    /// Deluxe touch serial ports only
+    #[default]
+    Deluxe,
+    /// Key presses only, Deluxe touch ports are not opened
+    Keyboard,
+    Both,
+}
+
+impl TouchOutput {
+    pub fn to_deluxe(&self) -> bool {
+        matches!(self, TouchOutput::Deluxe | TouchOutput::Both)
+    }
+
+    pub fn to_keyboard(&self) -> bool {
+        matches!(self, TouchOutput::Keyboard | TouchOutput::Both)
+    }
+}
+
+/// Which zone names `[touch.keys]` uses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyZones {
+    #[default]
+    Finale,
+    Deluxe,
+}
+
+/// `[touch.keys]` config section
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TouchKeysConfig {
+    pub zones: KeyZones,
+    /// Zone name to key code, for each player
+    pub p1: BTreeMap<String, KeyCode>,
+    pub p2: BTreeMap<String, KeyCode>,
+}
+
+struct Binding {
+    /// Byte and bit mask of the zone in Finale sensor bytes or in a Deluxe frame
+    byte: usize,
+    mask: u8,
+    key: KeyCode,
+}
+
+/// Presses and releases keys bound to touch zones
+pub struct TouchKeys {
+    zones: KeyZones,
+    bindings: [Vec<Binding>; 2],
+    keyboard: Keyboard,
+}
+
+impl TouchKeys {
+    pub fn from_config(config: &TouchKeysConfig) -> io::Result<Self> {
+        let bind = |keys: &BTreeMap<String, KeyCode>| -> io::Result<Vec<Binding>> {
+            keys.iter()
+                .map(|(zone, &key)| {
+                    let (byte, mask) = match config.zones {
+                        KeyZones::Finale => {
+                            finale_zone_position(zone).map(|(b, bit)| (b, 1 << bit))
+                        }
+                        KeyZones::Deluxe => deluxe_zone_position(zone),
+                    }
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "Invalid [touch.keys]: unknown {:?} touch zone \"{}\"",
+                                config.zones, zone
+                            ),
+                        )
+                    })?;
+                    Ok(Binding { byte, mask, key })
+                })
+                .collect()
+        };
+
+        Ok(Self {
+            zones: config.zones,
+            bindings: [bind(&config.p1)?, bind(&config.p2)?],
+            keyboard: Keyboard::new(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bindings.iter().all(Vec::is_empty)
+    }
+
+    /// Keys that should be held for the players' sensor bytes (None for a player that isn't used).
+    /// A key bound to several zones is held while any of them is pressed
+    pub fn pressed_keys(
+        &self,
+        mapping: &ZoneMapping,
+        players: [Option<&[u8; 4]>; 2],
+    ) -> BTreeSet<KeyCode> {
+        let mut pressed = BTreeSet::new();
+        for (bindings, sensors) in self.bindings.iter().zip(players) {
+            let sensors = match sensors {
+                Some(sensors) => sensors,
+                None => continue,
+            };
+            let deluxe;
+            let data: &[u8] = match self.zones {
+                KeyZones::Finale => sensors,
+                KeyZones::Deluxe => {
+                    deluxe = mapping.translate(sensors);
+                    &deluxe
+                }
+            };
+            for binding in bindings {
+                if data[binding.byte] & binding.mask != 0 {
+                    pressed.insert(binding.key);
+                }
+            }
+        }
+        pressed
+    }
+
+    /// Sends key downs and ups for the change since the last frame
+    pub fn update(&mut self, mapping: &ZoneMapping, players: [Option<&[u8; 4]>; 2]) {
+        let pressed = self.pressed_keys(mapping, players);
+        for binding in self.bindings.iter().flatten() {
+            if pressed.contains(&binding.key) {
+                self.keyboard.key_down(&binding.key);
+            } else {
+                self.keyboard.key_up(&binding.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::keyboard::KeyCode;
+    use crate::touch::keys::{KeyZones, TouchKeys, TouchKeysConfig};
+    use crate::touch::mapping::ZoneMapping;
+
+    const A1: [u8; 4] = [0b1, 0, 0, 0];
+    const B1: [u8; 4] = [0b10, 0, 0, 0];
+    const NONE: [u8; 4] = [0; 4];
+
+    fn keys(zones: KeyZones, p1: &[(&str, KeyCode)], p2: &[(&str, KeyCode)]) -> TouchKeys {
+        let map = |keys: &[(&str, KeyCode)]| -> BTreeMap<String, KeyCode> {
+            keys.iter().map(|&(z, k)| (z.to_string(), k)).collect()
+        };
+        TouchKeys::from_config(&TouchKeysConfig {
+            zones,
+            p1: map(p1),
+            p2: map(p2),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    pub fn finale_zones_per_player() {
+        let keys = keys(
+            KeyZones::Finale,
+            &[("A1", 0x41), ("C", 0x43)],
+            &[("A1", 0x5A)],
+        );
+        let mapping = ZoneMapping::default();
+        assert_eq!(
+            keys.pressed_keys(&mapping, [Some(&A1), Some(&A1)]),
+            [0x41, 0x5A].into()
+        );
+        assert_eq!(
+            keys.pressed_keys(&mapping, [Some(&B1), Some(&NONE)]),
+            [].into()
+        );
+        // Player that isn't used doesn't press anything
+        assert_eq!(keys.pressed_keys(&mapping, [None, Some(&NONE)]), [].into());
+    }
+
+    #[test]
+    pub fn deluxe_zones_go_through_mapping() {
+        // B1 -> B1, E1, E2
+        let keys = keys(KeyZones::Deluxe, &[("E2", 0x45), ("D1", 0x44)], &[]);
+        assert_eq!(
+            keys.pressed_keys(&ZoneMapping::default(), [Some(&B1), None]),
+            [0x45].into()
+        );
+    }
+
+    #[test]
+    pub fn shared_key_and_unknown_zone() {
+        let keys = keys(KeyZones::Finale, &[("A1", 0x20), ("B1", 0x20)], &[]);
+        assert_eq!(
+            keys.pressed_keys(&ZoneMapping::default(), [Some(&B1), None]),
+            [0x20].into()
+        );
+
+        let config = TouchKeysConfig {
+            zones: KeyZones::Finale,
+            p1: [("D1".to_string(), 0x20)].into(),
+            ..TouchKeysConfig::default()
+        };
+        assert!(TouchKeys::from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,270 @@
+// Latency of the touch path and a benchmark for it.
+//
+// For every Finale frame the bridge can note two delays: queue delay, from the bytes coming off
+// the panel port to the frame being processed (parsing, waiting for the bridge lock), and
+// processing, from there until the translated frame is written to every Deluxe port. Samples
+// are collected per report window and logged as p50/p99/max (`latency_report_secs` in [touch]).
+//
+// `bench-touch` pushes synthetic frames through the same RingEdge2 translation with in-memory
+// ports, so regressions in the translation path show up without a cabinet.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::config::{BenchTouchArgs, Touch};
+use crate::touch::debounce::Debouncer;
+use crate::touch::deluxe::{MessageCmd, TouchMasterCommand};
+use crate::touch::finale::RingEdge2;
+use crate::touch::frame::build_frame;
+use crate::touch::layout::Layout;
+use crate::touch::mapping::finale_sensors;
+use crate::touch::stuck::StuckDetector;
+use crate::touch::zone_mapping;
+use crate::transport::memory::MemoryTransport;
+use crate::transport::Transport;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub frames: usize,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl std::fmt::Display for LatencySummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "p50 {:.1?} p99 {:.1?} max {:.1?}",
+            self.p50, self.p99, self.max
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct LatencySamples {
+    samples: Vec<Duration>,
+}
+
+impl LatencySamples {
+    pub fn push(&mut self, sample: Duration) {
+        self.samples.push(sample);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Nearest-rank percentiles of the samples, None if there are none
+    pub fn summary(&self) -> Option<LatencySummary> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+        let percentile = |p: usize| sorted[(sorted.len() * p).div_ceil(100) - 1];
+        Some(LatencySummary {
+            frames: sorted.len(),
+            p50: percentile(50),
+            p99: percentile(99),
+            max: sorted[sorted.len() - 1],
+        })
+    }
+}
+
+/// Collects queue delay and processing time of every frame, see module docs
+pub struct LatencyMonitor {
+    pub queue: LatencySamples,
+    pub processing: LatencySamples,
+    /// None only collects, reporting is left to the owner
+    report_every: Option<Duration>,
+    window_start: Instant,
+}
+
+impl LatencyMonitor {
+    pub fn new(report_every: Option<Duration>) -> Self {
+        Self {
+            queue: LatencySamples::default(),
+            processing: LatencySamples::default(),
+            report_every,
+            window_start: Instant::now(),
+        }
+    }
+
+    /// None if `latency_report_secs` is 0, the bridge doesn't measure anything then
+    pub fn from_config(touch: &Touch) -> Option<Self> {
+        (touch.latency_report_secs > 0)
+            .then(|| Self::new(Some(Duration::from_secs(touch.latency_report_secs))))
+    }
+
+    /// Notes a frame whose bytes were read at `received`, processing of which ran from
+    /// `started` to `finished`. Logs and starts a new window once the report interval is over
+    pub fn record(&mut self, received: Instant, started: Instant, finished: Instant) {
+        self.queue.push(started.saturating_duration_since(received));
+        self.processing
+            .push(finished.saturating_duration_since(started));
+
+        if let Some(report_every) = self.report_every {
+            if finished.duration_since(self.window_start) >= report_every {
+                self.report(report_every);
+                self.window_start = finished;
+            }
+        }
+    }
+
+    fn report(&mut self, window: Duration) {
+        if let (Some(queue), Some(processing)) = (self.queue.summary(), self.processing.summary()) {
+            info!(
+                "Touch latency over last {} s ({} frames): queue {}, processing {}",
+                window.as_secs(),
+                processing.frames,
+                queue,
+                processing
+            );
+        }
+        self.queue.clear();
+        self.processing.clear();
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BenchResult {
+    pub elapsed: Duration,
+    pub processing: LatencySummary,
+}
+
+/// Pushes `frames` synthetic frames through RingEdge2 set up from `touch` config, with both
+/// players active on in-memory Deluxe ports
+pub fn bench(touch: &Touch, frames: u64) -> io::Result<BenchResult> {
+    let (_panel, bridge_end) = MemoryTransport::pair("finale");
+    let (dx_p1, game_p1) = MemoryTransport::pair("p1");
+    let (dx_p2, game_p2) = MemoryTransport::pair("p2");
+
+    let mut bridge = RingEdge2::with_transport(
+        Box::new(bridge_end),
+        Some(Box::new(dx_p1)),
+        Some(Box::new(dx_p2)),
+        zone_mapping(touch)?,
+    )?;
+    bridge.set_mode(touch.mode);
+    bridge.set_layout(Layout::from_config(touch)?);
+    bridge.set_debouncer(Debouncer::from_config(&touch.debounce)?);
+    bridge.set_stuck_detector(StuckDetector::from_config(touch));
+    bridge.set_latency_monitor(LatencyMonitor::new(None));
+    for player_num in 0..2 {
+        bridge.parse_command_from_alls(MessageCmd {
+            player_num,
+            cmd: TouchMasterCommand::Stat,
+        })?;
+    }
+
+    let sensors: Vec<_> = finale_sensors().collect();
+    let start = Instant::now();
+    for i in 0..frames as usize {
+        // Every sensor in turn, held for 4 frames, other side is a few sensors ahead.
+        // Every 8th frame nothing is pressed
+        let mut players = [[0u8; 4]; 2];
+        if i % 8 != 7 {
+            for (player, data) in players.iter_mut().enumerate() {
+                let (byte, bit, _) = sensors[(i / 4 + player * 5) % sensors.len()];
+                data[byte] |= 1 << bit;
+            }
+        }
+        bridge.feed(&build_frame(&players), Instant::now());
+
+        if i % 1024 == 0 {
+            game_p1.clear()?;
+            game_p2.clear()?;
+        }
+    }
+    let elapsed = start.elapsed();
+
+    let processing = bridge
+        .latency_monitor()
+        .and_then(|latency| latency.processing.summary())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No frames to measure"))?;
+    Ok(BenchResult {
+        elapsed,
+        processing,
+    })
+}
+
+pub fn run_bench(touch: &Touch, args: &BenchTouchArgs) -> io::Result<()> {
+    let result = bench(touch, args.frames)?;
+    info!(
+        "Translated {} frames in {:.1?} ({:.0} frames/s), per frame: {}",
+        result.processing.frames,
+        result.elapsed,
+        result.processing.frames as f64 / result.elapsed.as_secs_f64(),
+        result.processing
+    );
+
+    if let Some(max_p99_us) = args.max_p99_us {
+        if result.processing.p99 > Duration::from_micros(max_p99_us) {
+            return Err(io::Error::other(format!(
+                "p99 {:.1?} is over the limit of {} us",
+                result.processing.p99, max_p99_us
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::config::Touch;
+    use crate::touch::latency::{bench, LatencyMonitor, LatencySamples};
+
+    #[test]
+    pub fn percentiles() {
+        let mut samples = LatencySamples::default();
+        assert!(samples.summary().is_none());
+        for us in (1..=100).rev() {
+            samples.push(Duration::from_micros(us));
+        }
+        let summary = samples.summary().unwrap();
+        assert_eq!(summary.frames, 100);
+        assert_eq!(summary.p50, Duration::from_micros(50));
+        assert_eq!(summary.p99, Duration::from_micros(99));
+        assert_eq!(summary.max, Duration::from_micros(100));
+    }
+
+    #[test]
+    pub fn monitor_splits_queue_and_processing_and_resets_window() {
+        let mut monitor = LatencyMonitor::new(Some(Duration::from_secs(1)));
+        let received = Instant::now();
+        let started = received + Duration::from_micros(30);
+        monitor.record(received, started, started + Duration::from_micros(10));
+        assert_eq!(
+            monitor.queue.summary().unwrap().max,
+            Duration::from_micros(30)
+        );
+        assert_eq!(
+            monitor.processing.summary().unwrap().max,
+            Duration::from_micros(10)
+        );
+
+        let later = received + Duration::from_secs(2);
+        monitor.record(later, later, later);
+        assert!(monitor.processing.is_empty());
+    }
+
+    #[test]
+    pub fn bench_runs_every_frame_through_translation() {
+        let result = bench(&Touch::default(), 500).unwrap();
+        assert_eq!(result.processing.frames, 500);
+        assert!(result.processing.p50 <= result.processing.max);
+    }
+}
@@ -0,0 +1,194 @@
+// How the physical Finale panel maps onto Deluxe players.
+//
+// TouchMode picks which Deluxe players get touch and from which Finale side, e.g. a cabinet
+// with only one working side can drive both players from it (mirror). Layout fixes panels
+// installed rotated or flipped: `rotation` turns touches clockwise in 45 degree steps
+// (A1 -> A2 with rotation = 1), `flip` then mirrors them left to right (A1 <-> A8, B2 <-> B7).
+// C is in the middle and stays where it is. Sensitivity goes the other way: a threshold for a
+// sensor after rotation is sent to the panel sensor that ends up there (see source_sensor).
+
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Touch;
+use crate::touch::mapping::{finale_sensors, finale_zone_position};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TouchMode {
+    /// Each Finale side drives its own Deluxe player
+    #[default]
+    Both,
+    /// Only Deluxe P1 is opened, driven by Finale P1 side
+    P1,
+    /// Only Deluxe P2 is opened, driven by Finale P2 side
+    P2,
+    /// Finale P1 side drives both Deluxe players
+    MirrorP1,
+    /// Finale P2 side drives both Deluxe players
+    MirrorP2,
+}
+
+impl TouchMode {
+    /// Whether Deluxe player's touch port is used at all
+    pub fn uses_deluxe(&self, player_num: usize) -> bool {
+        match self {
+            TouchMode::P1 => player_num == 0,
+            TouchMode::P2 => player_num == 1,
+            _ => true,
+        }
+    }
+
+    /// Finale side whose touches go to Deluxe player
+    pub fn finale_side(&self, player_num: usize) -> usize {
+        match self {
+            TouchMode::MirrorP1 => 0,
+            TouchMode::MirrorP2 => 1,
+            _ => player_num,
+        }
+    }
+
+    /// Whether sensitivity set by Deluxe player is forwarded to the Finale panel.
+    /// When mirroring, only the player on the source side calibrates it, otherwise both
+    /// players would fight over the same sensors
+    pub fn forwards_sensitivity(&self, player_num: usize) -> bool {
+        self.uses_deluxe(player_num) && self.finale_side(player_num) == player_num
+    }
+}
+
+/// Rotation and flip of Finale sensors
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    /// Per Finale sensor in touch frame order, (byte, bit) it ends up at
+    targets: Vec<(usize, usize)>,
+}
+
+impl Layout {
+    pub fn new(rotation: u8, flip: bool) -> io::Result<Self> {
+        if rotation >= 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid [touch]: rotation has to be 0..7 (45 degree steps), got {rotation}"
+                ),
+            ));
+        }
+
+        let targets = finale_sensors()
+            .map(|(byte, bit, name)| {
+                let ring = &name[..1];
+                let num: u8 = match name[1..].parse() {
+                    Ok(num) => num,
+                    // C
+                    Err(_) => return (byte, bit),
+                };
+                let mut num = (num - 1 + rotation) % 8 + 1;
+                if flip {
+                    num = 9 - num;
+                }
+                finale_zone_position(&format!("{ring}{num}")).unwrap()
+            })
+            .collect();
+        Ok(Self { targets })
+    }
+
+    pub fn from_config(touch: &Touch) -> io::Result<Self> {
+        Self::new(touch.rotation, touch.flip)
+    }
+
+    /// Takes one player's 4 sensor bytes as the panel reports them and returns them
+    /// rotated and flipped
+    pub fn apply(&self, sensors: &[u8]) -> [u8; 4] {
+        let mut output = [0u8; 4];
+        for ((byte, bit, _), &(to_byte, to_bit)) in finale_sensors().zip(&self.targets) {
+            if sensors.get(byte).is_some_and(|b| b & (1 << bit) != 0) {
+                output[to_byte] |= 1 << to_bit;
+            }
+        }
+        output
+    }
+
+    /// Index (touch frame order) of the panel sensor that `apply` moves to `sensor`
+    pub fn source_sensor(&self, sensor: usize) -> usize {
+        finale_sensors()
+            .nth(sensor)
+            .and_then(|(byte, bit, _)| self.targets.iter().position(|&t| t == (byte, bit)))
+            .unwrap_or(sensor)
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self::new(0, false).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::touch::layout::{Layout, TouchMode};
+    use crate::touch::mapping::{finale_sensors, finale_zone_position};
+
+    fn sensors(zones: &[&str]) -> [u8; 4] {
+        let mut data = [0u8; 4];
+        for zone in zones {
+            let (byte, bit) = finale_zone_position(zone).unwrap();
+            data[byte] |= 1 << bit;
+        }
+        data
+    }
+
+    #[test]
+    pub fn rotation_and_flip() {
+        #[rustfmt::skip]
+        let cases: &[(u8, bool, &[&str], &[&str])] = &[
+            // rotation, flip, pressed on the panel, what comes out
+            (0, false, &["A1", "B3", "C"], &["A1", "B3", "C"]),
+            (1, false, &["A1", "B8"], &["A2", "B1"]),
+            (4, false, &["A1", "B6", "C"], &["A5", "B2", "C"]),
+            (0, true, &["A1", "A4", "B2"], &["A8", "A5", "B7"]),
+            (2, true, &["A1"], &["A6"]),
+        ];
+
+        for (rotation, flip, pressed, expected) in cases {
+            let layout = Layout::new(*rotation, *flip).unwrap();
+            assert_eq!(
+                layout.apply(&sensors(pressed)),
+                sensors(expected),
+                "rotation = {rotation}, flip = {flip}, pressed {pressed:?}"
+            );
+        }
+        assert!(Layout::new(8, false).is_err());
+    }
+
+    #[test]
+    pub fn source_sensor_undoes_apply() {
+        let index = |zone| {
+            finale_sensors()
+                .position(|(_, _, name)| name == zone)
+                .unwrap()
+        };
+        let layout = Layout::new(1, false).unwrap();
+        // A8 on the panel comes out as A1
+        assert_eq!(layout.source_sensor(index("A1")), index("A8"));
+        assert_eq!(layout.source_sensor(index("C")), index("C"));
+        let layout = Layout::new(2, true).unwrap();
+        for (i, (byte, bit, _)) in finale_sensors().enumerate() {
+            let mut sensors = [0u8; 4];
+            let (src_byte, src_bit, _) = finale_sensors().nth(layout.source_sensor(i)).unwrap();
+            sensors[src_byte] |= 1 << src_bit;
+            assert_eq!(layout.apply(&sensors)[byte], 1 << bit);
+        }
+    }
+
+    #[test]
+    pub fn modes_route_players() {
+        assert!(TouchMode::P1.uses_deluxe(0));
+        assert!(!TouchMode::P1.uses_deluxe(1));
+        assert!(!TouchMode::P2.forwards_sensitivity(0));
+        assert_eq!(TouchMode::Both.finale_side(1), 1);
+        assert_eq!(TouchMode::MirrorP2.finale_side(0), 1);
+        assert!(TouchMode::MirrorP1.forwards_sensitivity(0));
+        assert!(!TouchMode::MirrorP1.forwards_sensitivity(1));
+    }
+}
@@ -0,0 +1,458 @@
+// Finale to Deluxe touch zone mapping.
+//
+// Finale panel has 17 sensors (A1..A8, B1..B8, C), Deluxe has 34 zones (A1..A8, B1..B8, C1, C2,
+// D1..D8, E1..E8). Each Finale sensor activates an arbitrary list of Deluxe zones, by default
+// the zone itself plus the new D/E zones next to it.
+//
+// That fan-out makes a single A1 press also hit D1 and D2, so D and E groups can instead be
+// synthesised (ZoneSynthesis::Adjacent): D/E zone lies on the border between two A/B sectors
+// and is only lit when both of them are pressed together, e.g. A1 + A2 -> D2, B8 + B1 -> E1.
+
+use std::collections::BTreeMap;
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::helper_funcs::bit_read;
+
+/// Deluxe touch frame with nothing pressed
+pub static DEFAULT_DELUXE_WRITE_BUFFER: [u8; 9] = [b'(', 0, 0, 0, 0, 0, 0, 0, b')'];
+
+/// Finale sensor names by their position in a player's half of the touch frame,
+/// `FINALE_ZONES[byte][bit]`
+pub static FINALE_ZONES: [[Option<&str>; 5]; 4] = [
+    [Some("A1"), Some("B1"), Some("A2"), Some("B2"), None],
+    [Some("A3"), Some("B3"), Some("A4"), Some("B4"), None],
+    [Some("A5"), Some("B5"), Some("A6"), Some("B6"), None],
+    [Some("A7"), Some("B7"), Some("A8"), Some("B8"), Some("C")],
+];
+
+/// Mapping for Deluxe touch areas
+/// (usize, u8) = (Index of DELUXE_WRITE_BUFFER, Bit Position)
+pub static DELUXE_ZONES: [(&str, (usize, u8)); 34] = [
+    ("A1", (1, 1)),
+    ("A2", (1, 2)),
+    ("A3", (1, 4)),
+    ("A4", (1, 8)),
+    ("A5", (1, 16)),
+    ("A6", (2, 1)),
+    ("A7", (2, 2)),
+    ("A8", (2, 4)),
+    ("B1", (2, 8)),
+    ("B2", (2, 16)),
+    ("B3", (3, 1)),
+    ("B4", (3, 2)),
+    ("B5", (3, 4)),
+    ("B6", (3, 8)),
+    ("B7", (3, 16)),
+    ("B8", (4, 1)),
+    ("C1", (4, 2)),
+    ("C2", (4, 4)),
+    ("D1", (4, 8)),
+    ("D2", (4, 16)),
+    ("D3", (5, 1)),
+    ("D4", (5, 2)),
+    ("D5", (5, 4)),
+    ("D6", (5, 8)),
+    ("D7", (5, 16)),
+    ("D8", (6, 1)),
+    ("E1", (6, 2)),
+    ("E2", (6, 4)),
+    ("E3", (6, 8)),
+    ("E4", (6, 16)),
+    ("E5", (7, 1)),
+    ("E6", (7, 2)),
+    ("E7", (7, 4)),
+    ("E8", (7, 8)),
+];
+
+static DEFAULT_MAPPING: [(&str, &[&str]); 17] = [
+    ("A1", &["A1", "D1", "D2"]),
+    ("B1", &["B1", "E1", "E2"]),
+    ("A2", &["A2", "D2", "D3"]),
+    ("B2", &["B2", "E2", "E3"]),
+    ("A3", &["A3", "D3", "D4"]),
+    ("B3", &["B3", "E3", "E4"]),
+    ("A4", &["A4", "D4", "D5"]),
+    ("B4", &["B4", "E4", "E5"]),
+    ("A5", &["A5", "D5", "D6"]),
+    ("B5", &["B5", "E5", "E6"]),
+    ("A6", &["A6", "D6", "D7"]),
+    ("B6", &["B6", "E6", "E7"]),
+    ("A7", &["A7", "D7", "D8"]),
+    ("B7", &["B7", "E7", "E8"]),
+    ("A8", &["A8", "D8", "D1"]),
+    ("B8", &["B8", "E8", "E1"]),
+    ("C", &["C1", "C2"]),
+];
+
+/// Built-in mapping in the form used by config
+pub fn default_mapping() -> BTreeMap<String, Vec<String>> {
+    DEFAULT_MAPPING
+        .iter()
+        .map(|(finale, deluxe)| {
+            (
+                finale.to_string(),
+                deluxe.iter().map(|z| z.to_string()).collect(),
+            )
+        })
+        .collect()
+}
+
+/// All Finale sensors as (byte, bit, name), in the order they appear in a touch frame
+pub fn finale_sensors() -> impl Iterator<Item = (usize, usize, &'static str)> {
+    FINALE_ZONES.iter().enumerate().flat_map(|(byte, bits)| {
+        bits.iter()
+            .enumerate()
+            .filter_map(move |(bit, zone)| zone.map(|name| (byte, bit, name)))
+    })
+}
+
+/// Returns (byte, bit) position of a Finale sensor in a player's half of the touch frame
+pub fn finale_zone_position(name: &str) -> Option<(usize, usize)> {
+    FINALE_ZONES.iter().enumerate().find_map(|(byte, bits)| {
+        bits.iter()
+            .position(|&zone| zone == Some(name))
+            .map(|bit| (byte, bit))
+    })
+}
+
+/// Returns (index, bit mask) of a Deluxe zone in the Deluxe touch frame
+pub fn deluxe_zone_position(name: &str) -> Option<(usize, u8)> {
+    DELUXE_ZONES
+        .iter()
+        .find(|(zone, _)| *zone == name)
+        .map(|(_, pos)| *pos)
+}
+
+/// Names of Deluxe zones pressed in a 9 bytes long Deluxe touch frame, in A1..E8 order
+pub fn deluxe_zones_in(frame: &[u8]) -> Vec<&'static str> {
+    DELUXE_ZONES
+        .iter()
+        .filter(|(_, (index, bit_mask))| frame.get(*index).is_some_and(|b| b & bit_mask != 0))
+        .map(|(zone, _)| *zone)
+        .collect()
+}
+
+/// How a group of Deluxe zones that Finale panel doesn't have (D or E) gets lit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ZoneSynthesis {
+    /// By whatever Finale sensors `[touch.mapping]` maps to them
+    #[default]
+    Mapping,
+    /// Only when both neighbouring A (for D) or B (for E) sensors are pressed together
+    Adjacent,
+}
+
+/// Deluxe zone groups that Finale panel doesn't have
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneGroup {
+    /// Outer ring between A sectors
+    D,
+    /// Inner ring between B sectors
+    E,
+}
+
+/// Deluxe zone lit when both Finale sensors are pressed, sensors are (byte, bit)
+#[derive(Debug, Clone, PartialEq)]
+struct AdjacentRule {
+    sensors: [(usize, usize); 2],
+    zone: (usize, u8),
+}
+
+/// Validated mapping, stores for every Finale sensor the bits it sets in a Deluxe frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoneMapping {
+    masks: [[[u8; 9]; 5]; 4],
+    /// Zones that are lit only by `adjacent` rules, whatever masks say
+    synthesized: [u8; 9],
+    adjacent: Vec<AdjacentRule>,
+}
+
+impl ZoneMapping {
+    /// Builds mapping from a `[touch.mapping]` table (see `from_mapping`) and how D and E zones
+    /// are produced
+    pub fn new(
+        mapping: &BTreeMap<String, Vec<String>>,
+        d_zones: ZoneSynthesis,
+        e_zones: ZoneSynthesis,
+    ) -> io::Result<Self> {
+        let mut zone_mapping = Self::from_mapping(mapping)?;
+        zone_mapping.set_synthesis(ZoneGroup::D, d_zones);
+        zone_mapping.set_synthesis(ZoneGroup::E, e_zones);
+        Ok(zone_mapping)
+    }
+
+    /// Builds mapping from `[touch.mapping]`. Finale zones that are not listed keep their
+    /// default mapping, an empty list disables the zone
+    pub fn from_mapping(mapping: &BTreeMap<String, Vec<String>>) -> io::Result<Self> {
+        let mut zone_mapping = Self::default();
+        for (finale, deluxe) in mapping {
+            let (byte, bit) = finale_zone_position(finale).ok_or_else(|| {
+                invalid_mapping(format!("unknown Finale touch zone \"{finale}\""))
+            })?;
+
+            let mut mask = [0u8; 9];
+            for zone in deluxe {
+                let (index, bit_mask) = deluxe_zone_position(zone).ok_or_else(|| {
+                    invalid_mapping(format!(
+                        "unknown Deluxe touch zone \"{zone}\" in mapping of {finale}"
+                    ))
+                })?;
+                mask[index] |= bit_mask;
+            }
+            zone_mapping.masks[byte][bit] = mask;
+        }
+        Ok(zone_mapping)
+    }
+
+    /// Switches how a zone group is produced. With `Adjacent` mapping entries pointing at
+    /// the group are ignored
+    pub fn set_synthesis(&mut self, group: ZoneGroup, synthesis: ZoneSynthesis) {
+        let (group, source) = match group {
+            ZoneGroup::D => ('D', 'A'),
+            ZoneGroup::E => ('E', 'B'),
+        };
+        let sensor = |n: usize| finale_zone_position(&format!("{source}{n}")).unwrap();
+        let zones: Vec<_> = (1..=8)
+            .map(|i| deluxe_zone_position(&format!("{group}{i}")).unwrap())
+            .collect();
+        self.adjacent.retain(|rule| !zones.contains(&rule.zone));
+
+        match synthesis {
+            ZoneSynthesis::Mapping => {
+                for &(index, bit_mask) in &zones {
+                    self.synthesized[index] &= !bit_mask;
+                }
+            }
+            ZoneSynthesis::Adjacent => {
+                for (i, &zone) in zones.iter().enumerate() {
+                    self.synthesized[zone.0] |= zone.1;
+                    // Zone i + 1 lies between sectors i and i + 1 (1-based, wrapping around)
+                    let prev = if i == 0 { 8 } else { i };
+                    self.adjacent.push(AdjacentRule {
+                        sensors: [sensor(prev), sensor(i + 1)],
+                        zone,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Translates one player's half of a Finale touch frame (4 sensor bytes, without parentheses)
+    /// into a 9 bytes long Deluxe touch frame
+    pub fn translate(&self, finale: &[u8]) -> [u8; 9] {
+        let mut write_buffer = DEFAULT_DELUXE_WRITE_BUFFER;
+        for (byte, bit) in finale.iter().take(self.masks.len()).enumerate() {
+            for (pos, mask) in self.masks[byte].iter().enumerate() {
+                if !bit_read(bit, pos) {
+                    continue;
+                }
+
+                for (dst, src) in write_buffer.iter_mut().zip(mask) {
+                    *dst |= src;
+                }
+            }
+        }
+
+        for (dst, synthesized) in write_buffer.iter_mut().zip(&self.synthesized) {
+            *dst &= !synthesized;
+        }
+        let pressed =
+            |&(byte, bit): &(usize, usize)| finale.get(byte).is_some_and(|b| bit_read(b, bit));
+        for rule in &self.adjacent {
+            if rule.sensors.iter().all(pressed) {
+                write_buffer[rule.zone.0] |= rule.zone.1;
+            }
+        }
+        write_buffer
+    }
+
+    /// Indices (in `DELUXE_ZONES`) of Deluxe zones that Finale sensor at (byte, bit) activates
+    pub fn deluxe_zones_of(&self, byte: usize, bit: usize) -> Vec<usize> {
+        let mut mask = self.masks[byte][bit];
+        for (dst, synthesized) in mask.iter_mut().zip(&self.synthesized) {
+            *dst &= !synthesized;
+        }
+        for rule in &self.adjacent {
+            if rule.sensors.contains(&(byte, bit)) {
+                mask[rule.zone.0] |= rule.zone.1;
+            }
+        }
+        DELUXE_ZONES
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, (index, bit_mask)))| mask[*index] & bit_mask != 0)
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+impl Default for ZoneMapping {
+    fn default() -> Self {
+        let mut masks = [[[0u8; 9]; 5]; 4];
+        for (finale, deluxe) in DEFAULT_MAPPING {
+            let (byte, bit) = finale_zone_position(finale).unwrap();
+            for zone in deluxe {
+                let (index, bit_mask) = deluxe_zone_position(zone).unwrap();
+                masks[byte][bit][index] |= bit_mask;
+            }
+        }
+        Self {
+            masks,
+            synthesized: [0; 9],
+            adjacent: Vec::new(),
+        }
+    }
+}
+
+fn invalid_mapping(msg: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid [touch.mapping]: {msg}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::touch::mapping::{
+        default_mapping, deluxe_zones_in, finale_zone_position, ZoneGroup, ZoneMapping,
+        ZoneSynthesis, DEFAULT_DELUXE_WRITE_BUFFER, DELUXE_ZONES,
+    };
+    use std::collections::BTreeMap;
+
+    fn finale_data(pressed: &[&str]) -> [u8; 4] {
+        let mut data = [0u8; 4];
+        for zone in pressed {
+            let (byte, bit) = finale_zone_position(zone).unwrap();
+            data[byte] |= 1 << bit;
+        }
+        data
+    }
+
+    #[test]
+    pub fn translate_nothing_pressed() {
+        assert_eq!(
+            ZoneMapping::default().translate(&[0, 0, 0, 0]),
+            DEFAULT_DELUXE_WRITE_BUFFER
+        );
+    }
+
+    #[test]
+    pub fn translate_b1_lights_e1_e2() {
+        // B1 is the second bit of the first byte
+        assert_eq!(
+            ZoneMapping::default().translate(&[0b10, 0, 0, 0]),
+            [b'(', 0, 8, 0, 0, 0, 2 | 4, 0, b')']
+        );
+    }
+
+    #[test]
+    pub fn translate_c_lights_c1_c2() {
+        assert_eq!(
+            ZoneMapping::default().translate(&[0, 0, 0, 0b10000]),
+            [b'(', 0, 0, 0, 2 | 4, 0, 0, 0, b')']
+        );
+    }
+
+    #[test]
+    pub fn decodes_deluxe_frame() {
+        let frame = ZoneMapping::default().translate(&[0b10, 0, 0, 0b10000]);
+        assert_eq!(deluxe_zones_in(&frame), ["B1", "C1", "C2", "E1", "E2"]);
+        assert!(deluxe_zones_in(&DEFAULT_DELUXE_WRITE_BUFFER).is_empty());
+    }
+
+    #[test]
+    pub fn default_config_matches_builtin() {
+        assert_eq!(
+            ZoneMapping::from_mapping(&default_mapping()).unwrap(),
+            ZoneMapping::default()
+        );
+    }
+
+    #[test]
+    pub fn custom_mapping_overrides_zone() {
+        let mut config = BTreeMap::new();
+        config.insert("A1".to_string(), vec!["A1".to_string()]);
+        config.insert("B1".to_string(), vec![]);
+        let mapping = ZoneMapping::from_mapping(&config).unwrap();
+
+        assert_eq!(
+            mapping.translate(&[0b11, 0, 0, 0]),
+            [b'(', 1, 0, 0, 0, 0, 0, 0, b')']
+        );
+        // Zones not in config keep defaults
+        assert_eq!(
+            mapping.translate(&[0b100, 0, 0, 0]),
+            ZoneMapping::default().translate(&[0b100, 0, 0, 0])
+        );
+    }
+
+    #[test]
+    pub fn unknown_zones_are_rejected() {
+        let mut config = BTreeMap::new();
+        config.insert("A9".to_string(), vec!["A1".to_string()]);
+        assert!(ZoneMapping::from_mapping(&config).is_err());
+
+        let mut config = BTreeMap::new();
+        config.insert("A1".to_string(), vec!["F1".to_string()]);
+        assert!(ZoneMapping::from_mapping(&config).is_err());
+    }
+
+    #[test]
+    pub fn zone_synthesis() {
+        use ZoneSynthesis::{Adjacent, Mapping};
+
+        #[rustfmt::skip]
+        let cases: &[(ZoneSynthesis, ZoneSynthesis, &[&str], &[&str])] = &[
+            // d_zones, e_zones, pressed Finale sensors, lit Deluxe zones
+            (Mapping, Mapping, &["A1"], &["A1", "D1", "D2"]),
+            (Adjacent, Mapping, &["A1"], &["A1"]),
+            (Adjacent, Mapping, &["A1", "A2"], &["A1", "A2", "D2"]),
+            (Adjacent, Mapping, &["A8", "A1"], &["A1", "A8", "D1"]),
+            (Adjacent, Mapping, &["A1", "A3"], &["A1", "A3"]),
+            (Adjacent, Mapping, &["B1"], &["B1", "E1", "E2"]),
+            (Mapping, Adjacent, &["B1"], &["B1"]),
+            (Mapping, Adjacent, &["B1", "B2", "A1"], &["A1", "B1", "B2", "D1", "D2", "E2"]),
+            (Adjacent, Adjacent, &["A1", "A2", "A3", "B8", "B1"], &["A1", "A2", "A3", "B1", "B8", "D2", "D3", "E1"]),
+            (Adjacent, Adjacent, &["C"], &["C1", "C2"]),
+        ];
+
+        for (d_zones, e_zones, pressed, expected) in cases {
+            let mapping = ZoneMapping::new(&default_mapping(), *d_zones, *e_zones).unwrap();
+            let frame = mapping.translate(&finale_data(pressed));
+            assert_eq!(
+                deluxe_zones_in(&frame),
+                *expected,
+                "d_zones = {d_zones:?}, e_zones = {e_zones:?}, pressed {pressed:?}"
+            );
+        }
+    }
+
+    #[test]
+    pub fn adjacent_synthesis_ignores_mapping_and_can_be_reverted() {
+        let mut config = BTreeMap::new();
+        config.insert("A1".to_string(), vec!["A1".to_string(), "D5".to_string()]);
+        let mut mapping = ZoneMapping::from_mapping(&config).unwrap();
+        mapping.set_synthesis(ZoneGroup::D, ZoneSynthesis::Adjacent);
+        assert_eq!(
+            deluxe_zones_in(&mapping.translate(&finale_data(&["A1"]))),
+            ["A1"]
+        );
+
+        // Both neighbouring D zones are still driven by A1, which matters for sensitivity
+        let zones: Vec<_> = mapping
+            .deluxe_zones_of(0, 0)
+            .iter()
+            .map(|&i| DELUXE_ZONES[i].0)
+            .collect();
+        assert_eq!(zones, ["A1", "D1", "D2"]);
+
+        mapping.set_synthesis(ZoneGroup::D, ZoneSynthesis::Mapping);
+        assert_eq!(
+            deluxe_zones_in(&mapping.translate(&finale_data(&["A1"]))),
+            ["A1", "D5"]
+        );
+    }
+}
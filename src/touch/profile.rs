@@ -0,0 +1,409 @@
+// Persisted touch sensitivity.
+//
+// Deluxe pushes a threshold for every zone when it boots (and from the test menu). The last value
+// per player and zone, whichever side byte it came with, is kept in a state file (`file` in
+// [touch.sensitivity]) and sent to the Finale panel on startup, so calibration survives restarts of
+// the bridge even before the game is up. `[touch.sensitivity.p1]` and `[touch.sensitivity.p2]`
+// override single zones: the game still gets its own value acknowledged, but the panel gets the
+// configured one. Zones are Deluxe zone names (A1..E8, C1, C2), thresholds are translated to Finale
+// sensors the same way as the game's ones (see touch::sensitivity). A relative `file` is next to
+// the config file. All of it is only used with `forward = true`, like the game's thresholds. The
+// file is replaced by a rename, so it is never half written, and a broken one is ignored with a
+// warning, touch comes up without it.
+//
+// The profile lives in RingEdge2 behind the touch mutex, so saving only takes a snapshot there
+// and hands it to ProfileSaver. Its thread is the only writer of the file: snapshots arrive in
+// the order they were taken and the latest one is written once the game stops changing
+// thresholds for SAVE_DELAY, so the ~34 thresholds per player the game sends at boot make one
+// write and an older snapshot can't overwrite a newer one.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::touch::mapping::DELUXE_ZONES;
+use crate::touch::sensitivity::AREA_BASE;
+
+/// Zone name to threshold, for each player
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ZoneThresholds {
+    pub p1: BTreeMap<String, u8>,
+    pub p2: BTreeMap<String, u8>,
+}
+
+impl ZoneThresholds {
+    fn player(&self, player_num: usize) -> &BTreeMap<String, u8> {
+        match player_num {
+            0 => &self.p1,
+            _ => &self.p2,
+        }
+    }
+
+    fn player_mut(&mut self, player_num: usize) -> &mut BTreeMap<String, u8> {
+        match player_num {
+            0 => &mut self.p1,
+            _ => &mut self.p2,
+        }
+    }
+}
+
+/// `[touch.sensitivity]` config section
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SensitivityConfig {
+    /// Experimental: send thresholds to the Finale panel, in a command format that is a guess
+    /// (see touch::sensitivity). Off, the game only gets them acknowledged
+    pub forward: bool,
+    /// State file with the last thresholds set by the game, empty disables it
+    pub file: String,
+    #[serde(flatten)]
+    pub overrides: ZoneThresholds,
+}
+
+impl SensitivityConfig {
+    /// Same config with a relative `file` turned into a path next to `config_path`
+    pub fn relative_to(&self, config_path: &str) -> Self {
+        let mut config = self.clone();
+        if !config.file.is_empty() && Path::new(&config.file).is_relative() {
+            if let Some(dir) = Path::new(config_path).parent() {
+                config.file = dir.join(&config.file).to_string_lossy().into_owned();
+            }
+        }
+        config
+    }
+}
+
+impl Default for SensitivityConfig {
+    fn default() -> Self {
+        Self {
+            forward: false,
+            file: "touch_sensitivity.toml".to_string(),
+            overrides: ZoneThresholds::default(),
+        }
+    }
+}
+
+/// How long the saver waits for newer thresholds before writing the state file
+pub const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+pub struct SensitivityProfile {
+    file: Option<String>,
+    stored: ZoneThresholds,
+    overrides: ZoneThresholds,
+    changed: bool,
+    /// Writes the state file, without one save writes it right away
+    saver: Option<ProfileSaver>,
+}
+
+impl SensitivityProfile {
+    /// Reads the state file (a missing or broken one is just empty) and checks the overrides
+    pub fn load(config: &SensitivityConfig) -> io::Result<Self> {
+        for (player_num, overrides) in [&config.overrides.p1, &config.overrides.p2]
+            .into_iter()
+            .enumerate()
+        {
+            if let Some(zone) = overrides.keys().find(|zone| area_of(zone).is_none()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Invalid [touch.sensitivity.p{}]: unknown Deluxe touch zone \"{}\"",
+                        player_num + 1,
+                        zone
+                    ),
+                ));
+            }
+        }
+
+        let file = (!config.file.is_empty()).then(|| config.file.clone());
+        let mut stored = match &file {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(data) => toml::from_str(&data).unwrap_or_else(|err| {
+                    // Touch works without it, the game sets every threshold again anyway
+                    warn!("Touch: sensitivity file {path} is broken, starting without it: {err}");
+                    ZoneThresholds::default()
+                }),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => ZoneThresholds::default(),
+                Err(err) => return Err(err),
+            },
+            None => ZoneThresholds::default(),
+        };
+        for player_num in 0..2 {
+            stored.player_mut(player_num).retain(|zone, _| {
+                let known = area_of(zone).is_some();
+                if !known {
+                    warn!("Touch: sensitivity file has unknown zone \"{zone}\", ignoring it");
+                }
+                known
+            });
+        }
+
+        Ok(Self {
+            file,
+            stored,
+            overrides: config.overrides.clone(),
+            changed: false,
+            saver: None,
+        })
+    }
+
+    /// Leaves writing the state file to `saver` from now on
+    pub fn set_saver(&mut self, saver: ProfileSaver) {
+        self.saver = Some(saver);
+    }
+
+    /// Thresholds to send to the panel on startup, (area, value) in Deluxe command form
+    pub fn thresholds(&self, player_num: usize) -> Vec<(u8, u8)> {
+        let mut zones = self.stored.player(player_num).clone();
+        zones.extend(self.overrides.player(player_num).clone());
+        zones
+            .iter()
+            .filter_map(|(zone, &value)| Some((area_of(zone)?, value)))
+            .collect()
+    }
+
+    /// Remembers a threshold the game set and returns the one that should go to the panel
+    pub fn update(&mut self, player_num: usize, area: u8, value: u8) -> u8 {
+        let zone = match area
+            .checked_sub(AREA_BASE)
+            .and_then(|zone| DELUXE_ZONES.get(zone as usize))
+        {
+            Some((zone, _)) => *zone,
+            None => return value,
+        };
+
+        let stored = self.stored.player_mut(player_num);
+        if stored.get(zone) != Some(&value) {
+            stored.insert(zone.to_string(), value);
+            self.changed = true;
+        }
+        self.overrides
+            .player(player_num)
+            .get(zone)
+            .copied()
+            .unwrap_or(value)
+    }
+
+    /// Writes the state file if anything changed since the last save, through the saver if
+    /// there is one
+    pub fn save(&mut self) -> io::Result<()> {
+        let save = match self.take_save()? {
+            Some(save) => save,
+            None => return Ok(()),
+        };
+        match &self.saver {
+            Some(saver) => saver.sender.send(save).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "Touch: sensitivity saver has stopped",
+                )
+            }),
+            None => save.write(),
+        }
+    }
+
+    /// State file contents to write, if anything changed since the last save
+    fn take_save(&mut self) -> io::Result<Option<ProfileSave>> {
+        let path = match &self.file {
+            Some(path) if self.changed => path.clone(),
+            _ => return Ok(None),
+        };
+        let data = toml::to_string_pretty(&self.stored)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        self.changed = false;
+        Ok(Some(ProfileSave { path, data }))
+    }
+}
+
+/// Serialized state file
+struct ProfileSave {
+    path: String,
+    data: String,
+}
+
+impl ProfileSave {
+    /// Writes next to the state file and renames it over, so the file is never half written
+    fn write(&self) -> io::Result<()> {
+        let temp = format!("{}.tmp", self.path);
+        fs::write(&temp, &self.data)?;
+        fs::rename(&temp, &self.path)?;
+        info!("Touch: sensitivity saved to {}", self.path);
+        Ok(())
+    }
+}
+
+/// Writes state file snapshots from its own thread, see module docs. The thread writes what's
+/// left and ends once the profile is dropped
+pub struct ProfileSaver {
+    sender: Sender<ProfileSave>,
+}
+
+impl ProfileSaver {
+    /// Starts the saver thread, a snapshot is written once no newer one came for `delay`
+    pub fn spawn(delay: Duration) -> io::Result<(Self, JoinHandle<io::Result<()>>)> {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let handle = thread::Builder::new()
+            .name("Touch Sensitivity Saver Thread".to_string())
+            .spawn(move || {
+                save_latest(receiver, delay);
+                Ok(())
+            })?;
+        Ok((Self { sender }, handle))
+    }
+}
+
+fn save_latest(receiver: Receiver<ProfileSave>, delay: Duration) {
+    while let Ok(mut save) = receiver.recv() {
+        let stopping = loop {
+            match receiver.recv_timeout(delay) {
+                Ok(newer) => save = newer,
+                Err(RecvTimeoutError::Timeout) => break false,
+                Err(RecvTimeoutError::Disconnected) => break true,
+            }
+        };
+        if let Err(err) = save.write() {
+            error!("Touch: saving sensitivity failed: {}", err);
+        }
+        if stopping {
+            return;
+        }
+    }
+}
+
+/// Area byte of a Deluxe zone, as the game sends it
+fn area_of(zone: &str) -> Option<u8> {
+    DELUXE_ZONES
+        .iter()
+        .position(|(name, _)| *name == zone)
+        .map(|index| AREA_BASE + index as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use crate::touch::profile::{ProfileSaver, SensitivityConfig, SensitivityProfile};
+
+    fn temp_file(name: &str) -> String {
+        let path = env::temp_dir().join(format!("{}_{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    pub fn game_values_survive_restart() {
+        let config = SensitivityConfig {
+            file: temp_file("sensitivity.toml"),
+            ..SensitivityConfig::default()
+        };
+        let mut profile = SensitivityProfile::load(&config).unwrap();
+        assert!(profile.thresholds(0).is_empty());
+        // A1 and D2 of P1, C1 of P2
+        assert_eq!(profile.update(0, b'A', 20), 20);
+        profile.update(0, b'A' + 19, 30);
+        profile.update(1, b'A' + 16, 10);
+        // Out of range area is passed through and not stored
+        assert_eq!(profile.update(0, b'A' + 40, 5), 5);
+        profile.save().unwrap();
+
+        let profile = SensitivityProfile::load(&config).unwrap();
+        assert_eq!(profile.thresholds(0), [(b'A', 20), (b'A' + 19, 30)]);
+        assert_eq!(profile.thresholds(1), [(b'A' + 16, 10)]);
+        assert!(!Path::new(&format!("{}.tmp", config.file)).exists());
+        fs::remove_file(&config.file).unwrap();
+    }
+
+    #[test]
+    pub fn broken_file_starts_empty() {
+        let config = SensitivityConfig {
+            file: temp_file("broken.toml"),
+            ..SensitivityConfig::default()
+        };
+        // Cut off in the middle of a save
+        fs::write(&config.file, "[p1]\nA1 = ").unwrap();
+        let mut profile = SensitivityProfile::load(&config).unwrap();
+        assert!(profile.thresholds(0).is_empty());
+
+        profile.update(0, b'A', 20);
+        profile.save().unwrap();
+        let profile = SensitivityProfile::load(&config).unwrap();
+        assert_eq!(profile.thresholds(0), [(b'A', 20)]);
+        fs::remove_file(&config.file).unwrap();
+    }
+
+    #[test]
+    pub fn file_is_next_to_config() {
+        let config = SensitivityConfig::default();
+        let resolved = config.relative_to("/etc/bridge/config.toml");
+        assert_eq!(
+            Path::new(&resolved.file),
+            Path::new("/etc/bridge/touch_sensitivity.toml")
+        );
+        assert_eq!(config.relative_to("config.toml").file, config.file);
+
+        let absolute = SensitivityConfig {
+            file: temp_file("absolute.toml"),
+            ..SensitivityConfig::default()
+        };
+        assert_eq!(absolute.relative_to("/etc/config.toml"), absolute);
+        let disabled = SensitivityConfig {
+            file: String::new(),
+            ..SensitivityConfig::default()
+        };
+        assert_eq!(disabled.relative_to("/etc/config.toml"), disabled);
+    }
+
+    #[test]
+    pub fn saver_writes_latest_snapshot() {
+        let config = SensitivityConfig {
+            file: temp_file("saver.toml"),
+            ..SensitivityConfig::default()
+        };
+        let mut profile = SensitivityProfile::load(&config).unwrap();
+        let (saver, handle) = ProfileSaver::spawn(Duration::from_secs(60)).unwrap();
+        profile.set_saver(saver);
+        for value in [20, 25, 30] {
+            profile.update(0, b'A', value);
+            profile.save().unwrap();
+        }
+        // Nothing new, nothing to send
+        profile.save().unwrap();
+        assert!(!Path::new(&config.file).exists());
+
+        // Still waiting for more, the last snapshot is written once the profile is gone
+        drop(profile);
+        handle.join().unwrap().unwrap();
+        let profile = SensitivityProfile::load(&config).unwrap();
+        assert_eq!(profile.thresholds(0), [(b'A', 30)]);
+        fs::remove_file(&config.file).unwrap();
+    }
+
+    #[test]
+    pub fn overrides_win_over_game() {
+        let mut config = SensitivityConfig {
+            file: String::new(),
+            ..SensitivityConfig::default()
+        };
+        config.overrides.p2.insert("B1".to_string(), 40);
+        let mut profile = SensitivityProfile::load(&config).unwrap();
+        assert_eq!(profile.thresholds(1), [(b'A' + 8, 40)]);
+        assert_eq!(profile.update(1, b'A' + 8, 20), 40);
+        assert_eq!(profile.update(0, b'A' + 8, 20), 20);
+        // Nowhere to save, not an error
+        profile.save().unwrap();
+
+        config.overrides.p1.insert("F1".to_string(), 40);
+        assert!(SensitivityProfile::load(&config).is_err());
+    }
+}
@@ -0,0 +1,353 @@
+// Touch recording and replay.
+//
+// A recording is a header followed by records:
+//   [delta: u32 LE, microseconds since previous record] [kind: u8] [len: u8] [data: len bytes]
+// where kind is one of RecordKind. Raw Finale frames are recorded as they arrive, followed by
+// the Deluxe frames translated from them, so a recording can be both inspected and replayed
+// through a different mapping. Replay runs frames through the same layout, debounce and stuck
+// detection as the bridge, with recorded timestamps as the clock, so it sends what the bridge
+// would have sent.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::config::{Config, ReplayArgs, Touch};
+use crate::touch::debounce::Debouncer;
+use crate::touch::frame::{FRAME_LEN, PLAYER_DATA};
+use crate::touch::layout::Layout;
+use crate::touch::mapping::ZoneMapping;
+use crate::touch::stuck::StuckDetector;
+use crate::touch::zone_mapping;
+use crate::transport;
+
+const MAGIC: &[u8; 4] = b"MFDT";
+const VERSION: u8 = 1;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    /// Raw 14 bytes long frame from the Finale panel
+    Finale = 0,
+    /// Translated 9 bytes long frame sent to Deluxe P1
+    DeluxeP1 = 1,
+    /// Translated 9 bytes long frame sent to Deluxe P2
+    DeluxeP2 = 2,
+}
+
+impl RecordKind {
+    pub fn deluxe(player_num: usize) -> Self {
+        if player_num == 0 {
+            RecordKind::DeluxeP1
+        } else {
+            RecordKind::DeluxeP2
+        }
+    }
+
+    fn from_u8(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(RecordKind::Finale),
+            1 => Some(RecordKind::DeluxeP1),
+            2 => Some(RecordKind::DeluxeP2),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Time since the start of the recording
+    pub timestamp: Duration,
+    pub kind: RecordKind,
+    pub data: Vec<u8>,
+}
+
+pub struct TouchRecorder<W: Write = BufWriter<File>> {
+    writer: W,
+    last: Instant,
+}
+
+impl TouchRecorder {
+    pub fn create(path: &str) -> io::Result<Self> {
+        info!("Touch: recording into {}", path);
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> TouchRecorder<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        Ok(Self {
+            writer,
+            last: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, kind: RecordKind, data: &[u8]) -> io::Result<()> {
+        let now = Instant::now();
+        let delta = now
+            .duration_since(self.last)
+            .as_micros()
+            .min(u32::MAX as u128) as u32;
+        self.last = now;
+
+        self.writer.write_all(&delta.to_le_bytes())?;
+        self.writer.write_all(&[kind as u8, data.len() as u8])?;
+        self.writer.write_all(data)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+pub struct TouchRecording<R: Read> {
+    reader: R,
+    timestamp: Duration,
+}
+
+impl TouchRecording<BufReader<File>> {
+    pub fn open(path: &str) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> TouchRecording<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != MAGIC || header[4] != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a touch recording or unsupported version",
+            ));
+        }
+        Ok(Self {
+            reader,
+            timestamp: Duration::ZERO,
+        })
+    }
+
+    /// Reads next record, returns None at the end of the recording
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut header = [0u8; 6];
+        match self.reader.read_exact(&mut header[..1]) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        self.reader.read_exact(&mut header[1..])?;
+
+        let delta = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let kind = RecordKind::from_u8(header[4]).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown record kind {}", header[4]),
+            )
+        })?;
+        let mut data = vec![0u8; header[5] as usize];
+        self.reader.read_exact(&mut data)?;
+
+        self.timestamp += Duration::from_micros(delta as u64);
+        Ok(Some(Record {
+            timestamp: self.timestamp,
+            kind,
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for TouchRecording<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+/// What the bridge does to Finale sensors before mapping them, see RingEdge2::forward_frame
+#[derive(Default)]
+pub struct ReplayFilters {
+    pub layout: Layout,
+    pub debouncer: Debouncer,
+    pub stuck: StuckDetector,
+}
+
+impl ReplayFilters {
+    pub fn from_config(touch: &Touch) -> io::Result<Self> {
+        Ok(Self {
+            layout: Layout::from_config(touch)?,
+            debouncer: Debouncer::from_config(&touch.debounce)?,
+            stuck: StuckDetector::from_config(touch),
+        })
+    }
+
+    fn apply(&mut self, player_num: usize, sensors: &[u8], now: Instant) -> [u8; 4] {
+        let sensors = self.layout.apply(sensors);
+        let sensors = self.debouncer.filter(player_num, &sensors);
+        self.stuck.filter(player_num, &sensors, now)
+    }
+}
+
+/// Feeds recorded Finale frames of `player_num` through `filters` and `mapping` into `output`.
+/// Returns number of frames written
+pub fn replay<R: Read>(
+    recording: TouchRecording<R>,
+    mapping: &ZoneMapping,
+    filters: &mut ReplayFilters,
+    player_num: usize,
+    output: &mut dyn Write,
+    realtime: bool,
+) -> io::Result<usize> {
+    let start = Instant::now();
+    let mut frames = 0;
+    for record in recording {
+        let record = record?;
+        if record.kind != RecordKind::Finale || record.data.len() != FRAME_LEN {
+            continue;
+        }
+
+        if realtime {
+            // Sleep until the absolute time of the record, so delays don't pile up
+            let elapsed = start.elapsed();
+            if record.timestamp > elapsed {
+                thread::sleep(record.timestamp - elapsed);
+            }
+        }
+
+        // Stuck detection goes by recording time, also when replaying fast
+        let sensors = filters.apply(
+            player_num,
+            &record.data[PLAYER_DATA[player_num].clone()],
+            start + record.timestamp,
+        );
+        output.write_all(&mapping.translate(&sensors))?;
+        frames += 1;
+    }
+    output.flush()?;
+    Ok(frames)
+}
+
+/// Entry point of `replay` subcommand
+pub fn run_replay(config: &Config, args: &ReplayArgs) -> io::Result<()> {
+    let mapping = zone_mapping(&config.touch)?;
+    let mut filters = ReplayFilters::from_config(&config.touch)?;
+    let recording = TouchRecording::open(&args.file)?;
+    let player_num = args.player as usize - 1;
+
+    let mut output: Box<dyn Write> = match (&args.port, &args.output) {
+        (Some(port), _) => transport::open(port, 115_200)?,
+        (None, Some(path)) => Box::new(BufWriter::new(File::create(path)?)),
+        (None, None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Replay needs either --port or --output",
+            ))
+        }
+    };
+
+    info!("Replaying {} as P{}", args.file, args.player);
+    let frames = replay(
+        recording,
+        &mapping,
+        &mut filters,
+        player_num,
+        &mut output,
+        !args.fast,
+    )?;
+    info!("Replay finished, {} frames sent", frames);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::touch::mapping::ZoneMapping;
+    use crate::touch::record::{replay, RecordKind, ReplayFilters, TouchRecorder, TouchRecording};
+    use crate::touch::stuck::StuckDetector;
+
+    const FRAME: [u8; 14] = [b'(', 0b10, 0, 0, 0, b')', b'(', 0, 0, 0, 0, b')', 0, 0];
+
+    fn recording() -> Vec<u8> {
+        let mut recorder = TouchRecorder::new(Vec::new()).unwrap();
+        recorder.record(RecordKind::Finale, &FRAME).unwrap();
+        recorder
+            .record(RecordKind::DeluxeP1, &[b'(', 0, 8, 0, 0, 0, 6, 0, b')'])
+            .unwrap();
+        recorder.record(RecordKind::Finale, &FRAME).unwrap();
+        recorder.into_inner()
+    }
+
+    #[test]
+    pub fn round_trip() {
+        let data = recording();
+        let records: Vec<_> = TouchRecording::new(data.as_slice())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].kind, RecordKind::Finale);
+        assert_eq!(records[0].data, FRAME);
+        assert_eq!(records[1].kind, RecordKind::DeluxeP1);
+        assert!(records[2].timestamp >= records[0].timestamp);
+    }
+
+    #[test]
+    pub fn replay_translates_finale_frames() {
+        let data = recording();
+        let mut output = Vec::new();
+        let frames = replay(
+            TouchRecording::new(data.as_slice()).unwrap(),
+            &ZoneMapping::default(),
+            &mut ReplayFilters::default(),
+            0,
+            &mut output,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(frames, 2);
+        assert_eq!(output, [[b'(', 0, 8, 0, 0, 0, 6, 0, b')']; 2].concat());
+    }
+
+    #[test]
+    pub fn replay_masks_stuck_sensors_by_recording_time() {
+        // B1 held for 3 s, in frames 1 s apart
+        let mut data = TouchRecorder::new(Vec::new()).unwrap().into_inner();
+        for _ in 0..4 {
+            data.extend_from_slice(&1_000_000u32.to_le_bytes());
+            data.extend_from_slice(&[RecordKind::Finale as u8, FRAME.len() as u8]);
+            data.extend_from_slice(&FRAME);
+        }
+        let mut filters = ReplayFilters {
+            stuck: StuckDetector::new(Some(Duration::from_secs(2)), true),
+            ..ReplayFilters::default()
+        };
+        let mut output = Vec::new();
+        replay(
+            TouchRecording::new(data.as_slice()).unwrap(),
+            &ZoneMapping::default(),
+            &mut filters,
+            0,
+            &mut output,
+            false,
+        )
+        .unwrap();
+
+        let pressed = [b'(', 0, 8, 0, 0, 0, 6, 0, b')'];
+        let masked = [b'(', 0, 0, 0, 0, 0, 0, 0, b')'];
+        assert_eq!(output, [pressed, pressed, masked, masked].concat());
+    }
+
+    #[test]
+    pub fn rejects_other_files() {
+        assert!(TouchRecording::new(&b"hello"[..]).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+// Translation of Deluxe sensitivity settings into Finale touch panel commands.
+//
+// Deluxe sets a threshold per zone with `{ L/R Area k Threshold }` where Area is `'A' + index`
+// of the zone in A1..E8 order. The game sends every zone on both L and R to each player's port,
+// and what the side selects on a Deluxe panel isn't known, so thresholds are kept per side and
+// zone, and both sides count for the sensors behind the zone.
+//
+// One Finale sensor usually drives several Deluxe zones (A1 -> A1, D1, D2), so its threshold is
+// aggregated from all of them: the lowest (most sensitive) value wins, so that no Deluxe zone
+// ends up harder to press than the game asked for.
+//
+// The Finale command format is unverified, there is no documentation or capture of it, so
+// nothing is sent to the panel unless `forward = true` in [touch.sensitivity]. It is
+// assumed to be the same command family as Deluxe, with one port for both players, so the side
+// byte selects the player (L = P1, R = P2) and Area is `'A' + index` of the sensor in the touch
+// frame order (A1, B1, A2, ... B8, C). Sensors are tracked as the bridge sees them, after
+// rotation and flip (touch::layout), and addressed on the panel by where they really are.
+
+use crate::touch::layout::Layout;
+use crate::touch::mapping::{finale_sensors, ZoneMapping, DELUXE_ZONES};
+
+pub const SENS_CMD: u8 = b'k';
+
+pub const AREA_BASE: u8 = b'A';
+/// Side byte of Finale sensitivity commands, L for P1 and R for P2
+pub const PLAYER_SIDE: [u8; 2] = [b'L', b'R'];
+/// Side bytes Deluxe sends sensitivity with
+pub const DELUXE_SIDES: [u8; 2] = [b'L', b'R'];
+
+/// Threshold of every Deluxe zone, for each side byte
+type DeluxeThresholds = [[Option<u8>; DELUXE_ZONES.len()]; DELUXE_SIDES.len()];
+
+struct FinaleSensor {
+    deluxe_zones: Vec<usize>,
+}
+
+pub struct SensitivityTranslator {
+    sensors: Vec<FinaleSensor>,
+    /// Panel sensor behind every sensor, see Layout::source_sensor
+    panel_sensors: Vec<usize>,
+    deluxe: [DeluxeThresholds; 2],
+    finale: [Vec<Option<u8>>; 2],
+}
+
+impl SensitivityTranslator {
+    pub fn new(mapping: &ZoneMapping) -> Self {
+        let sensors: Vec<_> = finale_sensors()
+            .map(|(byte, bit, _)| FinaleSensor {
+                deluxe_zones: mapping.deluxe_zones_of(byte, bit),
+            })
+            .collect();
+        let finale = [vec![None; sensors.len()], vec![None; sensors.len()]];
+        Self {
+            panel_sensors: (0..sensors.len()).collect(),
+            sensors,
+            deluxe: [[[None; DELUXE_ZONES.len()]; DELUXE_SIDES.len()]; 2],
+            finale,
+        }
+    }
+
+    /// Addresses the panel through `layout`, thresholds already sent stay where they are
+    pub fn set_layout(&mut self, layout: &Layout) {
+        for (i, panel_sensor) in self.panel_sensors.iter_mut().enumerate() {
+            *panel_sensor = layout.source_sensor(i);
+        }
+    }
+
+    /// Stores a threshold Deluxe set for `area` on `side` (L/R byte) and returns Finale
+    /// commands for every sensor whose aggregated threshold changed
+    pub fn set(&mut self, player_num: usize, side: u8, area: u8, value: u8) -> Vec<[u8; 6]> {
+        let side = match DELUXE_SIDES.iter().position(|&s| s == side) {
+            Some(side) => side,
+            None => return Vec::new(),
+        };
+        let zone = match area.checked_sub(AREA_BASE) {
+            Some(zone) if (zone as usize) < DELUXE_ZONES.len() => zone as usize,
+            _ => return Vec::new(),
+        };
+        self.deluxe[player_num][side][zone] = Some(value);
+
+        let mut commands = Vec::new();
+        for (i, sensor) in self.sensors.iter().enumerate() {
+            if !sensor.deluxe_zones.contains(&zone) {
+                continue;
+            }
+
+            let deluxe = &self.deluxe[player_num];
+            let threshold = sensor
+                .deluxe_zones
+                .iter()
+                .flat_map(|&z| deluxe.iter().filter_map(move |side| side[z]))
+                .min();
+
+            if threshold != self.finale[player_num][i] {
+                self.finale[player_num][i] = threshold;
+                if let Some(threshold) = threshold {
+                    let sensor = self.panel_sensors[i];
+                    commands.push(finale_sens_command(player_num, sensor, threshold));
+                }
+            }
+        }
+        commands
+    }
+
+    /// Last threshold sent to each Finale sensor of a player, in touch frame order after layout
+    pub fn finale_thresholds(&self, player_num: usize) -> &[Option<u8>] {
+        &self.finale[player_num]
+    }
+
+    /// Forgets everything Deluxe set for a player, after `{RSET}` the panel is back at its
+    /// own defaults, so the same thresholds have to be sent again when the game repeats them
+    pub fn reset(&mut self, player_num: usize) {
+        self.deluxe[player_num] = [[None; DELUXE_ZONES.len()]; DELUXE_SIDES.len()];
+        self.finale[player_num].fill(None);
+    }
+
+    /// Finale commands that bring a freshly reset panel back to the player's thresholds
+    pub fn commands(&self, player_num: usize) -> Vec<[u8; 6]> {
+        self.finale[player_num]
+            .iter()
+            .enumerate()
+            .filter_map(|(i, threshold)| {
+                threshold.map(|threshold| {
+                    finale_sens_command(player_num, self.panel_sensors[i], threshold)
+                })
+            })
+            .collect()
+    }
+}
+
+/// Builds a Finale sensitivity command for `sensor` (index in touch frame order)
+pub fn finale_sens_command(player_num: usize, sensor: usize, value: u8) -> [u8; 6] {
+    [
+        b'{',
+        PLAYER_SIDE[player_num],
+        AREA_BASE + sensor as u8,
+        SENS_CMD,
+        value,
+        b'}',
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::touch::layout::Layout;
+    use crate::touch::mapping::ZoneMapping;
+    use crate::touch::sensitivity::SensitivityTranslator;
+
+    // Indices in A1..E8 order
+    const A1: u8 = b'A';
+    const D1: u8 = b'A' + 18;
+    const D2: u8 = b'A' + 19;
+    const C2: u8 = b'A' + 17;
+
+    #[test]
+    pub fn single_zone_sets_mapped_sensors() {
+        let mut sens = SensitivityTranslator::new(&ZoneMapping::default());
+        // A1 is driven by Finale A1 only
+        assert_eq!(sens.set(0, b'L', A1, 20), vec![*b"{LAk\x14}"]);
+        assert_eq!(sens.set(1, b'L', A1, 20), vec![*b"{RAk\x14}"]);
+    }
+
+    #[test]
+    pub fn shared_zone_is_aggregated() {
+        let mut sens = SensitivityTranslator::new(&ZoneMapping::default());
+        sens.set(0, b'L', A1, 30);
+        // D2 is driven by Finale A1 and A2, both get it, A1 takes the lower value
+        let commands = sens.set(0, b'L', D2, 20);
+        assert_eq!(commands, vec![*b"{LAk\x14}", *b"{LCk\x14}"]);
+
+        // Higher value on D1 doesn't change A1, which stays at 20 (D2)
+        assert!(sens.set(0, b'L', D1, 40).contains(b"{LOk\x28}"));
+        assert_eq!(sens.finale_thresholds(0)[0], Some(20));
+    }
+
+    #[test]
+    pub fn c_sensor_and_unknown_areas() {
+        let mut sens = SensitivityTranslator::new(&ZoneMapping::default());
+        assert_eq!(sens.set(0, b'L', C2, 10), vec![*b"{LQk\x0A}"]);
+        // Same value again is not resent
+        assert!(sens.set(0, b'L', C2, 10).is_empty());
+        assert!(sens.set(0, b'L', b'A' + 40, 10).is_empty());
+        assert!(sens.set(0, b'L', 0, 10).is_empty());
+    }
+
+    #[test]
+    pub fn sides_are_kept_apart() {
+        let mut sens = SensitivityTranslator::new(&ZoneMapping::default());
+        assert_eq!(sens.set(0, b'L', A1, 20), vec![*b"{LAk\x14}"]);
+        // R doesn't overwrite L, the lower one still wins
+        assert!(sens.set(0, b'R', A1, 40).is_empty());
+        assert_eq!(sens.finale_thresholds(0)[0], Some(20));
+        assert_eq!(sens.set(0, b'R', A1, 10), vec![*b"{LAk\x0A}"]);
+        // L going up doesn't matter while R is lower
+        assert!(sens.set(0, b'L', A1, 30).is_empty());
+        assert!(sens.set(0, b'X', A1, 5).is_empty());
+    }
+
+    #[test]
+    pub fn rotated_panel_gets_thresholds_on_its_own_sensors() {
+        let mut sens = SensitivityTranslator::new(&ZoneMapping::default());
+        // Rotated by 45 degrees, panel A8 is A1 for the game
+        sens.set_layout(&Layout::new(1, false).unwrap());
+        assert_eq!(sens.set(0, b'L', A1, 20), vec![*b"{LOk\x14}"]);
+        assert_eq!(sens.finale_thresholds(0)[0], Some(20));
+        assert_eq!(sens.set(0, b'L', C2, 10), vec![*b"{LQk\x0A}"]);
+        assert_eq!(sens.commands(0), vec![*b"{LOk\x14}", *b"{LQk\x0A}"]);
+    }
+
+    #[test]
+    pub fn reset_forgets_one_player() {
+        let mut sens = SensitivityTranslator::new(&ZoneMapping::default());
+        sens.set(0, b'L', A1, 20);
+        sens.set(1, b'L', A1, 30);
+        sens.reset(0);
+        assert!(sens.commands(0).is_empty());
+        assert_eq!(sens.commands(1), vec![*b"{RAk\x1E}"]);
+        // Same value as before the reset is sent again
+        assert_eq!(sens.set(0, b'L', A1, 20), vec![*b"{LAk\x14}"]);
+    }
+}
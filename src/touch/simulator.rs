@@ -0,0 +1,358 @@
+// Simulator of the RingEdge 2 (Finale) touch panel.
+//
+// Behaves like the real panel on the other end of a port: waits for `{STAT}`, then streams
+// frames at the panel's cadence until `{HALT}` or `{RSET}`. Touches come from a script:
+//   # time_ms player zones...
+//   0    1 A1 B1
+//   250  1
+//   300  2 C
+// Every line replaces the whole state of that player (empty zone list releases everything).
+// Script time starts at the first `{STAT}`, so it doesn't depend on how fast the bridge starts.
+
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+
+use crate::config::SimulateFinaleArgs;
+use crate::touch::frame::build_frame;
+use crate::touch::mapping::finale_zone_position;
+use crate::transport;
+use crate::transport::Transport;
+
+/// Panel streams as fast as 9600 baud allows, 14 bytes take ~14.6 ms
+pub const FRAME_INTERVAL: Duration = Duration::from_millis(15);
+
+const COMMAND_LEN: usize = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptStep {
+    /// Time since the first `{STAT}`
+    pub at: Duration,
+    pub player_num: usize,
+    /// Sensor bytes of the player's half of the frame
+    pub sensors: [u8; 4],
+}
+
+/// Parses a touch script, see module comment for the format
+pub fn parse_script(script: &str) -> io::Result<Vec<ScriptStep>> {
+    let mut steps = Vec::new();
+    for (line_num, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid = |msg: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Touch script line {}: {}", line_num + 1, msg),
+            )
+        };
+
+        let mut words = line.split_whitespace();
+        let at = words
+            .next()
+            .and_then(|w| w.parse::<u64>().ok())
+            .ok_or_else(|| invalid("expected time in milliseconds".to_string()))?;
+        let player_num = match words.next() {
+            Some("1") => 0,
+            Some("2") => 1,
+            _ => return Err(invalid("expected player, 1 or 2".to_string())),
+        };
+
+        let mut sensors = [0u8; 4];
+        for zone in words {
+            let (byte, bit) = finale_zone_position(zone)
+                .ok_or_else(|| invalid(format!("unknown Finale touch zone \"{zone}\"")))?;
+            sensors[byte] |= 1 << bit;
+        }
+
+        steps.push(ScriptStep {
+            at: Duration::from_millis(at),
+            player_num,
+            sensors,
+        });
+    }
+    steps.sort_by_key(|step| step.at);
+    Ok(steps)
+}
+
+pub struct FinaleSimulator {
+    port: Box<dyn Transport>,
+    script: Vec<ScriptStep>,
+    next_step: usize,
+    sensors: [[u8; 4]; 2],
+
+    streaming: bool,
+    started: Option<Instant>,
+    next_frame: Instant,
+    frame_interval: Duration,
+
+    cmd_buffer: Vec<u8>,
+    /// Sensitivity commands received, as (side, sensor, threshold)
+    pub sensitivity: Vec<(u8, u8, u8)>,
+    pub frames_sent: u64,
+}
+
+impl FinaleSimulator {
+    pub fn with_transport(
+        mut port: Box<dyn Transport>,
+        script: Vec<ScriptStep>,
+    ) -> io::Result<Self> {
+        port.set_timeout(Duration::from_millis(1))?;
+        Ok(Self {
+            port,
+            script,
+            next_step: 0,
+            sensors: [[0; 4]; 2],
+            streaming: false,
+            started: None,
+            next_frame: Instant::now(),
+            frame_interval: FRAME_INTERVAL,
+            cmd_buffer: Vec::new(),
+            sensitivity: Vec::new(),
+            frames_sent: 0,
+        })
+    }
+
+    pub fn set_frame_interval(&mut self, interval: Duration) {
+        self.frame_interval = interval;
+    }
+
+    pub fn is_streaming(&self) -> bool {
+        self.streaming
+    }
+
+    /// Handles pending commands and sends a frame if it's time to
+    pub fn poll(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 64];
+        match self.port.read(&mut buf) {
+            Ok(len) => self.cmd_buffer.extend_from_slice(&buf[..len]),
+            Err(err) if transport::is_timeout(&err) => {}
+            Err(err) => return Err(err),
+        }
+        self.handle_commands();
+
+        if !self.streaming {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        if let Some(started) = self.started {
+            let elapsed = now.duration_since(started);
+            while let Some(step) = self.script.get(self.next_step) {
+                if step.at > elapsed {
+                    break;
+                }
+                self.sensors[step.player_num] = step.sensors;
+                self.next_step += 1;
+            }
+        }
+
+        if now >= self.next_frame {
+            self.port.write_all(&build_frame(&self.sensors))?;
+            self.frames_sent += 1;
+            self.next_frame = now + self.frame_interval;
+        }
+        Ok(())
+    }
+
+    /// Runs until `running` is cleared
+    pub fn run(&mut self, running: &AtomicBool) -> io::Result<()> {
+        while running.load(Ordering::Acquire) {
+            self.poll()?;
+        }
+        Ok(())
+    }
+
+    /// True when every scripted step has been played
+    pub fn script_finished(&self) -> bool {
+        self.next_step >= self.script.len()
+    }
+
+    fn handle_commands(&mut self) {
+        loop {
+            // Everything before a command start is garbage
+            match self.cmd_buffer.iter().position(|&b| b == b'{') {
+                Some(start) => drop(self.cmd_buffer.drain(..start)),
+                None => {
+                    self.cmd_buffer.clear();
+                    return;
+                }
+            }
+            if self.cmd_buffer.len() < COMMAND_LEN {
+                return;
+            }
+            if self.cmd_buffer[COMMAND_LEN - 1] != b'}' {
+                self.cmd_buffer.remove(0);
+                continue;
+            }
+
+            let cmd: Vec<u8> = self.cmd_buffer.drain(..COMMAND_LEN).collect();
+            self.handle_command(&cmd[1..COMMAND_LEN - 1]);
+        }
+    }
+
+    fn handle_command(&mut self, cmd: &[u8]) {
+        debug!("Simulator: {:?}", String::from_utf8_lossy(cmd));
+        match cmd {
+            b"STAT" => {
+                self.streaming = true;
+                self.started.get_or_insert_with(Instant::now);
+            }
+            b"HALT" => self.streaming = false,
+            b"RSET" => {
+                self.streaming = false;
+                self.sensitivity.clear();
+            }
+            [side, sensor, b'k', value] => self.sensitivity.push((*side, *sensor, *value)),
+            _ => debug!("Simulator: unknown command {:02X?}", cmd),
+        }
+    }
+}
+
+/// Entry point of `simulate-finale-touch` subcommand
+pub fn run_simulator(args: &SimulateFinaleArgs, running: &AtomicBool) -> io::Result<()> {
+    let script = match &args.script {
+        Some(path) => parse_script(&fs::read_to_string(path)?)?,
+        None => Vec::new(),
+    };
+
+    let (port, _pty_slave) = open_port(args)?;
+
+    let mut simulator = FinaleSimulator::with_transport(port, script)?;
+    simulator.set_frame_interval(Duration::from_millis(args.interval_ms));
+    simulator.run(running)?;
+    info!("Simulator stopped, {} frames sent", simulator.frames_sent);
+    Ok(())
+}
+
+/// Opens simulator's port. With `--pty` also returns the slave end, which has to stay open
+/// while the simulator runs, otherwise the pty is torn down before the bridge opens it
+#[allow(clippy::type_complexity)]
+fn open_port(
+    args: &SimulateFinaleArgs,
+) -> io::Result<(Box<dyn Transport>, Option<Box<dyn Transport>>)> {
+    if args.pty {
+        #[cfg(unix)]
+        {
+            let (master, slave) = transport::serial::pty_pair()?;
+            info!("Point touch_re2_com to {}", slave.name());
+            return Ok((Box::new(master), Some(Box::new(slave))));
+        }
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--pty is only available on Unix",
+        ));
+    }
+
+    match &args.port {
+        Some(port) => {
+            info!("Finale touch simulator is listening on {}", port);
+            Ok((transport::open(port, 9600)?, None))
+        }
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Simulator needs either --port or --pty",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::time::Duration;
+
+    use crate::touch::frame::{build_frame, FRAME_LEN};
+    use crate::touch::simulator::{parse_script, FinaleSimulator};
+    use crate::transport::memory::MemoryTransport;
+    use crate::transport::Transport;
+
+    #[test]
+    pub fn parses_script() {
+        let steps = parse_script("# comment\n100 2 C\n0 1 A1 B1\n\n200 1\n").unwrap();
+        assert_eq!(steps.len(), 3);
+        assert_eq!(steps[0].at, Duration::ZERO);
+        assert_eq!(steps[0].sensors, [0b11, 0, 0, 0]);
+        assert_eq!(steps[1].player_num, 1);
+        assert_eq!(steps[1].sensors, [0, 0, 0, 0b10000]);
+        assert_eq!(steps[2].sensors, [0; 4]);
+
+        assert!(parse_script("0 3 A1").is_err());
+        assert!(parse_script("0 1 A9").is_err());
+        assert!(parse_script("soon 1 A1").is_err());
+    }
+
+    #[test]
+    pub fn streams_only_between_stat_and_halt() {
+        let (sim_end, mut host) = MemoryTransport::pair("sim");
+        host.set_timeout(Duration::from_millis(200)).unwrap();
+        let script = parse_script("0 1 A1").unwrap();
+        let mut sim = FinaleSimulator::with_transport(Box::new(sim_end), script).unwrap();
+        sim.set_frame_interval(Duration::ZERO);
+
+        sim.poll().unwrap();
+        assert_eq!(sim.frames_sent, 0);
+
+        host.write_all(b"{STAT}").unwrap();
+        sim.poll().unwrap();
+        let mut frame = [0u8; FRAME_LEN];
+        host.read_exact(&mut frame).unwrap();
+        assert_eq!(frame, build_frame(&[[1, 0, 0, 0], [0; 4]]));
+        assert!(sim.script_finished());
+
+        host.write_all(b"xx{HALT}{LAk\x14}").unwrap();
+        sim.poll().unwrap();
+        assert!(!sim.is_streaming());
+        assert_eq!(sim.sensitivity, vec![(b'L', b'A', 0x14)]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn bridge_translates_simulated_touch_over_pty() {
+        use crate::touch::deluxe::{MessageCmd, TouchMasterCommand};
+        use crate::touch::finale::RingEdge2;
+        use crate::touch::mapping::ZoneMapping;
+        use crate::touch::STAT;
+        use crate::transport::serial::pty_pair;
+        use std::time::Instant;
+
+        let (master, slave) = pty_pair().unwrap();
+        let script = parse_script("0 1 B1").unwrap();
+        let mut sim = FinaleSimulator::with_transport(Box::new(master), script).unwrap();
+        sim.set_frame_interval(Duration::from_millis(1));
+
+        let (dx_p1, mut game_p1) = MemoryTransport::pair("p1");
+        let (dx_p2, _game_p2) = MemoryTransport::pair("p2");
+        let mut bridge = RingEdge2::with_transport(
+            Box::new(slave),
+            Some(Box::new(dx_p1)),
+            Some(Box::new(dx_p2)),
+            ZoneMapping::default(),
+        )
+        .unwrap();
+        bridge
+            .parse_command_from_alls(MessageCmd {
+                player_num: 0,
+                cmd: TouchMasterCommand::Stat,
+            })
+            .unwrap();
+        bridge.port.write_all(STAT).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while game_p1.available() < 9 && Instant::now() < deadline {
+            sim.poll().unwrap();
+            bridge.read().unwrap();
+        }
+
+        let mut frame = [0u8; 9];
+        game_p1.read_exact(&mut frame).unwrap();
+        // B1 -> B1, E1, E2
+        assert_eq!(frame, [b'(', 0, 8, 0, 0, 0, 2 | 4, 0, b')']);
+        assert_eq!(bridge.frame_stats().resyncs, 0);
+    }
+}
@@ -0,0 +1,226 @@
+// Detection of stuck Finale sensors.
+//
+// A sensor that reads pressed forever (dirty glass, broken IR) is in every frame and makes
+// Deluxe unplayable. Sensors pressed without a break for longer than `stuck_secs` are reported
+// and, with `mask_stuck`, left out of what goes to Deluxe until they release. Every change
+// logs the player's whole current stuck mask, so the log always shows what is left out.
+
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::config::Touch;
+use crate::helper_funcs::bit_read;
+use crate::touch::mapping::finale_sensors;
+
+pub struct StuckDetector {
+    /// None disables detection
+    stuck_after: Option<Duration>,
+    mask: bool,
+    logging: bool,
+    /// (byte, bit, name) of every Finale sensor
+    sensors: Vec<(usize, usize, &'static str)>,
+    held_since: [Vec<Option<Instant>>; 2],
+    stuck: [Vec<bool>; 2],
+}
+
+impl StuckDetector {
+    pub fn new(stuck_after: Option<Duration>, mask: bool) -> Self {
+        let sensors: Vec<_> = finale_sensors().collect();
+        let held_since = vec![None; sensors.len()];
+        let stuck = vec![false; sensors.len()];
+        Self {
+            stuck_after,
+            mask,
+            logging: true,
+            sensors,
+            held_since: [held_since.clone(), held_since],
+            stuck: [stuck.clone(), stuck],
+        }
+    }
+
+    pub fn from_config(touch: &Touch) -> Self {
+        let stuck_after = (touch.stuck_secs > 0).then(|| Duration::from_secs(touch.stuck_secs));
+        Self::new(stuck_after, touch.mask_stuck)
+    }
+
+    /// Turns off warnings about sensors getting stuck and released
+    pub fn set_logging(&mut self, logging: bool) {
+        self.logging = logging;
+    }
+
+    pub fn masks_stuck(&self) -> bool {
+        self.mask
+    }
+
+    /// Takes one player's 4 sensor bytes, updates stuck state and returns them with stuck
+    /// sensors masked out (if masking is on)
+    pub fn filter(&mut self, player_num: usize, sensors: &[u8], now: Instant) -> [u8; 4] {
+        let mut output = [0u8; 4];
+        for (dst, src) in output.iter_mut().zip(sensors) {
+            *dst = *src;
+        }
+        let stuck_after = match self.stuck_after {
+            Some(stuck_after) => stuck_after,
+            None => return output,
+        };
+
+        let mut changed = false;
+        for (i, &(byte, bit, name)) in self.sensors.iter().enumerate() {
+            let pressed = bit_read(&output[byte], bit);
+            let held_since = &mut self.held_since[player_num][i];
+            let stuck = &mut self.stuck[player_num][i];
+
+            if !pressed {
+                if *stuck && self.logging {
+                    info!(
+                        "Touch: P{} {} released, not stuck anymore",
+                        player_num + 1,
+                        name
+                    );
+                }
+                changed |= *stuck;
+                *held_since = None;
+                *stuck = false;
+                continue;
+            }
+
+            let since = *held_since.get_or_insert(now);
+            if !*stuck && now.duration_since(since) >= stuck_after {
+                *stuck = true;
+                changed = true;
+                if self.logging {
+                    warn!(
+                        "Touch: P{} {} is pressed for over {} s, looks stuck{}",
+                        player_num + 1,
+                        name,
+                        stuck_after.as_secs(),
+                        if self.mask {
+                            ", masking it until it releases"
+                        } else {
+                            ""
+                        }
+                    );
+                }
+            }
+            if *stuck && self.mask {
+                output[byte] &= !(1 << bit);
+            }
+        }
+
+        if changed && self.logging {
+            self.log_mask(player_num);
+        }
+        output
+    }
+
+    /// Logs stuck sensors of a player, as sensor bytes and names
+    pub fn log_mask(&self, player_num: usize) {
+        let names = self.stuck_sensors(player_num);
+        info!(
+            "Touch: P{} stuck mask {:02X?} ({}){}",
+            player_num + 1,
+            self.stuck_mask(player_num),
+            if names.is_empty() {
+                "none".to_string()
+            } else {
+                names.join(" ")
+            },
+            if self.mask {
+                ", masked"
+            } else {
+                ", not masked"
+            }
+        );
+    }
+
+    /// True if Finale sensor (index in touch frame order) is stuck
+    pub fn is_stuck(&self, player_num: usize, sensor: usize) -> bool {
+        self.stuck[player_num][sensor]
+    }
+
+    /// Stuck sensors of a player as sensor bytes
+    pub fn stuck_mask(&self, player_num: usize) -> [u8; 4] {
+        let mut mask = [0u8; 4];
+        for (i, &(byte, bit, _)) in self.sensors.iter().enumerate() {
+            if self.stuck[player_num][i] {
+                mask[byte] |= 1 << bit;
+            }
+        }
+        mask
+    }
+
+    /// Names of stuck sensors of a player
+    pub fn stuck_sensors(&self, player_num: usize) -> Vec<&'static str> {
+        self.sensors
+            .iter()
+            .zip(&self.stuck[player_num])
+            .filter(|(_, &stuck)| stuck)
+            .map(|(&(_, _, name), _)| name)
+            .collect()
+    }
+}
+
+impl Default for StuckDetector {
+    fn default() -> Self {
+        Self::new(None, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::touch::stuck::StuckDetector;
+
+    const A1_B1: [u8; 4] = [0b11, 0, 0, 0];
+    const B1: [u8; 4] = [0b10, 0, 0, 0];
+    const NONE: [u8; 4] = [0; 4];
+
+    #[test]
+    pub fn masks_stuck_sensor_until_release() {
+        let mut detector = StuckDetector::new(Some(Duration::from_secs(10)), true);
+        let start = Instant::now();
+
+        assert_eq!(detector.filter(0, &A1_B1, start), A1_B1);
+        // A1 was released and pressed again, B1 is held all along
+        assert_eq!(detector.filter(0, &B1, start + Duration::from_secs(5)), B1);
+        assert_eq!(
+            detector.filter(0, &A1_B1, start + Duration::from_secs(11)),
+            [0b01, 0, 0, 0]
+        );
+        assert!(detector.is_stuck(0, 1));
+        assert!(!detector.is_stuck(0, 0));
+        assert_eq!(detector.stuck_mask(0), B1);
+        assert_eq!(detector.stuck_sensors(0), ["B1"]);
+        assert!(detector.stuck_sensors(1).is_empty());
+
+        assert_eq!(
+            detector.filter(0, &NONE, start + Duration::from_secs(12)),
+            NONE
+        );
+        assert_eq!(detector.stuck_mask(0), NONE);
+        assert_eq!(detector.filter(0, &B1, start + Duration::from_secs(13)), B1);
+    }
+
+    #[test]
+    pub fn reports_without_masking() {
+        let mut detector = StuckDetector::new(Some(Duration::from_secs(10)), false);
+        let start = Instant::now();
+        detector.filter(0, &B1, start);
+        assert_eq!(detector.filter(0, &B1, start + Duration::from_secs(10)), B1);
+        assert_eq!(detector.stuck_sensors(0), ["B1"]);
+    }
+
+    #[test]
+    pub fn disabled_detection_passes_through() {
+        let mut detector = StuckDetector::default();
+        let start = Instant::now();
+        detector.filter(0, &B1, start);
+        assert_eq!(
+            detector.filter(0, &B1, start + Duration::from_secs(3600)),
+            B1
+        );
+        assert!(detector.stuck_sensors(0).is_empty());
+    }
+}
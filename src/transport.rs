@@ -0,0 +1,60 @@
+// Byte transports that the subsystems talk through.
+//
+// Every device in the bridge (Finale touch, Deluxe touch, JVS, card reader) only needs to read,
+// write, change a read timeout and clone its port, so they all take a `Box<dyn Transport>`
+// instead of a concrete serial port. This way the same code runs on Windows COM ports, Unix
+// ptys, TCP sockets and in-memory pipes used by tests.
+
+use std::io;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use crate::transport::serial::SerialTransport;
+use crate::transport::tcp::TcpTransport;
+
+pub mod memory;
+pub mod queued;
+pub mod serial;
+pub mod tcp;
+
+pub const TCP_PREFIX: &str = "tcp://";
+
+pub trait Transport: Read + Write + Send {
+    /// Name of the underlying port, used in logs
+    fn name(&self) -> String;
+
+    fn timeout(&self) -> Duration;
+
+    /// Sets read timeout. Reads that don't get any data in time fail with `ErrorKind::TimedOut`,
+    /// zero timeout means that read returns immediately
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+
+    /// Returns a new handle to the same port, so one thread can read while other writes
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+
+    /// Discards all pending data in both directions
+    fn clear(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Opens a transport by its name.
+///
+/// `tcp://host:port` connects to a TCP socket, anything else is treated as a serial port name
+/// (`COM6` on Windows, `/dev/ttyUSB0` or a pty path on Unix)
+pub fn open(name: &str, baud_rate: u32) -> io::Result<Box<dyn Transport>> {
+    if let Some(addr) = name.strip_prefix(TCP_PREFIX) {
+        Ok(Box::new(TcpTransport::connect(addr)?))
+    } else {
+        Ok(Box::new(SerialTransport::open(name, baud_rate)?))
+    }
+}
+
+/// Returns true if read got no data because of a timeout (or was interrupted by a signal),
+/// which is not an actual error for polling loops
+pub fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    )
+}
@@ -0,0 +1,160 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::transport::Transport;
+
+#[derive(Default)]
+struct Pipe {
+    data: Mutex<VecDeque<u8>>,
+    cond: Condvar,
+}
+
+/// One end of an in-memory duplex pipe, behaves like a null-modem serial cable.
+/// Mostly used for tests and for wiring subsystems together without any ports
+pub struct MemoryTransport {
+    name: String,
+    rx: Arc<Pipe>,
+    tx: Arc<Pipe>,
+    timeout: Duration,
+}
+
+impl MemoryTransport {
+    /// Creates two connected ends, everything written to one end can be read from the other
+    pub fn pair(name: &str) -> (Self, Self) {
+        let a = Arc::new(Pipe::default());
+        let b = Arc::new(Pipe::default());
+        (
+            Self {
+                name: format!("{name}:0"),
+                rx: a.clone(),
+                tx: b.clone(),
+                timeout: Duration::from_millis(100),
+            },
+            Self {
+                name: format!("{name}:1"),
+                rx: b,
+                tx: a,
+                timeout: Duration::from_millis(100),
+            },
+        )
+    }
+
+    /// Number of bytes waiting to be read on this end
+    pub fn available(&self) -> usize {
+        self.rx.data.lock().unwrap().len()
+    }
+}
+
+impl Read for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        let mut data = self.rx.data.lock().unwrap();
+        while data.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            data = self.rx.cond.wait_timeout(data, deadline - now).unwrap().0;
+        }
+
+        let len = buf.len().min(data.len());
+        for (dst, src) in buf.iter_mut().zip(data.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MemoryTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx.data.lock().unwrap().extend(buf);
+        self.tx.cond.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Self {
+            name: self.name.clone(),
+            rx: self.rx.clone(),
+            tx: self.tx.clone(),
+            timeout: self.timeout,
+        }))
+    }
+
+    fn clear(&self) -> io::Result<()> {
+        self.rx.data.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transport::memory::MemoryTransport;
+    use crate::transport::Transport;
+    use std::io::{ErrorKind, Read, Write};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    pub fn memory_pair_round_trip() {
+        let (mut a, mut b) = MemoryTransport::pair("test");
+        a.write_all(&[1, 2, 3]).unwrap();
+        let mut buf = [0u8; 3];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+
+        b.write_all(b"{HALT}").unwrap();
+        let mut buf = [0u8; 6];
+        a.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"{HALT}");
+    }
+
+    #[test]
+    pub fn memory_read_times_out() {
+        let (mut a, _b) = MemoryTransport::pair("test");
+        a.set_timeout(Duration::ZERO).unwrap();
+        let err = a.read(&mut [0u8; 1]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    pub fn memory_clone_wakes_reader() {
+        let (mut a, b) = MemoryTransport::pair("test");
+        a.set_timeout(Duration::from_secs(5)).unwrap();
+        let mut writer = b.try_clone().unwrap();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            writer.write_all(&[0x42]).unwrap();
+        });
+        let mut buf = [0u8; 1];
+        a.read_exact(&mut buf).unwrap();
+        handle.join().unwrap();
+        assert_eq!(buf[0], 0x42);
+    }
+}
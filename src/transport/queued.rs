@@ -0,0 +1,198 @@
+use std::io;
+use std::io::{Read, Write};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use log::{info, warn};
+
+use crate::transport::{is_timeout, Transport};
+
+/// Write-only handle to a port that is written by its own thread, so a port that stops taking
+/// data (e.g. a virtual COM port nobody reads) never blocks the writer. Writes only queue the
+/// data, when the queue is full they are dropped. Reads are not supported.
+///
+/// The writer thread ends once every handle is dropped and the queue is written out
+pub struct QueuedWriter {
+    name: String,
+    sender: Sender<Vec<u8>>,
+    dropping: bool,
+}
+
+impl QueuedWriter {
+    /// Moves `port` to a new writer thread with room for `capacity` pending writes. Port writes
+    /// give up after `write_timeout`, what timed out is dropped
+    pub fn spawn(
+        mut port: Box<dyn Transport>,
+        capacity: usize,
+        write_timeout: Duration,
+    ) -> io::Result<(Self, JoinHandle<io::Result<()>>)> {
+        let name = port.name();
+        port.set_timeout(write_timeout)?;
+        let (sender, receiver) = crossbeam_channel::bounded(capacity);
+        let handle = thread::Builder::new()
+            .name(format!("{name} Writer Thread"))
+            .spawn(move || write_all_queued(port, receiver))?;
+        Ok((
+            Self {
+                name,
+                sender,
+                dropping: false,
+            },
+            handle,
+        ))
+    }
+}
+
+fn write_all_queued(mut port: Box<dyn Transport>, receiver: Receiver<Vec<u8>>) -> io::Result<()> {
+    let mut timed_out = false;
+    for data in receiver {
+        match port.write_all(&data) {
+            Ok(()) if timed_out => {
+                info!("{}: takes data again", port.name());
+                timed_out = false;
+            }
+            Ok(()) => {}
+            Err(err) if is_timeout(&err) => {
+                if !timed_out {
+                    warn!("{}: write timed out, dropping data", port.name());
+                    timed_out = true;
+                }
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+impl Read for QueuedWriter {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} is write-only", self.name),
+        ))
+    }
+}
+
+impl Write for QueuedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.sender.try_send(buf.to_vec()) {
+            Ok(()) => {
+                self.dropping = false;
+                Ok(buf.len())
+            }
+            Err(TrySendError::Full(_)) => {
+                if !self.dropping {
+                    warn!("{}: write queue is full, dropping data", self.name);
+                    self.dropping = true;
+                }
+                Ok(buf.len())
+            }
+            Err(TrySendError::Disconnected(_)) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!("{}: writer thread has stopped", self.name),
+            )),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for QueuedWriter {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    /// Writes never wait, there is nothing to set
+    fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Self {
+            name: self.name.clone(),
+            sender: self.sender.clone(),
+            dropping: false,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::time::{Duration, Instant};
+
+    use crate::transport::memory::MemoryTransport;
+    use crate::transport::queued::QueuedWriter;
+    use crate::transport::Transport;
+
+    #[test]
+    pub fn writes_in_order_and_ends_with_last_handle() {
+        let (port, mut other) = MemoryTransport::pair("queued");
+        let (mut writer, handle) =
+            QueuedWriter::spawn(Box::new(port), 4, Duration::from_millis(100)).unwrap();
+        let mut clone = writer.try_clone().unwrap();
+        writer.write_all(b"{HALT}").unwrap();
+        clone.write_all(b"{STAT}").unwrap();
+        assert!(writer.read(&mut [0u8; 1]).is_err());
+
+        drop(writer);
+        drop(clone);
+        handle.join().unwrap().unwrap();
+        let mut buf = [0u8; 12];
+        other.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"{HALT}{STAT}");
+    }
+
+    #[test]
+    pub fn full_queue_drops_instead_of_blocking() {
+        // Port that takes nothing, every write times out
+        struct Stalled;
+        impl Read for Stalled {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::TimedOut.into())
+            }
+        }
+        impl Write for Stalled {
+            fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+                std::thread::sleep(Duration::from_millis(20));
+                Err(std::io::ErrorKind::TimedOut.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        impl Transport for Stalled {
+            fn name(&self) -> String {
+                "stalled".to_string()
+            }
+            fn timeout(&self) -> Duration {
+                Duration::ZERO
+            }
+            fn set_timeout(&mut self, _timeout: Duration) -> std::io::Result<()> {
+                Ok(())
+            }
+            fn try_clone(&self) -> std::io::Result<Box<dyn Transport>> {
+                Ok(Box::new(Stalled))
+            }
+        }
+
+        let (mut writer, handle) =
+            QueuedWriter::spawn(Box::new(Stalled), 2, Duration::from_millis(20)).unwrap();
+        let started = Instant::now();
+        for _ in 0..100 {
+            writer.write_all(&[0; 9]).unwrap();
+        }
+        assert!(started.elapsed() < Duration::from_millis(500));
+        drop(writer);
+        // Timed out writes are dropped, not an error
+        handle.join().unwrap().unwrap();
+    }
+}
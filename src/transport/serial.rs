@@ -0,0 +1,74 @@
+use std::io;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use serialport::{ClearBuffer, SerialPort};
+
+use crate::transport::Transport;
+
+/// Native serial port, COM port on Windows and tty on Unix
+pub struct SerialTransport {
+    port: Box<dyn SerialPort>,
+}
+
+impl SerialTransport {
+    pub fn open(name: &str, baud_rate: u32) -> Result<Self, serialport::Error> {
+        let port = serialport::new(name, baud_rate).open()?;
+        Ok(Self { port })
+    }
+
+    pub fn from_port(port: Box<dyn SerialPort>) -> Self {
+        Self { port }
+    }
+}
+
+impl Read for SerialTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.port.read(buf)
+    }
+}
+
+impl Write for SerialTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl Transport for SerialTransport {
+    fn name(&self) -> String {
+        self.port.name().unwrap_or_else(|| "serial".to_string())
+    }
+
+    fn timeout(&self) -> Duration {
+        self.port.timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        Ok(self.port.set_timeout(timeout)?)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Self {
+            port: self.port.try_clone()?,
+        }))
+    }
+
+    fn clear(&self) -> io::Result<()> {
+        Ok(self.port.clear(ClearBuffer::All)?)
+    }
+}
+
+/// Creates a pseudo terminal pair. Returns (master, slave), slave's name is the path that
+/// other programs (or the game running under wine) can open as a regular serial port
+#[cfg(unix)]
+pub fn pty_pair() -> io::Result<(SerialTransport, SerialTransport)> {
+    let (master, slave) = serialport::TTYPort::pair()?;
+    Ok((
+        SerialTransport::from_port(Box::new(master)),
+        SerialTransport::from_port(Box::new(slave)),
+    ))
+}
@@ -0,0 +1,123 @@
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::transport::{is_timeout, Transport};
+
+/// Shortest read timeout a socket takes, used for zero timeout
+const MIN_READ_TIMEOUT: Duration = Duration::from_millis(1);
+
+/// TCP socket, useful for serial-over-network bridges (e.g. `socat`, `ser2net`).
+///
+/// Clones share one socket, and with it the socket's read timeout and blocking mode. The socket
+/// always stays blocking, and every handle sets its own timeout right before it reads, so a
+/// reader polling with zero timeout never turns a writer's writes into `WouldBlock`
+pub struct TcpTransport {
+    stream: TcpStream,
+    name: String,
+    timeout: Duration,
+}
+
+impl TcpTransport {
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Self::from_stream(stream, addr.to_string())
+    }
+
+    pub fn from_stream(stream: TcpStream, name: String) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            name,
+            timeout: Duration::from_millis(100),
+        })
+    }
+}
+
+impl Read for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Sockets don't accept zero read timeout, the shortest one is close enough
+        self.stream
+            .set_read_timeout(Some(self.timeout.max(MIN_READ_TIMEOUT)))?;
+        // Platforms disagree on which error a timed out socket read returns,
+        // so make it look like a serial port timeout
+        self.stream.read(buf).map_err(|err| {
+            if is_timeout(&err) {
+                io::Error::from(io::ErrorKind::TimedOut)
+            } else {
+                err
+            }
+        })
+    }
+}
+
+impl Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Only kept for this handle, applied to the socket by read
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Self {
+            stream: self.stream.try_clone()?,
+            name: self.name.clone(),
+            timeout: self.timeout,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    use crate::transport::tcp::TcpTransport;
+    use crate::transport::Transport;
+
+    #[test]
+    pub fn zero_timeout_reader_does_not_affect_writer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let mut reader = TcpTransport::connect(&addr).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        let mut peer = TcpTransport::from_stream(peer, "peer".to_string()).unwrap();
+
+        let mut writer = reader.try_clone().unwrap();
+        reader.set_timeout(Duration::ZERO).unwrap();
+        let err = reader.read(&mut [0u8; 1]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(writer.timeout(), Duration::from_millis(100));
+
+        // Big enough to fill the socket buffers if the socket were non-blocking
+        let data = vec![0x5A; 1 << 20];
+        let handle = std::thread::spawn(move || {
+            let mut buf = vec![0u8; 1 << 20];
+            peer.set_timeout(Duration::from_secs(5)).unwrap();
+            peer.read_exact(&mut buf).unwrap();
+            buf
+        });
+        writer.write_all(&data).unwrap();
+        assert_eq!(handle.join().unwrap(), data);
+    }
+}
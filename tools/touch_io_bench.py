@@ -0,0 +1,115 @@
+#!/usr/bin/env python3
+"""End-to-end touch I/O benchmark for the bridge on Linux, over pseudo terminals.
+
+Plays the Finale panel on one pty and the game on the two Deluxe ptys, streams a
+touch frame every --interval ms (B1 toggled on every frame), and measures:
+  * latency: Finale frame written -> translated Deluxe P1 frame read back
+  * CPU: user + system time of the bridge process over the run
+
+Usage: touch_io_bench.py path/to/mai_finale_to_deluxe [--seconds 20] [--interval 15]
+"""
+
+import argparse
+import os
+import select
+import statistics
+import subprocess
+import tempfile
+import threading
+import time
+import tty
+
+FRAME_IDLE = bytes([ord("("), 0, 0, 0, 0, ord(")"), ord("("), 0, 0, 0, 0, ord(")"), 0, 0])
+FRAME_B1 = bytes([ord("("), 0b10, 0, 0, 0, ord(")"), ord("("), 0, 0, 0, 0, ord(")"), 0, 0])
+
+
+def pty():
+    master, slave = os.openpty()
+    tty.setraw(master)
+    return master, os.ttyname(slave), slave
+
+
+def cpu_seconds(pid):
+    with open(f"/proc/{pid}/stat") as f:
+        fields = f.read().rsplit(")", 1)[1].split()
+    return (int(fields[11]) + int(fields[12])) / os.sysconf("SC_CLK_TCK")
+
+
+def drain(fd, stop):
+    while not stop.is_set():
+        if select.select([fd], [], [], 0.1)[0]:
+            try:
+                os.read(fd, 4096)
+            except OSError:
+                return
+
+
+def main():
+    parser = argparse.ArgumentParser()
+    parser.add_argument("bridge")
+    parser.add_argument("--seconds", type=float, default=20)
+    parser.add_argument("--interval", type=float, default=15)
+    args = parser.parse_args()
+
+    panel, panel_name, _ = pty()
+    game1, game1_name, _ = pty()
+    game2, game2_name, _ = pty()
+    workdir = tempfile.mkdtemp()
+    bridge = subprocess.Popen(
+        [os.path.abspath(args.bridge), "--log-level", "warn",
+         "--disable-jvs", "--disable-reader",
+         "--touch-re2-com", panel_name,
+         "--touch-alls-p1-com", game1_name,
+         "--touch-alls-p2-com", game2_name],
+        cwd=workdir,
+    )
+    time.sleep(1)
+    stop = threading.Event()
+    threading.Thread(target=drain, args=(panel, stop), daemon=True).start()
+    threading.Thread(target=drain, args=(game2, stop), daemon=True).start()
+    for game in (game1, game2):
+        os.write(game, b"{STAT}")
+    time.sleep(0.5)
+
+    # Let the bridge settle, then measure
+    latencies = []
+    pending = b""
+    pressed = False
+    cpu_start = cpu_seconds(bridge.pid)
+    started = time.monotonic()
+    next_frame = started
+    while time.monotonic() - started < args.seconds:
+        pressed = not pressed
+        sent = time.perf_counter()
+        os.write(panel, FRAME_B1 if pressed else FRAME_IDLE)
+        deadline = sent + 0.5
+        while True:
+            remaining = deadline - time.perf_counter()
+            if remaining <= 0 or not select.select([game1], [], [], remaining)[0]:
+                break
+            pending += os.read(game1, 4096)
+            frames = [pending[i:i + 9] for i in range(0, len(pending) - len(pending) % 9, 9)]
+            pending = pending[len(frames) * 9:]
+            if any((frame[2] != 0) == pressed for frame in frames[-1:]):
+                latencies.append(time.perf_counter() - sent)
+                break
+        next_frame += args.interval / 1000
+        time.sleep(max(0, next_frame - time.monotonic()))
+    elapsed = time.monotonic() - started
+    cpu = cpu_seconds(bridge.pid) - cpu_start
+
+    stop.set()
+    bridge.terminate()
+    bridge.wait()
+
+    latencies.sort()
+    us = lambda s: f"{s * 1e6:.0f} us"
+    print(f"{args.bridge}")
+    print(f"  frames: {len(latencies)} answered")
+    print(f"  CPU: {cpu / elapsed * 100:.1f}% of one core")
+    print(f"  latency: p50 {us(statistics.median(latencies))}, "
+          f"p99 {us(latencies[int(len(latencies) * 0.99) - 1])}, max {us(latencies[-1])}")
+
+
+if __name__ == "__main__":
+    main()